@@ -1,8 +1,64 @@
 defmodule CnftNif do
     use Rustler, otp_app: :cnft_project, crate: "cnftnif"
+    def set_backend(_backend), do: :erlang.nif_error(:nif_not_loaded);
+    def set_retry_policy(_policy), do: :erlang.nif_error(:nif_not_loaded);
+    def retry_policy(), do: :erlang.nif_error(:nif_not_loaded);
     def tree_manager_init(), do: :erlang.nif_error(:nif_not_loaded);
-    def create_merkle_tree(_tree_manager, _owner_private_key), do: :erlang.nif_error(:nif_not_loaded);
-    def mint_cnft(_tree_manager, _owner_private_key, _nft_owner_pub_key), do: :erlang.nif_error(:nif_not_loaded);
-    def transfer_cnft(_tree_manager, _owner_private_key, _old_owner_private_key, _new_owner_pub_key, _index, _data_hash, _creator_hash), do: :erlang.nif_error(:nif_not_loaded);
+    def tree_manager_attach(_tree_address, _rebuild_nodes), do: :erlang.nif_error(:nif_not_loaded);
+    def tree_manager_migrate(_tree_manager), do: :erlang.nif_error(:nif_not_loaded);
+    def create_merkle_tree(_tree_manager, _owner, _options \\ []), do: :erlang.nif_error(:nif_not_loaded);
+    def mint_cnft(_tree_manager, _owner, _nft_owner_pub_key, _options \\ []), do: :erlang.nif_error(:nif_not_loaded);
+    def mint_batch(_tree_manager, _owner, _entries, _options \\ []), do: :erlang.nif_error(:nif_not_loaded);
+    def transfer_cnft(_tree_manager, _owner, _old_owner, _new_owner_pub_key, _index, _data_hash, _creator_hash, _options \\ []), do: :erlang.nif_error(:nif_not_loaded);
+    def transfer_batch(_tree_manager, _owner, _transfers, _options \\ []), do: :erlang.nif_error(:nif_not_loaded);
+    def run_pipeline(_tree_manager, _owner, _operations, _options \\ []), do: :erlang.nif_error(:nif_not_loaded);
+    def burn_cnft(_tree_manager, _owner, _nft_owner, _index, _data_hash, _creator_hash, _options \\ []), do: :erlang.nif_error(:nif_not_loaded);
+    def delegate_cnft(_tree_manager, _owner, _nft_owner, _new_delegate_pub_key, _index, _data_hash, _creator_hash, _options \\ []), do: :erlang.nif_error(:nif_not_loaded);
+    def mint_cnft_send_only(_tree_manager, _owner, _nft_owner_pub_key, _options \\ []), do: :erlang.nif_error(:nif_not_loaded);
+    def transfer_cnft_send_only(_tree_manager, _owner, _old_owner, _new_owner_pub_key, _index, _data_hash, _creator_hash, _options \\ []), do: :erlang.nif_error(:nif_not_loaded);
+    def burn_cnft_send_only(_tree_manager, _owner, _nft_owner, _index, _data_hash, _creator_hash, _options \\ []), do: :erlang.nif_error(:nif_not_loaded);
+    def delegate_cnft_send_only(_tree_manager, _owner, _nft_owner, _new_delegate_pub_key, _index, _data_hash, _creator_hash, _options \\ []), do: :erlang.nif_error(:nif_not_loaded);
+    def confirm_pending(_tree_manager, _pending), do: :erlang.nif_error(:nif_not_loaded);
+    def remaining_capacity(_tree_manager), do: :erlang.nif_error(:nif_not_loaded);
+    def get_proof(_tree_manager, _leaf, _options \\ []), do: :erlang.nif_error(:nif_not_loaded);
+    def verify_leaf_proof(_leaf, _proof, _root), do: :erlang.nif_error(:nif_not_loaded);
+    def verify_leaf_on_chain(_tree_manager, _payer, _leaf, _mode), do: :erlang.nif_error(:nif_not_loaded);
+    def verify_proof_on_chain(_payer, _proof, _mode), do: :erlang.nif_error(:nif_not_loaded);
+    def das_get_asset(_das_url, _asset_id), do: :erlang.nif_error(:nif_not_loaded);
+    def das_get_asset_proof(_das_url, _asset_id), do: :erlang.nif_error(:nif_not_loaded);
+    def das_get_assets_by_owner(_das_url, _owner, _page, _limit), do: :erlang.nif_error(:nif_not_loaded);
+    def das_get_assets_by_group(_das_url, _group_key, _group_value, _page, _limit), do: :erlang.nif_error(:nif_not_loaded);
+    def das_get_signatures_for_asset(_das_url, _asset_id, _page, _limit), do: :erlang.nif_error(:nif_not_loaded);
+    def hash_metadata(_metadata), do: :erlang.nif_error(:nif_not_loaded);
+    def hash_creators(_creators), do: :erlang.nif_error(:nif_not_loaded);
+    def hash_leaf(_leaf), do: :erlang.nif_error(:nif_not_loaded);
+    def asset_id(_merkle_tree, _nonce), do: :erlang.nif_error(:nif_not_loaded);
+    def tree_config_pda(_merkle_tree), do: :erlang.nif_error(:nif_not_loaded);
+    def bubblegum_signer_pda(), do: :erlang.nif_error(:nif_not_loaded);
+    def voucher_pda(_merkle_tree, _nonce), do: :erlang.nif_error(:nif_not_loaded);
+    def tree_pool_init(_tree_manager, _rollover_threshold), do: :erlang.nif_error(:nif_not_loaded);
+    def tree_pool_rollover(_tree_pool, _owner), do: :erlang.nif_error(:nif_not_loaded);
+    def tree_pool_mint_cnft(_tree_pool, _owner, _nft_owner_pub_key, _options \\ []), do: :erlang.nif_error(:nif_not_loaded);
+    def build_mint_cnft(_tree_manager, _owner_pub_key, _nft_owner_pub_key), do: :erlang.nif_error(:nif_not_loaded);
+    def build_transfer_cnft(_tree_manager, _payer_pub_key, _old_owner_pub_key, _new_owner_pub_key, _index, _data_hash, _creator_hash), do: :erlang.nif_error(:nif_not_loaded);
+    def build_burn_cnft(_tree_manager, _payer_pub_key, _nft_owner_pub_key, _index, _data_hash, _creator_hash), do: :erlang.nif_error(:nif_not_loaded);
+    def build_delegate_cnft(_tree_manager, _payer_pub_key, _nft_owner_pub_key, _new_delegate_pub_key, _index, _data_hash, _creator_hash), do: :erlang.nif_error(:nif_not_loaded);
+    def sign_transaction(_unsigned_transaction, _signer), do: :erlang.nif_error(:nif_not_loaded);
+    def submit_signed_transaction(_tree_manager, _unsigned_transaction, _signed_transaction), do: :erlang.nif_error(:nif_not_loaded);
+    def shared_tree_manager_new(_tree_manager), do: :erlang.nif_error(:nif_not_loaded);
+    def shared_tree_manager_snapshot(_shared), do: :erlang.nif_error(:nif_not_loaded);
+    def shared_pending_reservations(_shared), do: :erlang.nif_error(:nif_not_loaded);
+    def shared_reserve_leaf(_shared), do: :erlang.nif_error(:nif_not_loaded);
+    def shared_commit_leaf(_shared, _reservation, _index, _leaf_hash), do: :erlang.nif_error(:nif_not_loaded);
+    def shared_rollback_leaf(_shared, _reservation), do: :erlang.nif_error(:nif_not_loaded);
+    def shared_mint_cnft(_shared, _owner, _nft_owner_pub_key, _options \\ []), do: :erlang.nif_error(:nif_not_loaded);
+    def subscribe_signature(_ws_url, _signature, _pid), do: :erlang.nif_error(:nif_not_loaded);
+    def subscribe_tree(_ws_url, _tree_address, _pid), do: :erlang.nif_error(:nif_not_loaded);
+    def unsubscribe(_subscription), do: :erlang.nif_error(:nif_not_loaded);
+    def keypair_generate(), do: :erlang.nif_error(:nif_not_loaded);
+    def keypair_from_base58(_private_key), do: :erlang.nif_error(:nif_not_loaded);
+    def keypair_from_file(_path), do: :erlang.nif_error(:nif_not_loaded);
+    def keypair_pubkey(_keypair), do: :erlang.nif_error(:nif_not_loaded);
+    def sign_reply(_request, _signature), do: :erlang.nif_error(:nif_not_loaded);
+    def sign_reject(_request, _reason), do: :erlang.nif_error(:nif_not_loaded);
   end
-  
\ No newline at end of file
@@ -0,0 +1,329 @@
+//! This module implements the chain backends every TreeManager operation talks to
+//!
+//! The backend is selected process wide with the `set_backend` NIF:
+//! - `{:rpc, url}` talks to a Solana RPC node. This is the default, pointing at the node named by the
+//!   `CNFT_NIF_RPC_URL` environment variable, or at the public devnet endpoint.
+//! - `:simulated` runs an in-memory Solana which applies Bubblegum semantics, see the `simulator` module.
+
+
+
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use mpl_bubblegum::programs::SPL_NOOP_ID;
+use once_cell::sync::Lazy;
+use rustler::NifTaggedEnum;
+use serde_json::json;
+use solana_client::{client_error::ClientErrorKind, rpc_client::{GetConfirmedSignaturesForAddress2Config, RpcClient}, rpc_config::RpcTransactionConfig, rpc_request::{RpcRequest, MAX_GET_SIGNATURE_STATUSES_QUERY_ITEMS}};
+use solana_sdk::{bs58, commitment_config::CommitmentConfig, hash::Hash, pubkey::Pubkey, signature::Signature, transaction::{Transaction, TransactionError}};
+use solana_transaction_status::{EncodedConfirmedTransactionWithStatusMeta, UiInnerInstructions, UiInstruction, UiLoadedAddresses, UiTransactionEncoding};
+
+use crate::error::CnftError;
+use crate::simulation::SimulationResult;
+use crate::simulator::SimulatedBackend;
+
+
+/// Environment variable naming the RPC node used until `set_backend` is called.
+const RPC_URL_VARIABLE: &str = "CNFT_NIF_RPC_URL";
+
+/// The public devnet endpoint, used when `RPC_URL_VARIABLE` is not set.
+const DEFAULT_RPC_URL: &str = "https://api.devnet.solana.com";
+
+
+static BACKEND: Lazy<RwLock<Arc<dyn ChainBackend>>> = Lazy::new(|| {
+    let url = std::env::var(RPC_URL_VARIABLE).unwrap_or_else(|_| DEFAULT_RPC_URL.to_string());
+
+    RwLock::new(Arc::new(RpcBackend::new(&url)))
+});
+
+
+
+/// The chain calls used by TreeManager operations.
+pub trait ChainBackend: Send + Sync {
+
+    /// Minimum balance for an account of `data_len` bytes to be rent exempt.
+    fn minimum_balance_for_rent_exemption(&self, data_len: usize) -> Result<u64, CnftError>;
+
+    /// Blockhash to build new transactions with.
+    fn latest_blockhash(&self) -> Result<Hash, CnftError>;
+
+    /// Sends a signed transaction and waits until it is confirmed, returning its signature and the slot it was processed in.
+    fn send_and_confirm(&self, transaction: &Transaction) -> Result<(Signature, u64), CnftError>;
+
+    /// Sends a signed transaction without waiting for its confirmation, returning its signature.
+    fn send(&self, transaction: &Transaction) -> Result<Signature, CnftError>;
+
+    /// Whether transactions built with `blockhash` can still be processed.
+    fn is_blockhash_valid(&self, blockhash: &Hash) -> Result<bool, CnftError>;
+
+    /// Status of every transaction in `signatures`, in order.
+    fn signature_statuses(&self, signatures: &[Signature]) -> Result<Vec<SignatureStatus>, CnftError>;
+
+    /// Status of a single transaction, see `signature_statuses`.
+    fn signature_status(&self, signature: &Signature) -> Result<SignatureStatus, CnftError> {
+        Ok(self.signature_statuses(std::slice::from_ref(signature))?.pop().flatten())
+    }
+
+    /// Executes a transaction without committing it, returning the compute units it consumed, its logs and the error
+    /// it would fail with. Fails only if the simulation cannot be run.
+    fn simulate(&self, transaction: &Transaction) -> Result<SimulationResult, CnftError>;
+
+    /// Data of the account at `pubkey`, or `None` if the account does not exist.
+    fn account_data(&self, pubkey: &Pubkey) -> Result<Option<Vec<u8>>, CnftError>;
+
+    /// Signatures of every successful transaction which referenced `address`, oldest first.
+    fn signatures_for_address(&self, address: &Pubkey) -> Result<Vec<Signature>, CnftError>;
+
+    /// A confirmed transaction, or `None` if it is unknown.
+    fn confirmed_transaction(&self, signature: &Signature) -> Result<Option<ConfirmedTransaction>, CnftError>;
+
+    /// Log messages of a processed transaction, to explain why it failed. Empty if they cannot be retrieved.
+    fn transaction_logs(&self, signature: &Signature) -> Vec<String> {
+        self.confirmed_transaction(signature).ok().flatten().map(|transaction| transaction.logs).unwrap_or_default()
+    }
+}
+
+
+
+/// What a confirmed transaction left on chain.
+#[derive(Clone, Debug)]
+pub struct ConfirmedTransaction {
+    /// Slot the transaction was processed in.
+    pub slot: u64,
+
+    /// Data of every instruction sent to the noop program, inner instructions included, in execution order.
+    /// This is where Account Compression and Bubblegum log their events.
+    pub noop_data: Vec<Vec<u8>>,
+
+    /// Log messages of the programs the transaction invoked.
+    pub logs: Vec<String>
+}
+
+
+
+/// Slot a transaction was processed in along with the error it failed with, or `None` if it is not confirmed.
+pub type SignatureStatus = Option<(u64, Option<TransactionError>)>;
+
+
+
+/// Backend description passed from Elixir, either `:simulated` or `{:rpc, url}`.
+#[derive(NifTaggedEnum, Clone)]
+pub enum BackendSpec {
+    Simulated,
+    Rpc(String)
+}
+
+impl BackendSpec {
+
+    /// Creates the backend described.
+    pub fn build(&self) -> Arc<dyn ChainBackend> {
+        match self {
+            BackendSpec::Simulated => Arc::new(SimulatedBackend::new()),
+            BackendSpec::Rpc(url) => Arc::new(RpcBackend::new(url))
+        }
+    }
+}
+
+
+
+/// The backend currently in use.
+pub fn backend() -> Arc<dyn ChainBackend> {
+    BACKEND.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+
+/// Replaces the backend used by every following operation.
+pub fn set_backend(backend: Arc<dyn ChainBackend>) {
+    *BACKEND.write().unwrap_or_else(|e| e.into_inner()) = backend;
+}
+
+
+/// Serializes the tests which use the process wide backend, each on a fresh simulated chain.
+#[cfg(test)]
+pub(crate) fn simulated_backend() -> std::sync::MutexGuard<'static, ()> {
+    static TEST_BACKEND: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    let guard = TEST_BACKEND.lock().unwrap_or_else(|e| e.into_inner());
+    set_backend(BackendSpec::Simulated.build());
+
+    guard
+}
+
+
+
+/// Backend talking to a Solana RPC node, confirming transactions at the `confirmed` commitment.
+pub struct RpcBackend {
+    client: RpcClient
+}
+
+impl RpcBackend {
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: RpcClient::new_with_commitment(url.to_string(), CommitmentConfig::confirmed())
+        }
+    }
+}
+
+impl ChainBackend for RpcBackend {
+    fn minimum_balance_for_rent_exemption(&self, data_len: usize) -> Result<u64, CnftError> {
+        self.client.get_minimum_balance_for_rent_exemption(data_len)
+            .map_err(|e| CnftError::from_client_error(e, None))
+    }
+
+    fn latest_blockhash(&self) -> Result<Hash, CnftError> {
+        self.client.get_latest_blockhash()
+            .map_err(|e| CnftError::from_client_error(e, None))
+    }
+
+    fn send_and_confirm(&self, transaction: &Transaction) -> Result<(Signature, u64), CnftError> {
+        let signature = self.client.send_and_confirm_transaction(transaction).map_err(|e| match e.kind() {
+            // The transaction landed and failed, its logs are only kept in the ledger.
+            ClientErrorKind::TransactionError(error) =>
+                CnftError::from_transaction_error(error, Some(transaction), &self.transaction_logs(&transaction.signatures[0])),
+            _ => CnftError::from_client_error(e, Some(transaction))
+        })?;
+
+        let status = self.client.get_signature_statuses(&[signature])
+            .map_err(|e| CnftError::from_client_error(e, None))?
+            .value
+            .into_iter()
+            .next()
+            .flatten()
+            .ok_or_else(|| CnftError::rpc("rpc_response", format!("transaction {signature}: confirmed without a status")))?;
+
+        Ok((signature, status.slot))
+    }
+
+    fn send(&self, transaction: &Transaction) -> Result<Signature, CnftError> {
+        self.client.send_transaction(transaction)
+            .map_err(|e| CnftError::from_client_error(e, Some(transaction)))
+    }
+
+    fn is_blockhash_valid(&self, blockhash: &Hash) -> Result<bool, CnftError> {
+        self.client.is_blockhash_valid(blockhash, self.client.commitment())
+            .map_err(|e| CnftError::from_client_error(e, None))
+    }
+
+    fn signature_statuses(&self, signatures: &[Signature]) -> Result<Vec<SignatureStatus>, CnftError> {
+        let mut statuses = Vec::with_capacity(signatures.len());
+
+        for chunk in signatures.chunks(MAX_GET_SIGNATURE_STATUSES_QUERY_ITEMS) {
+            let page = self.client.get_signature_statuses(chunk)
+                .map_err(|e| CnftError::from_client_error(e, None))?
+                .value;
+
+            statuses.extend(page.into_iter().map(|status| status
+                .filter(|status| status.satisfies_commitment(self.client.commitment()))
+                .map(|status| (status.slot, status.err))));
+        }
+
+        Ok(statuses)
+    }
+
+    fn simulate(&self, transaction: &Transaction) -> Result<SimulationResult, CnftError> {
+        let response = self.client.simulate_transaction(transaction)
+            .map_err(|e| CnftError::from_client_error(e, Some(transaction)))?;
+
+        let logs = response.value.logs.unwrap_or_default();
+
+        Ok(SimulationResult {
+            units_consumed: response.value.units_consumed,
+            error: response.value.err.map(|e| CnftError::from_transaction_error(&e, Some(transaction), &logs)),
+            logs
+        })
+    }
+
+    fn account_data(&self, pubkey: &Pubkey) -> Result<Option<Vec<u8>>, CnftError> {
+        let response = self.client.get_account_with_commitment(pubkey, self.client.commitment())
+            .map_err(|e| CnftError::from_client_error(e, None))?;
+
+        Ok(response.value.map(|account| account.data))
+    }
+
+    fn signatures_for_address(&self, address: &Pubkey) -> Result<Vec<Signature>, CnftError> {
+        let mut signatures = Vec::new();
+        let mut before = None;
+
+        // Pages are returned newest first, at most 1000 signatures each.
+        loop {
+            let config = GetConfirmedSignaturesForAddress2Config {
+                before,
+                until: None,
+                limit: None,
+                commitment: Some(self.client.commitment())
+            };
+            let page = self.client.get_signatures_for_address_with_config(address, config)
+                .map_err(|e| CnftError::from_client_error(e, None))?;
+
+            let Some(last) = page.last() else {
+                break;
+            };
+            before = Some(parse_signature(&last.signature)?);
+
+            for status in page.iter().filter(|status| status.err.is_none()) {
+                signatures.push(parse_signature(&status.signature)?);
+            }
+        }
+
+        signatures.reverse();
+        Ok(signatures)
+    }
+
+    fn confirmed_transaction(&self, signature: &Signature) -> Result<Option<ConfirmedTransaction>, CnftError> {
+        let config = RpcTransactionConfig {
+            encoding: Some(UiTransactionEncoding::Base64),
+            commitment: Some(self.client.commitment()),
+            max_supported_transaction_version: Some(0)
+        };
+        // `get_transaction_with_config` fails on unknown signatures instead of returning `None`.
+        let confirmed: Option<EncodedConfirmedTransactionWithStatusMeta> = self.client
+            .send(RpcRequest::GetTransaction, json!([signature.to_string(), config]))
+            .map_err(|e| CnftError::from_client_error(e, None))?;
+
+        let Some(confirmed) = confirmed else {
+            return Ok(None);
+        };
+
+        let malformed = |message: &str| CnftError::rpc("serde", format!("transaction {signature}: {message}"));
+        let meta = confirmed.transaction.meta.ok_or_else(|| malformed("missing status meta"))?;
+        let transaction = confirmed.transaction.transaction.decode().ok_or_else(|| malformed("undecodable transaction"))?;
+
+        let mut account_keys = transaction.message.static_account_keys().to_vec();
+        let loaded_addresses: Option<UiLoadedAddresses> = meta.loaded_addresses.into();
+        if let Some(loaded) = loaded_addresses {
+            for key in loaded.writable.iter().chain(loaded.readonly.iter()) {
+                account_keys.push(Pubkey::from_str(key).map_err(|_| malformed("invalid loaded address"))?);
+            }
+        }
+        let is_noop = |program_id_index: u8| account_keys.get(program_id_index as usize) == Some(&SPL_NOOP_ID);
+
+        let inner_instructions: Vec<UiInnerInstructions> = Option::from(meta.inner_instructions).unwrap_or_default();
+        let mut noop_data = Vec::new();
+
+        for (index, instruction) in transaction.message.instructions().iter().enumerate() {
+            if is_noop(instruction.program_id_index) {
+                noop_data.push(instruction.data.clone());
+            }
+
+            for inner in inner_instructions.iter().filter(|inner| inner.index as usize == index) {
+                for instruction in &inner.instructions {
+                    if let UiInstruction::Compiled(compiled) = instruction {
+                        if is_noop(compiled.program_id_index) {
+                            noop_data.push(bs58::decode(&compiled.data).into_vec().map_err(|_| malformed("invalid instruction data"))?);
+                        }
+                    }
+                }
+            }
+        }
+
+        let logs = Option::from(meta.log_messages).unwrap_or_default();
+
+        Ok(Some(ConfirmedTransaction { slot: confirmed.slot, noop_data, logs }))
+    }
+}
+
+
+
+fn parse_signature(signature: &str) -> Result<Signature, CnftError> {
+    Signature::from_str(signature).map_err(|e| CnftError::rpc("serde", e))
+}
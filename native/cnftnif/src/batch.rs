@@ -0,0 +1,452 @@
+//! This module implements batched operations, packing many leaf changes into as few transactions as possible
+
+
+
+use std::thread;
+
+use solana_sdk::{hash::Hash, instruction::Instruction, message::Message, packet::PACKET_DATA_SIZE, pubkey::Pubkey, signature::Signature, signer::Signer, transaction::Transaction};
+use spl_merkle_tree_reference::MerkleTree;
+
+use crate::backend::backend;
+use crate::error::CnftError;
+use crate::hash::Metadata;
+use crate::result::OperationResult;
+use crate::retry::send_with_retry;
+use crate::setup::{send_and_confirm, signed_transaction, TreeManager};
+use crate::transaction::Operation;
+use crate::utils::parse_pubkey;
+
+
+
+/// Maximum number of Bubblegum instructions per transaction. Without a compute budget instruction every instruction
+/// is granted 200k compute units, and a transaction at most 1.4M.
+const MAX_INSTRUCTIONS_PER_TRANSACTION: usize = 7;
+
+
+/// Outcome of each item of a batch, in the order the items were given.
+pub type BatchResults = Vec<Result<OperationResult, CnftError>>;
+
+
+
+/// A transfer of a batch, taking the same arguments as `transfer_cnft`.
+pub struct BatchTransfer<'a> {
+    /// The signer of the current owner, which must also be the delegate.
+    pub old_owner: &'a dyn Signer,
+
+    /// The base58 public key of the new owner.
+    pub new_owner_pub_key: &'a str,
+
+    /// The index of the leaf.
+    pub index: usize,
+
+    /// The base58 hash of the metadata of the cNFT.
+    pub data_hash: &'a str,
+
+    /// The base58 hash of the creators of the cNFT.
+    pub creator_hash: &'a str
+}
+
+
+
+/// Instructions sent together in one transaction, along with the batch items they belong to.
+#[derive(Default)]
+struct Packed {
+    instructions: Vec<Instruction>,
+    items: Vec<usize>
+}
+
+
+
+impl TreeManager {
+
+    /// Mints a cNFT for every `(recipient, metadata)` entry, packing as many `MintV1` instructions per transaction
+    /// as fit, and submitting up to `max_buffer_size` transactions concurrently.
+    ///
+    /// Mints sent concurrently are appended in whatever order they land, so the leaf of every cNFT is taken
+    /// from the events of its confirmed transaction. Only leaves read back this way are applied to the local tree.
+    ///
+    /// # Parameters
+    ///
+    /// * `tree_owner` - The signer of the tree owner, paying for and authorizing every mint.
+    /// * `entries` - The base58 public key of the recipient and the metadata of every cNFT to mint.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(BatchResults)` - The result of every entry, in order. An entry fails on its own if its recipient or
+    ///   metadata is invalid, and together with the other entries of its transaction if the transaction fails.
+    ///   A confirmed mint fails with `CnftError::InvalidState` if the leaves of its transaction could not be read
+    ///   back, as the local tree cannot tell where they landed.
+    /// * `Err(CnftError::TreeFull)` - If the tree cannot hold every entry, checked before anything is submitted.
+    pub fn mint_batch(&mut self, tree_owner: &dyn Signer, entries: &[(String, Metadata)]) -> Result<BatchResults, CnftError> {
+        if entries.len() > self.remaining_capacity() {
+            return Err(CnftError::TreeFull { capacity: self.capacity() });
+        }
+
+        let payer = tree_owner.pubkey();
+        let tree_account = self.tree_pubkey()?;
+        let mut results: Vec<Option<Result<OperationResult, CnftError>>> = vec![None; entries.len()];
+
+        let mut instructions = Vec::new();
+        for (item, (recipient, metadata)) in entries.iter().enumerate() {
+            match self.batch_mint_instruction(&payer, recipient, metadata) {
+                Ok(instruction) => instructions.push((item, instruction)),
+                Err(e) => results[item] = Some(Err(e))
+            }
+        }
+
+        let (transactions, too_large) = pack(instructions, &payer, MAX_INSTRUCTIONS_PER_TRANSACTION);
+        for item in too_large {
+            results[item] = Some(Err(CnftError::invalid_argument("metadata", "the mint does not fit into a transaction")));
+        }
+
+        for wave in waves(&transactions, self.max_buffer_size) {
+            for (packed, outcome) in wave.iter().zip(submit_concurrently(wave, &payer, &[tree_owner])) {
+                let (signature, slot) = match outcome {
+                    Ok(confirmation) => confirmation,
+                    Err(e) => {
+                        packed.items.iter().for_each(|item| results[*item] = Some(Err(e.clone())));
+                        continue;
+                    }
+                };
+
+                let leaves: Vec<_> = match self.confirmed_leaves(&signature) {
+                    Ok(leaves) => leaves.into_iter()
+                        .filter_map(|update| update.nonce().map(|nonce| (update.index as usize, nonce, update.leaf_hash)))
+                        .collect(),
+                    Err(e) => {
+                        packed.items.iter().for_each(|item| results[*item] = Some(Err(e.clone())));
+                        continue;
+                    }
+                };
+                if leaves.len() != packed.items.len() {
+                    let error = CnftError::invalid_state("leaf", format!("the mint was confirmed in {signature} but its leaf could not be read back"));
+                    packed.items.iter().for_each(|item| results[*item] = Some(Err(error.clone())));
+                    continue;
+                }
+
+                for (item, (index, nonce, leaf_hash)) in packed.items.iter().zip(leaves) {
+                    self.apply_leaf_update(Operation::Mint, index, leaf_hash.to_vec());
+                    results[*item] = Some(Ok(OperationResult::leaf_change(signature.to_string(), slot, &tree_account, index, nonce, &leaf_hash)));
+                }
+            }
+        }
+
+        Ok(results.into_iter()
+            .map(|result| result.unwrap_or_else(|| Err(CnftError::invalid_state("batch", "the entry was not processed"))))
+            .collect())
+    }
+
+
+    /// Transfers many cNFTs, grouping the `Transfer` instructions into as few transactions as fit.
+    ///
+    /// Every proof is computed from a single snapshot of the local tree, updated with the new leaf of every
+    /// preceding transfer, so each instruction proves its leaf against the root the previous one leaves on chain.
+    /// Transactions are therefore sent one after the other, in order. If one fails, the proofs of the remaining
+    /// transfers no longer match the chain and are computed again from the confirmed leaves.
+    ///
+    /// # Parameters
+    ///
+    /// * `tree_owner` - The signer of the tree owner, used as fee payer.
+    /// * `transfers` - The transfers, in the order they are applied.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(BatchResults)` - The result of every transfer, in order. Confirmed transfers are recorded in the
+    ///   local `nodes`. A transfer fails on its own if its arguments are invalid, and together with the other
+    ///   transfers of its transaction if the transaction fails.
+    /// * `Err(CnftError)` - If the local tree cannot be rebuilt from the `nodes`.
+    pub fn transfer_batch(&mut self, tree_owner: &dyn Signer, transfers: &[BatchTransfer]) -> Result<BatchResults, CnftError> {
+        let payer = tree_owner.pubkey();
+        let tree_account = self.tree_pubkey()?;
+        let mut results: Vec<Option<Result<OperationResult, CnftError>>> = vec![None; transfers.len()];
+        let mut pending: Vec<usize> = (0..transfers.len()).collect();
+
+        while !pending.is_empty() {
+            let mut snapshot = self.off_chain_merkle_tree()?;
+            let mut leaves = vec![[0; 32]; transfers.len()];
+
+            let mut instructions = Vec::new();
+            for item in pending.drain(..) {
+                match self.batch_transfer_instruction(&snapshot, &payer, &transfers[item]) {
+                    Ok((instruction, leaf_hash)) => {
+                        snapshot.add_leaf(leaf_hash, transfers[item].index);
+                        leaves[item] = leaf_hash;
+                        instructions.push((item, instruction));
+                    }
+                    Err(e) => results[item] = Some(Err(e))
+                }
+            }
+
+            // Every instruction was checked to fit on its own, nothing is left out here.
+            let (transactions, _) = pack(instructions, &payer, MAX_INSTRUCTIONS_PER_TRANSACTION);
+
+            for (position, packed) in transactions.iter().enumerate() {
+                let mut signers: Vec<&dyn Signer> = vec![tree_owner];
+                for item in &packed.items {
+                    let old_owner = transfers[*item].old_owner;
+                    if signers.iter().all(|signer| signer.pubkey() != old_owner.pubkey()) {
+                        signers.push(old_owner);
+                    }
+                }
+
+                let confirmation = signed_transaction(&packed.instructions, &payer, &signers)
+                    .and_then(|transaction| send_and_confirm(&transaction));
+
+                let (signature, slot) = match confirmation {
+                    Ok(confirmation) => confirmation,
+                    Err(e) => {
+                        packed.items.iter().for_each(|item| results[*item] = Some(Err(e.clone())));
+                        pending = transactions[position + 1..].iter().flat_map(|packed| packed.items.clone()).collect();
+                        break;
+                    }
+                };
+
+                for item in &packed.items {
+                    let index = transfers[*item].index;
+                    self.apply_leaf_update(Operation::Transfer, index, leaves[*item].to_vec());
+                    results[*item] = Some(Ok(OperationResult::leaf_change(signature.clone(), slot, &tree_account, index, index as u64, &leaves[*item])));
+                }
+            }
+        }
+
+        Ok(results.into_iter()
+            .map(|result| result.unwrap_or_else(|| Err(CnftError::invalid_state("batch", "the entry was not processed"))))
+            .collect())
+    }
+
+
+    /// Builds the `Transfer` instruction of a batch entry against `snapshot`, along with the hash of the new leaf.
+    pub(crate) fn batch_transfer_instruction(&self, snapshot: &MerkleTree, payer: &Pubkey, transfer: &BatchTransfer) -> Result<(Instruction, [u8; 32]), CnftError> {
+        let new_owner = parse_pubkey(transfer.new_owner_pub_key, "new_owner_pub_key")?;
+        let proof = self.snapshot_proof_accounts(snapshot, transfer.index)?;
+
+        let (instruction, leaf) = self.transfer_instruction_with_proof(
+            &transfer.old_owner.pubkey(),
+            &new_owner,
+            transfer.index,
+            transfer.data_hash,
+            transfer.creator_hash,
+            proof
+        )?;
+
+        if !fits(std::slice::from_ref(&instruction), payer) {
+            return Err(CnftError::invalid_argument("index", "the transfer does not fit into a transaction"));
+        }
+
+        Ok((instruction, leaf.hash()))
+    }
+
+
+    /// Builds the `MintV1` instruction of a batch entry, checking what would make the whole transaction fail.
+    pub(crate) fn batch_mint_instruction(&self, tree_owner: &Pubkey, recipient: &str, metadata: &Metadata) -> Result<Instruction, CnftError> {
+        let recipient = parse_pubkey(recipient, "recipient")?;
+        let metadata = metadata.to_args()?;
+
+        if let Some(creator) = metadata.creators.iter().find(|creator| creator.verified && creator.address != *tree_owner) {
+            return Err(CnftError::invalid_argument("metadata", format!("verified creator {} would have to sign the mint", creator.address)));
+        }
+
+        self.mint_v1_instruction(tree_owner, &recipient, metadata)
+    }
+}
+
+
+
+
+/// Packs the instructions of batch items into as few transactions as fit, keeping their order.
+/// Returns the transactions, and the items whose instruction does not fit into a transaction on its own.
+fn pack(instructions: Vec<(usize, Instruction)>, payer: &Pubkey, max_per_transaction: usize) -> (Vec<Packed>, Vec<usize>) {
+    let mut transactions = Vec::new();
+    let mut too_large = Vec::new();
+    let mut current = Packed::default();
+
+    for (item, instruction) in instructions {
+        current.instructions.push(instruction);
+        current.items.push(item);
+        if current.items.len() <= max_per_transaction && fits(&current.instructions, payer) {
+            continue;
+        }
+
+        let (Some(instruction), Some(item)) = (current.instructions.pop(), current.items.pop()) else {
+            continue;
+        };
+        if !current.items.is_empty() {
+            transactions.push(std::mem::take(&mut current));
+        }
+
+        if fits(std::slice::from_ref(&instruction), payer) {
+            current = Packed { instructions: vec![instruction], items: vec![item] };
+        } else {
+            too_large.push(item);
+        }
+    }
+
+    if !current.items.is_empty() {
+        transactions.push(current);
+    }
+
+    (transactions, too_large)
+}
+
+
+/// Splits the transactions into waves sent concurrently, of at most `max_per_wave` transactions.
+/// Identical mints pack into identical transactions, which would share a signature under the same blockhash,
+/// so a wave also ends before a transaction already in it.
+fn waves(transactions: &[Packed], max_per_wave: usize) -> Vec<&[Packed]> {
+    let mut waves = Vec::new();
+    let mut start = 0;
+
+    for end in 0..transactions.len() {
+        let wave = &transactions[start..end];
+        if wave.len() >= max_per_wave.max(1) || wave.iter().any(|packed| packed.instructions == transactions[end].instructions) {
+            waves.push(wave);
+            start = end;
+        }
+    }
+
+    if start < transactions.len() {
+        waves.push(&transactions[start..]);
+    }
+
+    waves
+}
+
+
+/// Whether a transaction with these instructions stays within the packet size limit once signed.
+fn fits(instructions: &[Instruction], payer: &Pubkey) -> bool {
+    let transaction = Transaction::new_unsigned(Message::new(instructions, Some(payer)));
+
+    bincode::serialized_size(&transaction).is_ok_and(|size| size as usize <= PACKET_DATA_SIZE)
+}
+
+
+
+
+/// Signs the transactions of a wave with a fresh blockhash and sends them concurrently,
+/// returning the signature and slot of every transaction, in order.
+fn submit_concurrently(wave: &[Packed], payer: &Pubkey, signers: &[&dyn Signer]) -> Vec<Result<(Signature, u64), CnftError>> {
+    let blockhash = match backend().latest_blockhash() {
+        Ok(blockhash) => blockhash,
+        Err(e) => return wave.iter().map(|_| Err(e.clone())).collect()
+    };
+
+    // Signers are not `Sync`, so every transaction is signed here before the sends are spread over threads.
+    let transactions: Vec<Result<Transaction, CnftError>> = wave.iter()
+        .map(|packed| sign(&packed.instructions, payer, signers, blockhash))
+        .collect();
+
+    send_concurrently(&transactions)
+}
+
+
+/// Sends the transactions concurrently, one thread each, returning the signature and slot of every transaction
+/// in order. Transactions which could not be built are reported with their error.
+/// Signers cannot move to other threads, so transient failures are only retried with the same transaction.
+pub(crate) fn send_concurrently(transactions: &[Result<Transaction, CnftError>]) -> Vec<Result<(Signature, u64), CnftError>> {
+    thread::scope(|scope| {
+        let sends: Vec<_> = transactions.iter()
+            .map(|transaction| scope.spawn(move || {
+                let transaction = transaction.as_ref().map_err(Clone::clone)?;
+                send_with_retry(|| Ok(transaction.clone()))
+            }))
+            .collect();
+
+        sends.into_iter()
+            .map(|send| send.join().unwrap_or_else(|_| Err(CnftError::rpc("batch", "sending the transaction panicked"))))
+            .collect()
+    })
+}
+
+
+/// Signs the instructions into a transaction with the given blockhash.
+pub(crate) fn sign(instructions: &[Instruction], payer: &Pubkey, signers: &[&dyn Signer], blockhash: Hash) -> Result<Transaction, CnftError> {
+    let mut transaction = Transaction::new_unsigned(Message::new(instructions, Some(payer)));
+    transaction.try_sign(signers, blockhash)?;
+
+    Ok(transaction)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{instruction::Instruction, packet::PACKET_DATA_SIZE, pubkey::Pubkey};
+
+    use super::{fits, pack, waves, Packed};
+
+
+    fn instruction(data_len: usize, fill: u8) -> Instruction {
+        Instruction::new_with_bytes(mpl_bubblegum::ID, &vec![fill; data_len], vec![])
+    }
+
+
+    fn items(transactions: &[Packed]) -> Vec<Vec<usize>> {
+        transactions.iter().map(|packed| packed.items.clone()).collect()
+    }
+
+
+    #[test]
+    fn fits_within_the_packet_size() {
+        let payer = Pubkey::new_unique();
+
+        assert!(fits(&[instruction(100, 0)], &payer));
+        assert!(fits(&[instruction(PACKET_DATA_SIZE / 2, 0)], &payer));
+        assert!(!fits(&[instruction(PACKET_DATA_SIZE / 2, 0), instruction(PACKET_DATA_SIZE / 2, 1)], &payer));
+        assert!(!fits(&[instruction(PACKET_DATA_SIZE, 0)], &payer));
+    }
+
+
+    #[test]
+    fn pack_keeps_the_order_and_the_instruction_limit() {
+        let payer = Pubkey::new_unique();
+        let instructions = (0..5).map(|item| (item, instruction(10, item as u8))).collect();
+
+        let (transactions, too_large) = pack(instructions, &payer, 2);
+
+        assert_eq!(items(&transactions), vec![vec![0, 1], vec![2, 3], vec![4]]);
+        assert!(too_large.is_empty());
+    }
+
+
+    #[test]
+    fn pack_starts_a_transaction_when_the_next_instruction_does_not_fit() {
+        let payer = Pubkey::new_unique();
+        let instructions = vec![
+            (0, instruction(10, 0)),
+            (1, instruction(PACKET_DATA_SIZE * 2 / 3, 1)),
+            (2, instruction(PACKET_DATA_SIZE, 2)),
+            (3, instruction(PACKET_DATA_SIZE * 2 / 3, 3)),
+            (4, instruction(PACKET_DATA_SIZE * 2 / 3, 4))
+        ];
+
+        let (transactions, too_large) = pack(instructions, &payer, 7);
+
+        assert_eq!(items(&transactions), vec![vec![0, 1], vec![3], vec![4]]);
+        assert_eq!(too_large, vec![2]);
+    }
+
+
+    #[test]
+    fn waves_hold_at_most_max_per_wave_distinct_transactions() {
+        let payer = Pubkey::new_unique();
+        let instructions = (0..5).map(|item| (item, instruction(10, item as u8))).collect();
+        let (transactions, _) = pack(instructions, &payer, 1);
+
+        let sizes: Vec<usize> = waves(&transactions, 2).iter().map(|wave| wave.len()).collect();
+        assert_eq!(sizes, vec![2, 2, 1]);
+
+        let sizes: Vec<usize> = waves(&transactions, 0).iter().map(|wave| wave.len()).collect();
+        assert_eq!(sizes, vec![1; 5]);
+    }
+
+
+    #[test]
+    fn waves_end_before_an_identical_transaction() {
+        let payer = Pubkey::new_unique();
+        let instructions = [0, 1, 0, 2].iter().enumerate().map(|(item, fill)| (item, instruction(10, *fill))).collect();
+        let (transactions, _) = pack(instructions, &payer, 1);
+
+        let waves: Vec<Vec<Vec<usize>>> = waves(&transactions, 10).iter().map(|wave| items(wave)).collect();
+        assert_eq!(waves, vec![vec![vec![0], vec![1]], vec![vec![2], vec![3]]]);
+    }
+}
@@ -0,0 +1,510 @@
+//! This module implements a read-only client for the Digital Asset Standard (DAS) API
+
+
+
+use reqwest::blocking::Client;
+use rustler::NifStruct;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+
+use crate::error::CnftError;
+use crate::hash::Creator;
+use crate::proof::AssetProof;
+
+
+
+/// A cNFT as returned by the DAS `getAsset` method. Keys and hashes are base58 encoded.
+
+#[derive(NifStruct, Clone)]
+#[module = "CnftNif.DasAsset"]
+pub struct DasAsset {
+    /// Asset id.
+    pub id: String,
+
+    /// Asset interface, e.g. `V1_NFT`.
+    pub interface: String,
+
+    pub name: String,
+    pub symbol: String,
+
+    /// URI of the off-chain JSON metadata.
+    pub json_uri: String,
+
+    pub owner: String,
+
+    /// Delegate of the asset, `nil` if there is none.
+    pub delegate: Option<String>,
+
+    pub frozen: bool,
+    pub burnt: bool,
+    pub mutable: bool,
+
+    /// Whether the asset is compressed. The fields below are empty for uncompressed assets.
+    pub compressed: bool,
+
+    /// Address of the merkle tree holding the asset.
+    pub tree: String,
+
+    /// Index of the leaf holding the asset, which is also its nonce.
+    pub leaf_id: u64,
+
+    /// Sequence number of the last change of the leaf.
+    pub seq: u64,
+
+    pub data_hash: String,
+    pub creator_hash: String,
+
+    /// Hash of the leaf.
+    pub asset_hash: String,
+
+    /// Collection of the asset, `nil` if it has none.
+    pub collection: Option<String>,
+
+    pub creators: Vec<Creator>,
+
+    pub royalty_basis_points: u16
+}
+
+
+
+/// A page of assets, as returned by `getAssetsByOwner` and `getAssetsByGroup`.
+
+#[derive(NifStruct, Clone)]
+#[module = "CnftNif.DasAssetPage"]
+pub struct DasAssetPage {
+    /// Number of assets in this page.
+    pub total: u32,
+    pub limit: u32,
+    pub page: u32,
+    pub items: Vec<DasAsset>
+}
+
+
+
+/// A page of the transactions of an asset, as returned by `getSignaturesForAsset`.
+
+#[derive(NifStruct, Clone)]
+#[module = "CnftNif.DasSignaturePage"]
+pub struct DasSignaturePage {
+    /// Number of signatures in this page.
+    pub total: u32,
+    pub limit: u32,
+    pub page: u32,
+
+    /// Signatures with the name of the Bubblegum instruction, e.g. `{"5Ua...", "Transfer"}`, newest first.
+    pub items: Vec<(String, String)>
+}
+
+
+
+/// Client for a DAS compatible endpoint, e.g. an RPC provider or a local stub server.
+pub struct DasClient {
+    client: Client,
+    url: String
+}
+
+impl DasClient {
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: Client::new(),
+            url: url.to_string()
+        }
+    }
+
+
+    /// Fetches an asset by id.
+    pub fn get_asset(&self, asset_id: &str) -> Result<DasAsset, CnftError> {
+        self.send::<RawAsset>("getAsset", json!({ "id": asset_id })).map(DasAsset::from)
+    }
+
+
+    /// Fetches the proof of a compressed asset, in the same shape `get_proof` returns.
+    pub fn get_asset_proof(&self, asset_id: &str) -> Result<AssetProof, CnftError> {
+        self.send("getAssetProof", json!({ "id": asset_id }))
+    }
+
+
+    /// Fetches a page of the assets owned by `owner`. Pages start at 1.
+    pub fn get_assets_by_owner(&self, owner: &str, page: u32, limit: u32) -> Result<DasAssetPage, CnftError> {
+        self.send::<RawPage<RawAsset>>("getAssetsByOwner", json!({ "ownerAddress": owner, "page": page, "limit": limit }))
+            .map(|raw| DasAssetPage {
+                total: raw.total,
+                limit: raw.limit,
+                page: raw.page.unwrap_or(page),
+                items: raw.items.into_iter().map(DasAsset::from).collect()
+            })
+    }
+
+
+    /// Fetches a page of the assets of a group, e.g. `group_key` `"collection"` and the collection address as `group_value`.
+    pub fn get_assets_by_group(&self, group_key: &str, group_value: &str, page: u32, limit: u32) -> Result<DasAssetPage, CnftError> {
+        self.send::<RawPage<RawAsset>>("getAssetsByGroup", json!({ "groupKey": group_key, "groupValue": group_value, "page": page, "limit": limit }))
+            .map(|raw| DasAssetPage {
+                total: raw.total,
+                limit: raw.limit,
+                page: raw.page.unwrap_or(page),
+                items: raw.items.into_iter().map(DasAsset::from).collect()
+            })
+    }
+
+
+    /// Fetches a page of the transactions which changed a compressed asset.
+    pub fn get_signatures_for_asset(&self, asset_id: &str, page: u32, limit: u32) -> Result<DasSignaturePage, CnftError> {
+        self.send::<RawPage<(String, String)>>("getSignaturesForAsset", json!({ "id": asset_id, "page": page, "limit": limit }))
+            .map(|raw| DasSignaturePage {
+                total: raw.total,
+                limit: raw.limit,
+                page: raw.page.unwrap_or(page),
+                items: raw.items
+            })
+    }
+
+
+    /// Sends a DAS request. Unlike Solana RPC methods, DAS methods take their parameters by name,
+    /// which `RpcClient` does not support.
+    ///
+    /// Failures use the same `{:rpc, kind, message}` kinds as the RPC backend.
+    fn send<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T, CnftError> {
+        let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+
+        let response: RawResponse<T> = self.client.post(&self.url)
+            .json(&body)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .map_err(|e| match e {
+                e if e.is_timeout() => CnftError::rpc("timeout", e),
+                e if e.is_decode() => CnftError::rpc("serde", e),
+                e => CnftError::rpc("http", e)
+            })?;
+
+        match (response.result, response.error) {
+            (_, Some(error)) => Err(CnftError::rpc("rpc_response", format!("{method}: {} ({})", error.message, error.code))),
+            (Some(result), None) => Ok(result),
+            (None, None) => Err(CnftError::rpc("rpc_response", format!("{method}: no result")))
+        }
+    }
+}
+
+
+
+
+// The DAS responses, only keeping the fields `DasAsset` exposes.
+
+#[derive(Deserialize)]
+struct RawResponse<T> {
+    result: Option<T>,
+    error: Option<RawError>
+}
+
+#[derive(Deserialize)]
+struct RawError {
+    code: i64,
+    message: String
+}
+
+#[derive(Deserialize)]
+struct RawPage<T> {
+    total: u32,
+    limit: u32,
+    /// Absent from some responses, e.g. of `getSignaturesForAsset`, the requested page is reported then.
+    page: Option<u32>,
+    items: Vec<T>
+}
+
+
+#[derive(Deserialize)]
+struct RawAsset {
+    id: String,
+    #[serde(default)]
+    interface: String,
+    #[serde(default)]
+    content: RawContent,
+    #[serde(default)]
+    compression: RawCompression,
+    #[serde(default)]
+    grouping: Vec<RawGroup>,
+    #[serde(default)]
+    royalty: RawRoyalty,
+    #[serde(default)]
+    creators: Vec<RawCreator>,
+    ownership: RawOwnership,
+    #[serde(default)]
+    mutable: bool,
+    #[serde(default)]
+    burnt: bool
+}
+
+#[derive(Deserialize, Default)]
+struct RawContent {
+    #[serde(default)]
+    json_uri: String,
+    #[serde(default)]
+    metadata: RawMetadata
+}
+
+#[derive(Deserialize, Default)]
+struct RawMetadata {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    symbol: String
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct RawCompression {
+    compressed: bool,
+    tree: String,
+    leaf_id: u64,
+    seq: u64,
+    data_hash: String,
+    creator_hash: String,
+    asset_hash: String
+}
+
+#[derive(Deserialize)]
+struct RawGroup {
+    group_key: String,
+    group_value: String
+}
+
+#[derive(Deserialize, Default)]
+struct RawRoyalty {
+    #[serde(default)]
+    basis_points: u16
+}
+
+#[derive(Deserialize)]
+struct RawCreator {
+    address: String,
+    share: u8,
+    verified: bool
+}
+
+#[derive(Deserialize)]
+struct RawOwnership {
+    owner: String,
+    #[serde(default)]
+    delegate: Option<String>,
+    #[serde(default)]
+    frozen: bool
+}
+
+
+impl From<RawAsset> for DasAsset {
+    fn from(raw: RawAsset) -> Self {
+        DasAsset {
+            id: raw.id,
+            interface: raw.interface,
+            name: raw.content.metadata.name,
+            symbol: raw.content.metadata.symbol,
+            json_uri: raw.content.json_uri,
+            owner: raw.ownership.owner,
+            delegate: raw.ownership.delegate,
+            frozen: raw.ownership.frozen,
+            burnt: raw.burnt,
+            mutable: raw.mutable,
+            compressed: raw.compression.compressed,
+            tree: raw.compression.tree,
+            leaf_id: raw.compression.leaf_id,
+            seq: raw.compression.seq,
+            data_hash: raw.compression.data_hash,
+            creator_hash: raw.compression.creator_hash,
+            asset_hash: raw.compression.asset_hash,
+            collection: raw.grouping.into_iter()
+                .find(|group| group.group_key == "collection")
+                .map(|group| group.group_value),
+            creators: raw.creators.into_iter()
+                .map(|creator| Creator { address: creator.address, verified: creator.verified, share: creator.share })
+                .collect(),
+            royalty_basis_points: raw.royalty.basis_points
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use serde_json::{json, Value};
+
+    use super::DasClient;
+    use crate::error::CnftError;
+
+
+    /// Serves one JSON-RPC request on a local port with `response` as its result, returning the URL and the
+    /// handle yielding the request body.
+    fn stub(response: Value) -> (String, JoinHandle<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            let payload = response.to_string();
+            write!(
+                &stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{payload}",
+                payload.len()
+            ).unwrap();
+
+            serde_json::from_slice(&body).unwrap()
+        });
+
+        (url, handle)
+    }
+
+
+    fn result(result: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": 1, "result": result })
+    }
+
+
+    fn asset(id: &str) -> Value {
+        json!({
+            "id": id,
+            "interface": "V1_NFT",
+            "content": { "json_uri": "https://example.com/0.json", "metadata": { "name": "cNFT 0", "symbol": "CNFT" } },
+            "compression": {
+                "compressed": true,
+                "tree": "tree",
+                "leaf_id": 5,
+                "seq": 7,
+                "data_hash": "data_hash",
+                "creator_hash": "creator_hash",
+                "asset_hash": "asset_hash"
+            },
+            "grouping": [{ "group_key": "collection", "group_value": "collection" }],
+            "royalty": { "basis_points": 500 },
+            "creators": [{ "address": "creator", "share": 100, "verified": true }],
+            "ownership": { "owner": "owner", "delegate": null, "frozen": false },
+            "mutable": true,
+            "burnt": false
+        })
+    }
+
+
+    #[test]
+    fn get_asset() {
+        let (url, request) = stub(result(asset("asset")));
+
+        let asset = DasClient::new(&url).get_asset("asset").unwrap();
+        let request = request.join().unwrap();
+
+        assert_eq!(request["method"], "getAsset");
+        assert_eq!(request["params"], json!({ "id": "asset" }));
+        assert_eq!(asset.id, "asset");
+        assert_eq!(asset.name, "cNFT 0");
+        assert_eq!((asset.tree.as_str(), asset.leaf_id, asset.seq), ("tree", 5, 7));
+        assert_eq!(asset.delegate, None);
+        assert_eq!(asset.collection.as_deref(), Some("collection"));
+        assert_eq!(asset.creators.len(), 1);
+        assert_eq!(asset.royalty_basis_points, 500);
+    }
+
+
+    #[test]
+    fn get_asset_proof() {
+        let (url, request) = stub(result(json!({
+            "root": "root",
+            "proof": ["node_0", "node_1"],
+            "node_index": 16389,
+            "leaf": "leaf",
+            "tree_id": "tree"
+        })));
+
+        let proof = DasClient::new(&url).get_asset_proof("asset").unwrap();
+        let request = request.join().unwrap();
+
+        assert_eq!(request["method"], "getAssetProof");
+        assert_eq!(request["params"], json!({ "id": "asset" }));
+        assert_eq!(proof.root, "root");
+        assert_eq!(proof.proof, vec!["node_0", "node_1"]);
+        assert_eq!(proof.node_index, 16389);
+        assert_eq!(proof.leaf, "leaf");
+        assert_eq!(proof.tree_id, "tree");
+    }
+
+
+    #[test]
+    fn get_assets_by_owner() {
+        let (url, request) = stub(result(json!({ "total": 2, "limit": 10, "page": 1, "items": [asset("first"), asset("second")] })));
+
+        let page = DasClient::new(&url).get_assets_by_owner("owner", 1, 10).unwrap();
+        let request = request.join().unwrap();
+
+        assert_eq!(request["method"], "getAssetsByOwner");
+        assert_eq!(request["params"], json!({ "ownerAddress": "owner", "page": 1, "limit": 10 }));
+        assert_eq!((page.total, page.limit, page.page), (2, 10, 1));
+        assert_eq!(page.items.iter().map(|asset| asset.id.as_str()).collect::<Vec<_>>(), ["first", "second"]);
+    }
+
+
+    #[test]
+    fn get_assets_by_group() {
+        let (url, request) = stub(result(json!({ "total": 1, "limit": 5, "page": 2, "items": [asset("first")] })));
+
+        let page = DasClient::new(&url).get_assets_by_group("collection", "collection", 2, 5).unwrap();
+        let request = request.join().unwrap();
+
+        assert_eq!(request["method"], "getAssetsByGroup");
+        assert_eq!(request["params"], json!({ "groupKey": "collection", "groupValue": "collection", "page": 2, "limit": 5 }));
+        assert_eq!((page.total, page.limit, page.page), (1, 5, 2));
+        assert_eq!(page.items[0].collection.as_deref(), Some("collection"));
+    }
+
+
+    #[test]
+    fn get_signatures_for_asset() {
+        let (url, request) = stub(result(json!({
+            "total": 2,
+            "limit": 10,
+            "items": [["signature_1", "Transfer"], ["signature_0", "MintV1"]]
+        })));
+
+        let page = DasClient::new(&url).get_signatures_for_asset("asset", 1, 10).unwrap();
+        let request = request.join().unwrap();
+
+        assert_eq!(request["method"], "getSignaturesForAsset");
+        assert_eq!(request["params"], json!({ "id": "asset", "page": 1, "limit": 10 }));
+        assert_eq!(page.page, 1);
+        assert_eq!(page.items, vec![
+            ("signature_1".to_string(), "Transfer".to_string()),
+            ("signature_0".to_string(), "MintV1".to_string())
+        ]);
+    }
+
+
+    #[test]
+    fn error_response() {
+        let (url, request) = stub(json!({ "jsonrpc": "2.0", "id": 1, "error": { "code": -32000, "message": "Asset Not Found" } }));
+
+        let error = DasClient::new(&url).get_asset("asset").err();
+        request.join().unwrap();
+
+        assert!(matches!(error, Some(CnftError::Rpc { kind, message }) if kind == "rpc_response" && message.contains("Asset Not Found")));
+    }
+}
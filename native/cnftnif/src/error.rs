@@ -0,0 +1,500 @@
+//! This module defines the error returned by every NIF
+//!
+//! Errors are encoded as tagged tuples so Elixir code can pattern match on them:
+//!
+//! | Error | Elixir term |
+//! | ----- | ----------- |
+//! | `Rpc` | `{:rpc, kind, message}` e.g. `{:rpc, :blockhash_not_found, msg}` |
+//! | `Program` | `{:program, program, code, name, logs}` e.g. `{:program, :account_compression, 6001, "ConcurrentMerkleTreeError", logs}` |
+//! | `InvalidKey` | `{:invalid_key, field}` |
+//! | `InvalidArgument` | `{:invalid_argument, field, message}` |
+//! | `InvalidTransaction` | `{:invalid_transaction, message}` |
+//! | `InvalidState` | `{:invalid_state, field, message}` |
+//! | `TreeFull` | `{:tree_full, capacity}` |
+//! | `Signer` | `{:signer, message}` |
+//! | `ProofUnavailable` | `{:proof_unavailable, [{source, message}]}` e.g. `[{:local, msg}, {:das, msg}]` |
+
+
+
+use std::fmt;
+use std::str::FromStr;
+
+use mpl_bubblegum::errors::{MplBubblegumError, SplAccountCompressionError};
+use num_traits::FromPrimitive;
+use rustler::{Atom, Encoder, Env, Term};
+use solana_client::{client_error::{ClientError, ClientErrorKind}, rpc_custom_error, rpc_request::{RpcError, RpcResponseErrorData}};
+use solana_sdk::{instruction::InstructionError, pubkey::Pubkey, signer::SignerError, system_instruction::SystemError, transaction::{Transaction, TransactionError}};
+
+
+mod atoms {
+    rustler::atoms! {
+        rpc,
+        program,
+        invalid_key,
+        invalid_argument,
+        invalid_transaction,
+        invalid_state,
+        tree_full,
+        signer,
+        proof_unavailable
+    }
+}
+
+
+
+/// Error returned by every NIF.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CnftError {
+    /// A request to the RPC node failed, or the transaction was rejected outside of a program.
+    /// `kind` is a snake_case atom such as `blockhash_not_found` or `timeout`.
+    Rpc { kind: String, message: String },
+
+    /// A program returned a custom error code, decoded into the name of the error variant where known.
+    /// `logs` holds the log messages of the failed transaction, empty when they could not be retrieved.
+    Program { program: ProgramName, code: u32, name: String, logs: Vec<String> },
+
+    /// A public or private key parameter could not be decoded.
+    InvalidKey { field: String },
+
+    /// Any other parameter was malformed.
+    InvalidArgument { field: String, message: String },
+
+    /// A transaction handed back by the caller could not be decoded or verified.
+    InvalidTransaction { message: String },
+
+    /// A field of the `TreeManager` handed over by the caller is inconsistent, e.g. it was built by hand or corrupted.
+    InvalidState { field: String, message: String },
+
+    /// Every leaf of the tree is already minted. Returned before anything is submitted.
+    TreeFull { capacity: usize },
+
+    /// A signer failed to produce a signature.
+    Signer { message: String },
+
+    /// None of the proof sources could provide a valid proof. `attempts` holds each source with the reason it failed, in order.
+    ProofUnavailable { attempts: Vec<(String, String)> }
+}
+
+
+
+/// Programs whose custom errors are decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgramName {
+    Bubblegum,
+    AccountCompression,
+    System,
+    Unknown
+}
+
+impl ProgramName {
+
+    /// Identifies the program by its id.
+    pub fn from_id(program_id: &Pubkey) -> Self {
+        if *program_id == mpl_bubblegum::ID {
+            ProgramName::Bubblegum
+        } else if *program_id == spl_account_compression::ID {
+            ProgramName::AccountCompression
+        } else if *program_id == solana_sdk::system_program::ID {
+            ProgramName::System
+        } else {
+            ProgramName::Unknown
+        }
+    }
+
+
+    /// Name of the error variant of this program for a custom error code.
+    pub fn error_name(&self, code: u32) -> String {
+        let name = match self {
+            ProgramName::Bubblegum => MplBubblegumError::from_u32(code.wrapping_sub(6000)).map(|e| format!("{e:?}")),
+            ProgramName::AccountCompression => SplAccountCompressionError::from_u32(code.wrapping_sub(6000)).map(|e| format!("{e:?}")),
+            ProgramName::System => SystemError::from_u32(code).map(|e| format!("{e:?}")),
+            ProgramName::Unknown => None
+        };
+
+        name.unwrap_or_else(|| String::from("Unknown"))
+    }
+
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            ProgramName::Bubblegum => "bubblegum",
+            ProgramName::AccountCompression => "account_compression",
+            ProgramName::System => "system",
+            ProgramName::Unknown => "unknown"
+        }
+    }
+}
+
+
+
+impl CnftError {
+
+    /// A key parameter named `field` could not be decoded.
+    pub fn invalid_key(field: &str) -> Self {
+        CnftError::InvalidKey { field: field.to_string() }
+    }
+
+
+    /// A parameter named `field` was malformed.
+    pub fn invalid_argument(field: &str, message: impl fmt::Display) -> Self {
+        CnftError::InvalidArgument { field: field.to_string(), message: message.to_string() }
+    }
+
+
+    /// A transaction handed back by the caller could not be decoded or verified.
+    pub fn invalid_transaction(message: impl fmt::Display) -> Self {
+        CnftError::InvalidTransaction { message: message.to_string() }
+    }
+
+
+    /// The `TreeManager` field named `field` is inconsistent.
+    pub fn invalid_state(field: &str, message: impl fmt::Display) -> Self {
+        CnftError::InvalidState { field: field.to_string(), message: message.to_string() }
+    }
+
+
+    /// An RPC failure of the given kind.
+    pub fn rpc(kind: &str, message: impl fmt::Display) -> Self {
+        CnftError::Rpc { kind: kind.to_string(), message: message.to_string() }
+    }
+
+
+    /// Whether the failure is transient, i.e. the same operation may succeed if sent again: network failures,
+    /// an unhealthy node, an expired blockhash or a congested block. Program errors never are.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, CnftError::Rpc { kind, .. } if RETRYABLE_RPC_KINDS.contains(&kind.as_str()))
+    }
+
+
+    /// Whether Account Compression rejected the proof of a leaf, e.g. because the root it was built against
+    /// left the changelog buffer. The same operation may succeed with a proof resolved again from the chain.
+    pub fn is_stale_proof(&self) -> bool {
+        matches!(self, CnftError::Program { program: ProgramName::AccountCompression, code, .. }
+            if *code == 6000 + SplAccountCompressionError::ConcurrentMerkleTreeError as u32)
+    }
+
+
+    /// Classifies an RPC client error.
+    ///
+    /// When the error carries a `TransactionError` and `transaction` is given, custom instruction errors are
+    /// decoded, along with the logs of a failed preflight simulation, see `from_transaction_error`.
+    pub fn from_client_error(error: ClientError, transaction: Option<&Transaction>) -> Self {
+        if let Some(transaction_error) = error.get_transaction_error() {
+            let logs = match error.kind() {
+                ClientErrorKind::RpcError(RpcError::RpcResponseError {
+                    data: RpcResponseErrorData::SendTransactionPreflightFailure(simulation), ..
+                }) => simulation.logs.clone().unwrap_or_default(),
+                _ => Vec::new()
+            };
+
+            return Self::from_transaction_error(&transaction_error, transaction, &logs);
+        }
+
+        let message = error.to_string();
+        let kind = match error.kind() {
+            ClientErrorKind::Io(_) => "io",
+            ClientErrorKind::Reqwest(e) if e.is_timeout() => "timeout",
+            ClientErrorKind::Reqwest(_) => "http",
+            ClientErrorKind::RpcError(RpcError::ForUser(m)) if m.starts_with("unable to confirm transaction") => "unconfirmed",
+            ClientErrorKind::RpcError(RpcError::RpcResponseError { code, .. }) => rpc_response_kind(*code),
+            ClientErrorKind::RpcError(_) => "rpc_request",
+            ClientErrorKind::SerdeJson(_) => "serde",
+            ClientErrorKind::SigningError(e) => return CnftError::Signer { message: e.to_string() },
+            ClientErrorKind::TransactionError(_) => "transaction_error",
+            ClientErrorKind::Custom(_) => "custom"
+        };
+
+        Self::rpc(kind, message)
+    }
+
+
+    /// Classifies a transaction error, decoding custom program errors.
+    ///
+    /// A custom error is attributed to the program which raised it according to `logs`, since the error of a CPI,
+    /// e.g. Account Compression called by Bubblegum, is reported for the top-level instruction. Without logs it is
+    /// attributed to the program of the failing instruction of `transaction`. The logs are kept in the error.
+    pub fn from_transaction_error(error: &TransactionError, transaction: Option<&Transaction>, logs: &[String]) -> Self {
+        if let TransactionError::InstructionError(index, InstructionError::Custom(code)) = error {
+            let program_id = failing_program(logs, *code).or_else(|| transaction
+                .and_then(|txn| txn.message.instructions.get(*index as usize)
+                    .and_then(|ix| txn.message.account_keys.get(ix.program_id_index as usize).copied())));
+            let program = program_id.as_ref().map(ProgramName::from_id).unwrap_or(ProgramName::Unknown);
+
+            // Anchor programs log the name of their errors, which covers programs not decoded here.
+            let name = match program.error_name(*code) {
+                name if name == "Unknown" => anchor_error_name(logs, *code).unwrap_or(name),
+                name => name
+            };
+
+            return CnftError::Program { program, code: *code, name, logs: logs.to_vec() };
+        }
+
+        Self::rpc(&variant_name(error), error)
+    }
+}
+
+
+impl From<SignerError> for CnftError {
+    fn from(error: SignerError) -> Self {
+        CnftError::Signer { message: error.to_string() }
+    }
+}
+
+
+
+impl fmt::Display for CnftError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CnftError::Rpc { kind, message } => write!(f, "rpc error ({kind}): {message}"),
+            CnftError::Program { program, code, name, .. } => write!(f, "{} program error {code}: {name}", program.as_str()),
+            CnftError::InvalidKey { field } => write!(f, "invalid key: {field}"),
+            CnftError::InvalidArgument { field, message } => write!(f, "invalid {field}: {message}"),
+            CnftError::InvalidTransaction { message } => write!(f, "invalid transaction: {message}"),
+            CnftError::InvalidState { field, message } => write!(f, "invalid tree manager {field}: {message}"),
+            CnftError::TreeFull { capacity } => write!(f, "tree is full, all {capacity} leaves are minted"),
+            CnftError::Signer { message } => write!(f, "signer error: {message}"),
+            CnftError::ProofUnavailable { attempts } => {
+                write!(f, "no proof source provided a valid proof")?;
+                attempts.iter().try_for_each(|(source, message)| write!(f, "; {source}: {message}"))
+            }
+        }
+    }
+}
+
+impl std::error::Error for CnftError {}
+
+
+
+impl Encoder for CnftError {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            CnftError::Rpc { kind, message } =>
+                (atoms::rpc(), atom(env, kind), message).encode(env),
+            CnftError::Program { program, code, name, logs } =>
+                (atoms::program(), atom(env, program.as_str()), code, name, logs).encode(env),
+            CnftError::InvalidKey { field } =>
+                (atoms::invalid_key(), atom(env, field)).encode(env),
+            CnftError::InvalidArgument { field, message } =>
+                (atoms::invalid_argument(), atom(env, field), message).encode(env),
+            CnftError::InvalidTransaction { message } =>
+                (atoms::invalid_transaction(), message).encode(env),
+            CnftError::InvalidState { field, message } =>
+                (atoms::invalid_state(), atom(env, field), message).encode(env),
+            CnftError::TreeFull { capacity } =>
+                (atoms::tree_full(), capacity).encode(env),
+            CnftError::Signer { message } =>
+                (atoms::signer(), message).encode(env),
+            CnftError::ProofUnavailable { attempts } => {
+                let attempts: Vec<(Atom, &String)> = attempts.iter()
+                    .map(|(source, message)| (atom(env, source), message))
+                    .collect();
+                (atoms::proof_unavailable(), attempts).encode(env)
+            }
+        }
+    }
+}
+
+
+/// `Rpc` error kinds worth sending the transaction again for. `already_processed` is included so that the retry
+/// finds the transaction which landed in the meantime.
+const RETRYABLE_RPC_KINDS: &[&str] = &[
+    "io",
+    "timeout",
+    "http",
+    "unconfirmed",
+    "node_unhealthy",
+    "block_not_available",
+    "block_status_not_available_yet",
+    "min_context_slot_not_reached",
+    "blockhash_not_found",
+    "already_processed",
+    "account_in_use",
+    "cluster_maintenance",
+    "would_exceed_max_block_cost_limit",
+    "would_exceed_max_account_cost_limit",
+    "would_exceed_account_data_block_limit"
+];
+
+
+/// Kind of a JSON-RPC error response. Server errors the node answers while lagging behind the cluster get their own
+/// kind, any other response, e.g. invalid params or a failed preflight, is `rpc_response`.
+fn rpc_response_kind(code: i64) -> &'static str {
+    match code {
+        rpc_custom_error::JSON_RPC_SERVER_ERROR_NODE_UNHEALTHY => "node_unhealthy",
+        rpc_custom_error::JSON_RPC_SERVER_ERROR_BLOCK_NOT_AVAILABLE => "block_not_available",
+        rpc_custom_error::JSON_RPC_SERVER_ERROR_BLOCK_STATUS_NOT_AVAILABLE_YET => "block_status_not_available_yet",
+        rpc_custom_error::JSON_RPC_SERVER_ERROR_MIN_CONTEXT_SLOT_NOT_REACHED => "min_context_slot_not_reached",
+        _ => "rpc_response"
+    }
+}
+
+
+/// Atoms are only created from field names and error variant names, both of which are a bounded set.
+fn atom(env: Env, name: &str) -> Atom {
+    Atom::from_str(env, name).unwrap_or_else(|_| Atom::from_str(env, "unknown").expect("valid atom"))
+}
+
+
+/// The program which raised the custom error `code`, read from `logs`. The innermost failing program logs its failure
+/// first, so the program which raised the error is found before the ones which called it.
+fn failing_program(logs: &[String], code: u32) -> Option<Pubkey> {
+    let failure = format!(" failed: {}", InstructionError::Custom(code));
+
+    logs.iter()
+        .find_map(|line| line.strip_prefix("Program ")?.strip_suffix(&failure))
+        .and_then(|program_id| Pubkey::from_str(program_id).ok())
+}
+
+
+/// Name of the error `code` as logged by an Anchor program, e.g.
+/// `AnchorError occurred. Error Code: LeafAuthorityMustSign. Error Number: 6027. Error Message: ...`.
+fn anchor_error_name(logs: &[String], code: u32) -> Option<String> {
+    let number = format!(". Error Number: {code}.");
+
+    logs.iter().find_map(|line| {
+        let (prefix, _) = line.split_once(&number)?;
+        let (_, name) = prefix.rsplit_once("Error Code: ")?;
+        Some(name.to_string())
+    })
+}
+
+
+/// snake_case name of an enum variant, e.g. `BlockhashNotFound` becomes `blockhash_not_found`.
+fn variant_name(value: &impl fmt::Debug) -> String {
+    let debug = format!("{value:?}");
+    let name = debug.split(|c: char| !c.is_alphanumeric()).next().unwrap_or_default();
+
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use solana_client::{client_error::{ClientError, ClientErrorKind}, rpc_custom_error, rpc_request::{RpcError, RpcResponseErrorData}};
+    use solana_sdk::{instruction::{Instruction, InstructionError}, pubkey::Pubkey, signature::Keypair, signer::Signer, transaction::{Transaction, TransactionError}};
+
+    use super::{anchor_error_name, failing_program, rpc_response_kind, CnftError, ProgramName};
+
+
+    /// Code Account Compression fails with when it rejects a proof.
+    const CONCURRENT_MERKLE_TREE_ERROR: u32 = 6001;
+
+
+    fn custom_error(code: u32) -> TransactionError {
+        TransactionError::InstructionError(0, InstructionError::Custom(code))
+    }
+
+
+    /// Logs of Bubblegum failing because its CPI into Account Compression failed with `code`.
+    fn cpi_failure_logs(code: u32) -> Vec<String> {
+        vec![
+            format!("Program {} invoke [1]", mpl_bubblegum::ID),
+            format!("Program {} invoke [2]", spl_account_compression::ID),
+            format!("Program {} failed: {}", spl_account_compression::ID, InstructionError::Custom(code)),
+            format!("Program {} failed: {}", mpl_bubblegum::ID, InstructionError::Custom(code))
+        ]
+    }
+
+
+    fn rpc_response_error(code: i64) -> ClientError {
+        ClientErrorKind::RpcError(RpcError::RpcResponseError {
+            code,
+            message: "rejected".to_string(),
+            data: RpcResponseErrorData::Empty
+        }).into()
+    }
+
+
+    #[test]
+    fn failing_program_is_the_innermost_one() {
+        let logs = cpi_failure_logs(CONCURRENT_MERKLE_TREE_ERROR);
+
+        assert_eq!(failing_program(&logs, CONCURRENT_MERKLE_TREE_ERROR), Some(spl_account_compression::ID));
+        assert_eq!(failing_program(&logs, 6000), None);
+    }
+
+
+    #[test]
+    fn cpi_error_is_decoded_for_the_program_which_raised_it() {
+        let logs = cpi_failure_logs(CONCURRENT_MERKLE_TREE_ERROR);
+
+        let error = CnftError::from_transaction_error(&custom_error(CONCURRENT_MERKLE_TREE_ERROR), None, &logs);
+
+        assert_eq!(error, CnftError::Program {
+            program: ProgramName::AccountCompression,
+            code: CONCURRENT_MERKLE_TREE_ERROR,
+            name: "ConcurrentMerkleTreeError".to_string(),
+            logs
+        });
+        assert!(error.is_stale_proof());
+        assert!(!error.is_retryable());
+    }
+
+
+    #[test]
+    fn error_without_logs_is_attributed_to_the_failing_instruction() {
+        let payer = Keypair::new();
+        let instruction = Instruction::new_with_bytes(mpl_bubblegum::ID, &[], vec![]);
+        let transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+
+        let error = CnftError::from_transaction_error(&custom_error(6000), Some(&transaction), &[]);
+
+        assert!(matches!(&error, CnftError::Program { program: ProgramName::Bubblegum, name, .. } if name == "AssetOwnerMismatch"));
+        assert!(!error.is_stale_proof());
+        assert!(matches!(
+            CnftError::from_transaction_error(&custom_error(6000), None, &[]),
+            CnftError::Program { program: ProgramName::Unknown, .. }
+        ));
+    }
+
+
+    #[test]
+    fn anchor_error_names_programs_which_are_not_decoded() {
+        let program = Pubkey::new_unique();
+        let logs = vec![
+            format!("Program {program} invoke [1]"),
+            "Program log: AnchorError occurred. Error Code: SomethingWrong. Error Number: 6003. Error Message: Wrong.".to_string(),
+            format!("Program {program} failed: {}", InstructionError::Custom(6003))
+        ];
+
+        assert_eq!(anchor_error_name(&logs, 6003).as_deref(), Some("SomethingWrong"));
+        assert_eq!(anchor_error_name(&logs, 6004), None);
+        assert!(matches!(
+            CnftError::from_transaction_error(&custom_error(6003), None, &logs),
+            CnftError::Program { program: ProgramName::Unknown, name, .. } if name == "SomethingWrong"
+        ));
+    }
+
+
+    #[test]
+    fn other_transaction_errors_are_rpc_errors_named_after_their_variant() {
+        let error = CnftError::from_transaction_error(&TransactionError::BlockhashNotFound, None, &[]);
+
+        assert!(matches!(&error, CnftError::Rpc { kind, .. } if kind == "blockhash_not_found"));
+        assert!(error.is_retryable());
+        assert!(!CnftError::from_transaction_error(&TransactionError::AccountNotFound, None, &[]).is_retryable());
+    }
+
+
+    #[test]
+    fn only_lagging_node_responses_are_retried() {
+        assert_eq!(rpc_response_kind(rpc_custom_error::JSON_RPC_SERVER_ERROR_NODE_UNHEALTHY), "node_unhealthy");
+        assert_eq!(rpc_response_kind(rpc_custom_error::JSON_RPC_SERVER_ERROR_MIN_CONTEXT_SLOT_NOT_REACHED), "min_context_slot_not_reached");
+        assert_eq!(rpc_response_kind(-32602), "rpc_response");
+
+        assert!(CnftError::from_client_error(rpc_response_error(rpc_custom_error::JSON_RPC_SERVER_ERROR_NODE_UNHEALTHY), None).is_retryable());
+        assert!(!CnftError::from_client_error(rpc_response_error(-32602), None).is_retryable());
+    }
+}
@@ -0,0 +1,96 @@
+//! This module decodes the events Account Compression and Bubblegum log through the noop program
+
+
+
+use anchor_lang::AnchorDeserialize;
+use mpl_bubblegum::{types::LeafSchema, LeafSchemaEvent};
+use solana_sdk::pubkey::Pubkey;
+use spl_account_compression::events::{AccountCompressionEvent, ApplicationDataEvent, ChangeLogEvent, ChangeLogEventV1};
+
+
+
+/// A leaf written into a tree, as reported by the events of the transaction which wrote it.
+#[derive(Clone, Debug)]
+pub struct LeafUpdate {
+    /// Index of the leaf in the tree.
+    pub index: u32,
+
+    /// Hash of the leaf.
+    pub leaf_hash: [u8; 32],
+
+    /// The leaf fields Bubblegum logged along with the change, `None` if it logged none, e.g. for a burn.
+    pub schema: Option<LeafSchema>
+}
+
+impl LeafUpdate {
+
+    /// Nonce of the cNFT held by the leaf, `None` if Bubblegum logged no leaf schema for it.
+    pub fn nonce(&self) -> Option<u64> {
+        self.schema.as_ref().map(LeafSchema::nonce)
+    }
+}
+
+
+
+/// Decodes the Account Compression events among the data of noop instructions, skipping anything else.
+pub fn decode(noop_data: &[Vec<u8>]) -> Vec<AccountCompressionEvent> {
+    noop_data.iter()
+        .filter_map(|data| AccountCompressionEvent::try_from_slice(data).ok())
+        .collect()
+}
+
+
+
+
+/// Changelog events of the tree `merkle_tree` among the data of noop instructions, in the order they were logged.
+///
+/// Each changelog holds the path from the changed leaf up to the root: `path[0]` is the new leaf at `index`,
+/// and `seq` orders the changes of the tree.
+pub fn changelogs(noop_data: &[Vec<u8>], merkle_tree: &Pubkey) -> Vec<ChangeLogEventV1> {
+    decode(noop_data)
+        .into_iter()
+        .filter_map(|event| match event {
+            AccountCompressionEvent::ChangeLog(ChangeLogEvent::V1(changelog)) if changelog.id == *merkle_tree => Some(changelog),
+            _ => None
+        })
+        .collect()
+}
+
+
+
+
+/// Leaf schema events Bubblegum logged among the data of noop instructions, in the order they were logged.
+pub fn leaf_schema_events(noop_data: &[Vec<u8>]) -> Vec<LeafSchemaEvent> {
+    decode(noop_data)
+        .into_iter()
+        .filter_map(|event| match event {
+            AccountCompressionEvent::ApplicationData(ApplicationDataEvent::V1(data)) => LeafSchemaEvent::try_from_slice(&data.application_data).ok(),
+            _ => None
+        })
+        .collect()
+}
+
+
+
+
+/// Leaves written into the tree `merkle_tree` among the data of noop instructions, in the order they were written.
+///
+/// Each changelog is paired with the leaf schema event Bubblegum logged for the same leaf hash.
+pub fn leaf_updates(noop_data: &[Vec<u8>], merkle_tree: &Pubkey) -> Vec<LeafUpdate> {
+    let schemas = leaf_schema_events(noop_data);
+
+    changelogs(noop_data, merkle_tree)
+        .into_iter()
+        .filter_map(|changelog| {
+            let leaf_hash = changelog.path.first()?.node;
+
+            Some(LeafUpdate {
+                index: changelog.index,
+                leaf_hash,
+                schema: schemas.iter()
+                    .find(|event| event.leaf_hash == leaf_hash)
+                    .map(|event| event.schema.clone())
+            })
+        })
+        .collect()
+}
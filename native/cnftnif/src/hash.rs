@@ -0,0 +1,343 @@
+//! This module exposes the hashes and addresses Bubblegum derives, so they can be computed locally
+
+
+
+use mpl_bubblegum::{accounts::{TreeConfig, Voucher}, hash, types::{self, MetadataArgs}, utils::get_asset_id};
+use rustler::{NifStruct, NifUnitEnum};
+use solana_sdk::{bs58, pubkey::Pubkey};
+
+use crate::error::CnftError;
+use crate::utils::parse_pubkey;
+
+
+
+/// Seed of the PDA Bubblegum signs collection CPIs with.
+const BUBBLEGUM_SIGNER_SEED: &[u8] = b"collection_cpi";
+
+
+
+/// Metadata of a cNFT, mirroring Bubblegum's `MetadataArgs`. Keys are base58 encoded.
+
+#[derive(NifStruct, Clone)]
+#[module = "CnftNif.Metadata"]
+pub struct Metadata {
+    pub name: String,
+    pub symbol: String,
+    pub uri: String,
+    pub seller_fee_basis_points: u16,
+    pub primary_sale_happened: bool,
+    pub is_mutable: bool,
+    pub edition_nonce: Option<u8>,
+    pub token_standard: Option<TokenStandard>,
+    pub collection: Option<Collection>,
+    pub uses: Option<Uses>,
+    pub token_program_version: TokenProgramVersion,
+    pub creators: Vec<Creator>
+}
+
+
+
+/// A creator of a cNFT, `share` being its percentage of the royalties.
+
+#[derive(NifStruct, Clone)]
+#[module = "CnftNif.Creator"]
+pub struct Creator {
+    pub address: String,
+    pub verified: bool,
+    pub share: u8
+}
+
+
+
+/// The collection a cNFT belongs to.
+
+#[derive(NifStruct, Clone)]
+#[module = "CnftNif.Collection"]
+pub struct Collection {
+    pub verified: bool,
+    pub key: String
+}
+
+
+
+/// How a cNFT can be used.
+
+#[derive(NifStruct, Clone)]
+#[module = "CnftNif.Uses"]
+pub struct Uses {
+    pub use_method: UseMethod,
+    pub remaining: u64,
+    pub total: u64
+}
+
+
+
+#[derive(NifUnitEnum, Clone, Copy)]
+pub enum TokenStandard {
+    NonFungible,
+    FungibleAsset,
+    Fungible,
+    NonFungibleEdition
+}
+
+
+
+#[derive(NifUnitEnum, Clone, Copy)]
+pub enum TokenProgramVersion {
+    Original,
+    Token2022
+}
+
+
+
+#[derive(NifUnitEnum, Clone, Copy)]
+pub enum UseMethod {
+    Burn,
+    Multiple,
+    Single
+}
+
+
+
+impl Metadata {
+
+    /// Converts the metadata into Bubblegum's `MetadataArgs`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(MetadataArgs)` - The metadata with decoded keys.
+    /// * `Err(CnftError::InvalidKey)` - An error if a creator address or the collection key is not a valid public key.
+    pub fn to_args(&self) -> Result<MetadataArgs, CnftError> {
+        Ok(MetadataArgs {
+            name: self.name.clone(),
+            symbol: self.symbol.clone(),
+            uri: self.uri.clone(),
+            seller_fee_basis_points: self.seller_fee_basis_points,
+            primary_sale_happened: self.primary_sale_happened,
+            is_mutable: self.is_mutable,
+            edition_nonce: self.edition_nonce,
+            token_standard: self.token_standard.map(|standard| match standard {
+                TokenStandard::NonFungible => types::TokenStandard::NonFungible,
+                TokenStandard::FungibleAsset => types::TokenStandard::FungibleAsset,
+                TokenStandard::Fungible => types::TokenStandard::Fungible,
+                TokenStandard::NonFungibleEdition => types::TokenStandard::NonFungibleEdition
+            }),
+            collection: self.collection.as_ref()
+                .map(|collection| Ok::<_, CnftError>(types::Collection {
+                    verified: collection.verified,
+                    key: parse_pubkey(&collection.key, "collection")?
+                }))
+                .transpose()?,
+            uses: self.uses.as_ref().map(|uses| types::Uses {
+                use_method: match uses.use_method {
+                    UseMethod::Burn => types::UseMethod::Burn,
+                    UseMethod::Multiple => types::UseMethod::Multiple,
+                    UseMethod::Single => types::UseMethod::Single
+                },
+                remaining: uses.remaining,
+                total: uses.total
+            }),
+            token_program_version: match self.token_program_version {
+                TokenProgramVersion::Original => types::TokenProgramVersion::Original,
+                TokenProgramVersion::Token2022 => types::TokenProgramVersion::Token2022
+            },
+            creators: creators_to_args(&self.creators)?
+        })
+    }
+}
+
+
+
+
+/// Converts creators into Bubblegum's `Creator`, failing with `InvalidKey` on a malformed address.
+fn creators_to_args(creators: &[Creator]) -> Result<Vec<types::Creator>, CnftError> {
+    creators.iter()
+        .map(|creator| Ok(types::Creator {
+            address: parse_pubkey(&creator.address, "creators")?,
+            verified: creator.verified,
+            share: creator.share
+        }))
+        .collect()
+}
+
+
+
+
+/// Computes the base58 `data_hash` of a cNFT, as stored in its leaf.
+///
+/// # Errors
+///
+/// This function can fail if a key is malformed or the metadata cannot be serialized.
+pub fn metadata_hash(metadata: &Metadata) -> Result<String, CnftError> {
+    let data_hash = hash::hash_metadata(&metadata.to_args()?)
+        .map_err(|e| CnftError::invalid_argument("metadata", e))?;
+
+    Ok(bs58::encode(data_hash).into_string())
+}
+
+
+
+
+/// Computes the base58 `creator_hash` of a cNFT, as stored in its leaf.
+///
+/// # Errors
+///
+/// This function can fail if a creator address is malformed.
+pub fn creator_hash(creators: &[Creator]) -> Result<String, CnftError> {
+    Ok(bs58::encode(hash::hash_creators(&creators_to_args(creators)?)).into_string())
+}
+
+
+
+
+/// Derives the base58 asset id of the cNFT minted into `merkle_tree` with `nonce`.
+pub fn asset_id(merkle_tree: &str, nonce: u64) -> Result<String, CnftError> {
+    let merkle_tree = parse_pubkey(merkle_tree, "merkle_tree")?;
+
+    Ok(get_asset_id(&merkle_tree, nonce).to_string())
+}
+
+
+
+
+/// Derives the tree config PDA of `merkle_tree`, returning the base58 address and its bump.
+pub fn tree_config_pda(merkle_tree: &str) -> Result<(String, u8), CnftError> {
+    let merkle_tree = parse_pubkey(merkle_tree, "merkle_tree")?;
+    let (pda, bump) = TreeConfig::find_pda(&merkle_tree);
+
+    Ok((pda.to_string(), bump))
+}
+
+
+
+
+/// Derives the PDA Bubblegum signs collection CPIs with, returning the base58 address and its bump.
+pub fn bubblegum_signer_pda() -> (String, u8) {
+    let (pda, bump) = Pubkey::find_program_address(&[BUBBLEGUM_SIGNER_SEED], &mpl_bubblegum::ID);
+
+    (pda.to_string(), bump)
+}
+
+
+
+
+/// Derives the voucher PDA of the leaf minted into `merkle_tree` with `nonce`, returning the base58 address and its bump.
+pub fn voucher_pda(merkle_tree: &str, nonce: u64) -> Result<(String, u8), CnftError> {
+    let merkle_tree = parse_pubkey(merkle_tree, "merkle_tree")?;
+    let (pda, bump) = Voucher::find_pda(&merkle_tree, nonce);
+
+    Ok((pda.to_string(), bump))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{bs58, keccak, pubkey::Pubkey};
+
+    use crate::error::CnftError;
+
+    use super::{creator_hash, metadata_hash, Collection, Creator, Metadata, TokenProgramVersion, TokenStandard, UseMethod, Uses};
+
+
+    /// Keccak-256 of no input, Bubblegum's creator hash of a cNFT without creators.
+    const EMPTY_CREATOR_HASH: &str = "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470";
+
+
+    fn creators() -> Vec<Creator> {
+        vec![
+            Creator { address: Pubkey::new_from_array([1; 32]).to_string(), verified: true, share: 60 },
+            Creator { address: Pubkey::new_from_array([2; 32]).to_string(), verified: false, share: 40 }
+        ]
+    }
+
+
+    fn metadata() -> Metadata {
+        Metadata {
+            name: "Test".to_string(),
+            symbol: "TST".to_string(),
+            uri: "https://example.com".to_string(),
+            seller_fee_basis_points: 500,
+            primary_sale_happened: false,
+            is_mutable: true,
+            edition_nonce: Some(1),
+            token_standard: Some(TokenStandard::NonFungible),
+            collection: Some(Collection { verified: false, key: Pubkey::new_from_array([3; 32]).to_string() }),
+            uses: Some(Uses { use_method: UseMethod::Single, remaining: 1, total: 2 }),
+            token_program_version: TokenProgramVersion::Original,
+            creators: creators()
+        }
+    }
+
+
+    fn borsh_string(bytes: &mut Vec<u8>, value: &str) {
+        bytes.extend((value.len() as u32).to_le_bytes());
+        bytes.extend(value.as_bytes());
+    }
+
+
+    /// `MetadataArgs` of `metadata()` serialized by hand, field by field, as Bubblegum lays it out.
+    fn serialized_metadata() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        borsh_string(&mut bytes, "Test");
+        borsh_string(&mut bytes, "TST");
+        borsh_string(&mut bytes, "https://example.com");
+        bytes.extend(500u16.to_le_bytes());
+        bytes.extend([0, 1]);
+        bytes.extend([1, 1]);
+        bytes.extend([1, 0]);
+        bytes.extend([1, 0]);
+        bytes.extend([3; 32]);
+        bytes.extend([1, 2]);
+        bytes.extend(1u64.to_le_bytes());
+        bytes.extend(2u64.to_le_bytes());
+        bytes.push(0);
+        bytes.extend(2u32.to_le_bytes());
+        bytes.extend([1; 32]);
+        bytes.extend([1, 60]);
+        bytes.extend([2; 32]);
+        bytes.extend([0, 40]);
+        bytes
+    }
+
+
+    fn encode(hash: keccak::Hash) -> String {
+        bs58::encode(hash.to_bytes()).into_string()
+    }
+
+
+    fn hex(value: &str) -> Vec<u8> {
+        (0..value.len()).step_by(2).map(|i| u8::from_str_radix(&value[i..i + 2], 16).unwrap()).collect()
+    }
+
+
+    #[test]
+    fn metadata_hash_is_the_bubblegum_data_hash() {
+        let expected = keccak::hashv(&[&keccak::hash(&serialized_metadata()).to_bytes(), &500u16.to_le_bytes()]);
+
+        assert_eq!(metadata_hash(&metadata()).unwrap(), encode(expected));
+    }
+
+
+    #[test]
+    fn creator_hash_is_the_bubblegum_creator_hash() {
+        let expected = keccak::hashv(&[&[1; 32], &[1, 60], &[2; 32], &[0, 40]]);
+
+        assert_eq!(creator_hash(&creators()).unwrap(), encode(expected));
+        assert_eq!(
+            bs58::decode(creator_hash(&[]).unwrap()).into_vec().unwrap(),
+            hex(EMPTY_CREATOR_HASH)
+        );
+    }
+
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        let mut creators = creators();
+        creators[1].address = "not a key".to_string();
+        assert!(matches!(creator_hash(&creators), Err(CnftError::InvalidKey { field }) if field == "creators"));
+
+        let metadata = Metadata { collection: Some(Collection { verified: true, key: "not a key".to_string() }), ..metadata() };
+        assert!(matches!(metadata_hash(&metadata), Err(CnftError::InvalidKey { field }) if field == "collection"));
+    }
+}
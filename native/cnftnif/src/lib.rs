@@ -4,9 +4,22 @@
 //!
 //! ## Overview
 //! This library allows you to:
-//! - Initialize a Merkle tree for storing compressed NFTs.
-//! - Mint new compressed NFTs.
-//! - Transfer ownership of a compressed NFT.
+//! - Initialize a Merkle tree for storing compressed NFTs, or attach an existing one.
+//! - Mint new compressed NFTs, one at a time or in batches packed into as few transactions as possible.
+//! - Transfer ownership of a compressed NFT, taking proofs from local state, a DAS endpoint or the chain history,
+//!   or of many compressed NFTs at once.
+//! - Burn or delegate a compressed NFT.
+//! - Send operations without waiting for them and confirm them later, in bulk.
+//! - Submit many transfers, burns and delegations concurrently, up to the changelog buffer size of the tree.
+//! - Run every operation against a Solana RPC node or an in-memory simulated Solana.
+//! - Dry run an operation, simulating it for the compute units it consumes, its logs and errors without paying any fees.
+//! - Retry transient failures with a configurable policy, without ever applying an operation twice.
+//! - Look assets and proofs up through any DAS compatible endpoint.
+//! - Compute cNFT hashes, asset ids and Bubblegum PDAs locally.
+//! - Roll minting over to a fresh Merkle tree once the current one is nearly full.
+//! - Share a Merkle tree between many BEAM processes minting into it concurrently.
+//! - Notify Elixir processes of confirmations and Merkle tree changes through Solana PubSub.
+//! - Build unsigned transactions for wallet-side signing and submit them once signed.
 //!
 //! ## Installation
 //! Add `CnftNif` as a dependency in your `mix.exs`:
@@ -26,42 +39,112 @@
 //!
 //! **1. Initialize the TreeManager**
 //! ```elixir
-//! iex> {:ok, tree_manager} = CnftNif.tree_manager_init()
-//! iex> tree_manager
-//! %TreeManager{...}
+//! iex> tree_manager = CnftNif.tree_manager_init()
+//! %CnftNif.TreeManager{...}
 //! ```
 //!
 //! **2. Create a Merkle Tree**
 //! ```elixir
-//! iex> {:ok, tree_manager} = CnftNif.tree_manager_init()
-//! iex> {:ok, tree_manager, tx_hash} = CnftNif.create_merkle_tree(tree_manager, "owner_private_key")
-//! iex> IO.puts("Tree created with transaction: #{tx_hash}")
+//! iex> owner = CnftNif.keypair_generate()
+//! iex> {:ok, {tree_manager, result}} = CnftNif.create_merkle_tree(CnftNif.tree_manager_init(), owner)
+//! iex> IO.puts("Tree #{result.tree_address} created with transaction: #{result.signature}")
 //! ```
 //!
 //! **3. Mint a Compressed NFT**
 //! ```elixir
-//! iex> {:ok, tree_manager} = CnftNif.tree_manager_init()
-//! iex> {:ok, tree_manager, _} = CnftNif.create_merkle_tree(tree_manager, "owner_private_key")
-//! iex> {:ok, tree_manager, mint_hash} = CnftNif.mint_cnft(tree_manager, "owner_private_key", "nft_owner_pub_key")
-//! iex> IO.puts("NFT minted with transaction: #{mint_hash}")
+//! iex> owner = CnftNif.keypair_generate()
+//! iex> {:ok, {tree_manager, _}} = CnftNif.create_merkle_tree(CnftNif.tree_manager_init(), owner)
+//! iex> {:ok, {tree_manager, result}} = CnftNif.mint_cnft(tree_manager, owner, "nft_owner_pub_key")
+//! iex> IO.puts("NFT #{result.asset_id} minted with transaction: #{result.signature}")
 //! ```
 //!
 //! **4. Transfer a Compressed NFT**
 //! ```elixir
-//! iex> {:ok, tree_manager} = CnftNif.tree_manager_init()
-//! iex> {:ok, tree_manager, _} = CnftNif.create_merkle_tree(tree_manager, "owner_private_key")
-//! iex> {:ok, tree_manager, _} = CnftNif.mint_cnft(tree_manager, "owner_private_key", "nft_owner_pub_key")
-//! iex> {:ok, tree_manager, transfer_hash} = CnftNif.transfer_cnft(
-//! ...>   tree_manager, "tree_owner_key", "old_owner_key", "new_owner_pub_key", 1, "data_hash", "creator_hash"
+//! iex> owner = CnftNif.keypair_generate()
+//! iex> nft_owner = CnftNif.keypair_generate()
+//! iex> {:ok, {tree_manager, _}} = CnftNif.create_merkle_tree(CnftNif.tree_manager_init(), owner)
+//! iex> {:ok, {tree_manager, minted}} = CnftNif.mint_cnft(tree_manager, owner, CnftNif.keypair_pubkey(nft_owner))
+//! iex> {:ok, {tree_manager, result}} = CnftNif.transfer_cnft(
+//! ...>   tree_manager, owner, nft_owner, "new_owner_pub_key", minted.leaf_index, "data_hash", "creator_hash"
 //! ...> )
-//! iex> IO.puts("NFT transferred with transaction: #{transfer_hash}")
+//! iex> IO.puts("NFT transferred with transaction: #{result.signature}")
 //! ```
 
 
+pub mod backend;
+pub mod batch;
+pub mod das;
+pub mod error;
+pub mod events;
+pub mod hash;
+pub mod options;
+pub mod pending;
+pub mod pipeline;
+pub mod pool;
+pub mod proof;
+pub mod proof_source;
+pub mod result;
+pub mod retry;
 pub mod setup;
+pub mod shared;
+pub mod signer;
+pub mod simulation;
+pub mod simulator;
+pub mod subscription;
+pub mod transaction;
 pub mod utils;
 
-use setup::TreeManager;
+use backend::BackendSpec;
+use batch::{BatchResults, BatchTransfer};
+use das::{DasAsset, DasAssetPage, DasClient, DasSignaturePage};
+use error::CnftError;
+use hash::{Creator, Metadata};
+use pending::{PendingChange, PendingStatus};
+use pipeline::OperationSpec;
+use options::OperationOptions;
+use pool::{PoolMint, TreePool};
+use proof::{AssetProof, Leaf, LeafId, VerifyMode};
+use result::OperationResult;
+use retry::RetryPolicy;
+use rustler::{Atom, Binary, LocalPid, ResourceArc};
+use setup::{CreatedTree, TreeManager};
+use shared::SharedTreeManager;
+use signer::{KeypairResource, SignRequest, SignerSpec};
+use simulation::{BatchSimulations, Outcome, SimulationResult};
+use subscription::Subscription;
+use solana_sdk::{bs58, signature::{read_keypair_file, Keypair}, signer::Signer};
+use transaction::UnsignedTransaction;
+use utils::{parse_hash, safely_from_base58_string};
+
+
+mod atoms {
+    rustler::atoms! {
+        ok
+    }
+}
+
+
+
+#[rustler::nif]
+pub fn set_backend(backend: BackendSpec) -> Atom{
+    backend::set_backend(backend.build());
+    atoms::ok()
+}
+
+
+
+#[rustler::nif]
+pub fn set_retry_policy(policy: RetryPolicy) -> Result<Atom, CnftError>{
+    retry::set_retry_policy(policy)?;
+    Ok(atoms::ok())
+}
+
+
+
+#[rustler::nif]
+pub fn retry_policy() -> RetryPolicy{
+    retry::retry_policy()
+}
 
 
 
@@ -72,59 +155,717 @@ pub fn tree_manager_init () -> TreeManager{
 
 
 
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn tree_manager_attach(tree_address: &str, rebuild_nodes: bool) -> Result<TreeManager, CnftError>{
+    TreeManager::attach(tree_address, rebuild_nodes)
+}
 
-#[rustler::nif]
-pub fn create_merkle_tree(tree_manager: TreeManager, owner_private_key: &str) -> Result<(TreeManager, String), String>{
-    let mutable_tree_manager = &mut tree_manager.clone();
-    let txn_hash = mutable_tree_manager.create_tree(owner_private_key);
 
-    match txn_hash {
-        Ok(hash) => Ok((mutable_tree_manager.clone(), hash.to_string())),
-        Err(e) => Err(format!("Error: \n {e}"))
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn tree_manager_migrate(tree_manager: TreeManager) -> Result<TreeManager, CnftError>{
+    let (tree_manager, _) = update(tree_manager, TreeManager::migrate)?;
+
+    Ok(tree_manager)
+}
+
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn create_merkle_tree(tree_manager: TreeManager, owner: SignerSpec, options: OperationOptions) -> Result<Outcome<CreatedTree>, CnftError>{
+    let owner = owner.resolve("owner")?;
+
+    let outcome = update_or_simulate(tree_manager, &options,
+        |tree_manager| tree_manager.simulate_create_tree(&owner),
+        |tree_manager| tree_manager.create_tree(&owner)
+    )?;
+
+    Ok(match outcome {
+        Outcome::Applied((tree_manager, (result, tree_keypair))) if options.return_keypair =>
+            Outcome::Applied(CreatedTree::WithKeypair(tree_manager, result, tree_keypair.to_base58_string())),
+        Outcome::Applied((tree_manager, (result, _))) => Outcome::Applied(CreatedTree::Tree(tree_manager, result)),
+        Outcome::Simulated(simulation) => Outcome::Simulated(simulation)
+    })
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn mint_cnft(tree_manager: TreeManager,
+    owner: SignerSpec,
+    nft_owner_pub_key: &str,
+    options: OperationOptions
+    ) -> Result<Outcome<(TreeManager, OperationResult)>, CnftError>{
+    let owner = owner.resolve("owner")?;
+
+    update_or_simulate(tree_manager, &options,
+        |tree_manager| tree_manager.simulate_mint_cnft(&owner, nft_owner_pub_key),
+        |tree_manager| tree_manager.mint_cnft(&owner, nft_owner_pub_key)
+    )
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn mint_batch(tree_manager: TreeManager,
+    owner: SignerSpec,
+    entries: Vec<(String, Metadata)>,
+    options: OperationOptions
+    ) -> Result<Outcome<(TreeManager, BatchResults), BatchSimulations>, CnftError>{
+    let owner = owner.resolve("owner")?;
+
+    update_or_simulate(tree_manager, &options,
+        |tree_manager| tree_manager.simulate_mint_batch(&owner, &entries),
+        |tree_manager| tree_manager.mint_batch(&owner, &entries)
+    )
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+#[allow(clippy::too_many_arguments)]
+pub fn transfer_cnft(tree_manager: TreeManager,
+    owner: SignerSpec,
+    old_owner: SignerSpec,
+    new_owner_pub_key: &str,
+    index: usize,
+    data_hash: &str,
+    creator_hash: &str,
+    options: OperationOptions
+    ) -> Result<Outcome<(TreeManager, OperationResult)>, CnftError>{
+    let owner = owner.resolve("owner")?;
+    let old_owner = old_owner.resolve("old_owner")?;
+
+    match &options.sources {
+        Some(sources) => update_or_simulate(tree_manager, &options,
+            |tree_manager| tree_manager.simulate_transfer_cnft_with_sources(
+                &owner, &old_owner, new_owner_pub_key, index, data_hash, creator_hash, sources
+            ),
+            |tree_manager| tree_manager.transfer_cnft_with_sources(
+                &owner, &old_owner, new_owner_pub_key, index, data_hash, creator_hash, sources
+            )
+        ),
+        None => update_or_simulate(tree_manager, &options,
+            |tree_manager| tree_manager.simulate_transfer_cnft(&owner, &old_owner, new_owner_pub_key, index, data_hash, creator_hash),
+            |tree_manager| tree_manager.transfer_cnft(&owner, &old_owner, new_owner_pub_key, index, data_hash, creator_hash)
+        )
     }
+}
 
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn transfer_batch(tree_manager: TreeManager,
+    owner: SignerSpec,
+    transfers: Vec<(SignerSpec, String, usize, String, String)>,
+    options: OperationOptions
+    ) -> Result<Outcome<(TreeManager, BatchResults), BatchSimulations>, CnftError>{
+    let owner = owner.resolve("owner")?;
+    let old_owners = transfers.iter()
+        .map(|(old_owner, ..)| old_owner.resolve("old_owner"))
+        .collect::<Result<Vec<_>, CnftError>>()?;
+    let transfers: Vec<BatchTransfer> = transfers.iter()
+        .zip(&old_owners)
+        .map(|((_, new_owner_pub_key, index, data_hash, creator_hash), old_owner)| BatchTransfer {
+            old_owner,
+            new_owner_pub_key,
+            index: *index,
+            data_hash,
+            creator_hash
+        })
+        .collect();
+
+    update_or_simulate(tree_manager, &options,
+        |tree_manager| tree_manager.simulate_transfer_batch(&owner, &transfers),
+        |tree_manager| tree_manager.transfer_batch(&owner, &transfers)
+    )
 }
 
 
 
-#[rustler::nif]
-pub fn mint_cnft(tree_manager: TreeManager, owner_private_key: &str, nft_owner_pub_key: &str) -> Result<(TreeManager, String), String>{
-    let mutable_tree_manager = &mut tree_manager.clone();
-    let txn_hash = mutable_tree_manager.mint_cnft(owner_private_key, nft_owner_pub_key);
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn run_pipeline(tree_manager: TreeManager,
+    owner: SignerSpec,
+    operations: Vec<OperationSpec>,
+    options: OperationOptions
+    ) -> Result<Outcome<(TreeManager, BatchResults), BatchSimulations>, CnftError>{
+    let owner = owner.resolve("owner")?;
+    let owners = operations.iter()
+        .map(OperationSpec::resolve_owner)
+        .collect::<Result<Vec<_>, CnftError>>()?;
+    let operations: Vec<_> = operations.iter()
+        .zip(&owners)
+        .map(|(operation, owner)| operation.operation(owner))
+        .collect();
+
+    update_or_simulate(tree_manager, &options,
+        |tree_manager| tree_manager.simulate_pipeline(&owner, &operations),
+        |tree_manager| tree_manager.run_pipeline(&owner, &operations)
+    )
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn burn_cnft(tree_manager: TreeManager,
+    owner: SignerSpec,
+    nft_owner: SignerSpec,
+    index: usize,
+    data_hash: &str,
+    creator_hash: &str,
+    options: OperationOptions
+    ) -> Result<Outcome<(TreeManager, OperationResult)>, CnftError>{
+    let owner = owner.resolve("owner")?;
+    let nft_owner = nft_owner.resolve("nft_owner")?;
+
+    update_or_simulate(tree_manager, &options,
+        |tree_manager| tree_manager.simulate_burn_cnft(&owner, &nft_owner, index, data_hash, creator_hash),
+        |tree_manager| tree_manager.burn_cnft(&owner, &nft_owner, index, data_hash, creator_hash)
+    )
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+#[allow(clippy::too_many_arguments)]
+pub fn delegate_cnft(tree_manager: TreeManager,
+    owner: SignerSpec,
+    nft_owner: SignerSpec,
+    new_delegate_pub_key: &str,
+    index: usize,
+    data_hash: &str,
+    creator_hash: &str,
+    options: OperationOptions
+    ) -> Result<Outcome<(TreeManager, OperationResult)>, CnftError>{
+    let owner = owner.resolve("owner")?;
+    let nft_owner = nft_owner.resolve("nft_owner")?;
+
+    update_or_simulate(tree_manager, &options,
+        |tree_manager| tree_manager.simulate_delegate_cnft(&owner, &nft_owner, new_delegate_pub_key, index, data_hash, creator_hash),
+        |tree_manager| tree_manager.delegate_cnft(&owner, &nft_owner, new_delegate_pub_key, index, data_hash, creator_hash)
+    )
+}
 
-    match txn_hash {
-        Ok(hash) => Ok((mutable_tree_manager.clone(), hash.to_string())),
-        Err(e) => Err(format!("Error: \n {e}"))
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn mint_cnft_send_only(tree_manager: TreeManager, owner: SignerSpec, nft_owner_pub_key: &str, options: OperationOptions) -> Result<Outcome<(TreeManager, PendingChange)>, CnftError>{
+    let owner = owner.resolve("owner")?;
+
+    update_or_simulate(tree_manager, &options,
+        |tree_manager| tree_manager.simulate_mint_cnft(&owner, nft_owner_pub_key),
+        |tree_manager| tree_manager.mint_cnft_send_only(&owner, nft_owner_pub_key)
+    )
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+#[allow(clippy::too_many_arguments)]
+pub fn transfer_cnft_send_only(tree_manager: TreeManager,
+    owner: SignerSpec,
+    old_owner: SignerSpec,
+    new_owner_pub_key: &str,
+    index: usize,
+    data_hash: &str,
+    creator_hash: &str,
+    options: OperationOptions
+    ) -> Result<Outcome<PendingChange>, CnftError>{
+    if options.sources.is_some() {
+        return Err(CnftError::invalid_argument("sources", "transfer_cnft_send_only always proves the leaf against the local tree"));
     }
+    tree_manager.validate()?;
+    let owner = owner.resolve("owner")?;
+    let old_owner = old_owner.resolve("old_owner")?;
 
+    apply_or_simulate(&options,
+        || tree_manager.simulate_transfer_cnft(&owner, &old_owner, new_owner_pub_key, index, data_hash, creator_hash),
+        || tree_manager.transfer_cnft_send_only(&owner, &old_owner, new_owner_pub_key, index, data_hash, creator_hash)
+    )
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn burn_cnft_send_only(tree_manager: TreeManager,
+    owner: SignerSpec,
+    nft_owner: SignerSpec,
+    index: usize,
+    data_hash: &str,
+    creator_hash: &str,
+    options: OperationOptions
+    ) -> Result<Outcome<PendingChange>, CnftError>{
+    tree_manager.validate()?;
+    let owner = owner.resolve("owner")?;
+    let nft_owner = nft_owner.resolve("nft_owner")?;
+
+    apply_or_simulate(&options,
+        || tree_manager.simulate_burn_cnft(&owner, &nft_owner, index, data_hash, creator_hash),
+        || tree_manager.burn_cnft_send_only(&owner, &nft_owner, index, data_hash, creator_hash)
+    )
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+#[allow(clippy::too_many_arguments)]
+pub fn delegate_cnft_send_only(tree_manager: TreeManager,
+    owner: SignerSpec,
+    nft_owner: SignerSpec,
+    new_delegate_pub_key: &str,
+    index: usize,
+    data_hash: &str,
+    creator_hash: &str,
+    options: OperationOptions
+    ) -> Result<Outcome<PendingChange>, CnftError>{
+    tree_manager.validate()?;
+    let owner = owner.resolve("owner")?;
+    let nft_owner = nft_owner.resolve("nft_owner")?;
+
+    apply_or_simulate(&options,
+        || tree_manager.simulate_delegate_cnft(&owner, &nft_owner, new_delegate_pub_key, index, data_hash, creator_hash),
+        || tree_manager.delegate_cnft_send_only(&owner, &nft_owner, new_delegate_pub_key, index, data_hash, creator_hash)
+    )
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn confirm_pending(tree_manager: TreeManager, pending: Vec<PendingChange>) -> Result<(TreeManager, Vec<PendingStatus>), CnftError>{
+    update(tree_manager, |tree_manager| tree_manager.confirm_pending(&pending))
 }
 
 
 
 #[rustler::nif]
-pub fn transfer_cnft(tree_manager: TreeManager,
-    owner_private_key: &str, 
-    old_owner_private_key: &str, 
+pub fn remaining_capacity(tree_manager: TreeManager) -> Result<usize, CnftError>{
+    tree_manager.validate()?;
+    Ok(tree_manager.remaining_capacity())
+}
+
+
+
+#[rustler::nif(schedule = "DirtyCpu")]
+pub fn get_proof(tree_manager: TreeManager, leaf: LeafId, options: OperationOptions) -> Result<AssetProof, CnftError>{
+    tree_manager.validate()?;
+    tree_manager.asset_proof(&leaf, options.canopy_depth)
+}
+
+
+
+#[rustler::nif]
+pub fn verify_leaf_proof(leaf: Leaf, proof: Vec<String>, root: &str) -> Result<bool, CnftError>{
+    proof::verify_leaf_proof(&leaf, &proof, root)
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn verify_leaf_on_chain(tree_manager: TreeManager, payer: SignerSpec, leaf: LeafId, mode: VerifyMode) -> Result<bool, CnftError>{
+    tree_manager.validate()?;
+    let payer = payer.resolve("payer")?;
+
+    tree_manager.asset_proof(&leaf, 0)?.verify_on_chain(&payer, mode)
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn verify_proof_on_chain(payer: SignerSpec, proof: AssetProof, mode: VerifyMode) -> Result<bool, CnftError>{
+    let payer = payer.resolve("payer")?;
+
+    proof.verify_on_chain(&payer, mode)
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn das_get_asset(das_url: &str, asset_id: &str) -> Result<DasAsset, CnftError>{
+    DasClient::new(das_url).get_asset(asset_id)
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn das_get_asset_proof(das_url: &str, asset_id: &str) -> Result<AssetProof, CnftError>{
+    DasClient::new(das_url).get_asset_proof(asset_id)
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn das_get_assets_by_owner(das_url: &str, owner: &str, page: u32, limit: u32) -> Result<DasAssetPage, CnftError>{
+    DasClient::new(das_url).get_assets_by_owner(owner, page, limit)
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn das_get_assets_by_group(das_url: &str, group_key: &str, group_value: &str, page: u32, limit: u32) -> Result<DasAssetPage, CnftError>{
+    DasClient::new(das_url).get_assets_by_group(group_key, group_value, page, limit)
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn das_get_signatures_for_asset(das_url: &str, asset_id: &str, page: u32, limit: u32) -> Result<DasSignaturePage, CnftError>{
+    DasClient::new(das_url).get_signatures_for_asset(asset_id, page, limit)
+}
+
+
+
+#[rustler::nif]
+pub fn hash_metadata(metadata: Metadata) -> Result<String, CnftError>{
+    hash::metadata_hash(&metadata)
+}
+
+
+
+#[rustler::nif]
+pub fn hash_creators(creators: Vec<Creator>) -> Result<String, CnftError>{
+    hash::creator_hash(&creators)
+}
+
+
+
+#[rustler::nif]
+pub fn hash_leaf(leaf: Leaf) -> Result<String, CnftError>{
+    Ok(bs58::encode(leaf.hash()?).into_string())
+}
+
+
+
+#[rustler::nif]
+pub fn asset_id(merkle_tree: &str, nonce: u64) -> Result<String, CnftError>{
+    hash::asset_id(merkle_tree, nonce)
+}
+
+
+
+#[rustler::nif]
+pub fn tree_config_pda(merkle_tree: &str) -> Result<(String, u8), CnftError>{
+    hash::tree_config_pda(merkle_tree)
+}
+
+
+
+#[rustler::nif]
+pub fn bubblegum_signer_pda() -> (String, u8){
+    hash::bubblegum_signer_pda()
+}
+
+
+
+#[rustler::nif]
+pub fn voucher_pda(merkle_tree: &str, nonce: u64) -> Result<(String, u8), CnftError>{
+    hash::voucher_pda(merkle_tree, nonce)
+}
+
+
+
+#[rustler::nif]
+pub fn tree_pool_init(tree_manager: TreeManager, rollover_threshold: usize) -> Result<TreePool, CnftError>{
+    tree_manager.validate()?;
+    TreePool::new(tree_manager, rollover_threshold)
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn tree_pool_rollover(tree_pool: TreePool, owner: SignerSpec) -> Result<(TreePool, Option<OperationResult>), CnftError>{
+    let owner = owner.resolve("owner")?;
+
+    update_pool(tree_pool, |tree_pool| tree_pool.rollover(&owner))
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn tree_pool_mint_cnft(tree_pool: TreePool, owner: SignerSpec, nft_owner_pub_key: &str, options: OperationOptions) -> Result<Outcome<PoolMint>, CnftError>{
+    let owner = owner.resolve("owner")?;
+    tree_pool.validate()?;
+
+    if options.dry_run {
+        return Ok(Outcome::Simulated(tree_pool.simulate_mint_cnft(&owner, nft_owner_pub_key)?));
+    }
+
+    // The pool is handed back even if the mint fails, as a rollover before it may have created a tree.
+    let mut tree_pool = tree_pool;
+    let result = tree_pool.mint_cnft(&owner, nft_owner_pub_key);
+
+    Ok(Outcome::Applied((tree_pool, result)))
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn build_mint_cnft(tree_manager: TreeManager, owner_pub_key: &str, nft_owner_pub_key: &str) -> Result<UnsignedTransaction, CnftError>{
+    tree_manager.validate()?;
+    tree_manager.build_mint_cnft(owner_pub_key, nft_owner_pub_key)
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn build_transfer_cnft(tree_manager: TreeManager,
+    payer_pub_key: &str,
+    old_owner_pub_key: &str,
     new_owner_pub_key: &str,
     index: usize,
     data_hash: &str,
     creator_hash: &str
-    ) -> Result<(TreeManager, String), String>{
-    let mutable_tree_manager = &mut tree_manager.clone();
-    let txn_hash = mutable_tree_manager.transfer_cnft(
-        owner_private_key,
-        old_owner_private_key, 
-        new_owner_pub_key,
-        index,
-        data_hash,
-        creator_hash
-    );
-
-    match txn_hash {
-        Ok(hash) => Ok((mutable_tree_manager.clone(), hash.to_string())),
-        Err(e) => Err(format!("Error: \n {e}"))
+    ) -> Result<UnsignedTransaction, CnftError>{
+    tree_manager.validate()?;
+    tree_manager.build_transfer_cnft(payer_pub_key, old_owner_pub_key, new_owner_pub_key, index, data_hash, creator_hash)
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn build_burn_cnft(tree_manager: TreeManager,
+    payer_pub_key: &str,
+    nft_owner_pub_key: &str,
+    index: usize,
+    data_hash: &str,
+    creator_hash: &str
+    ) -> Result<UnsignedTransaction, CnftError>{
+    tree_manager.validate()?;
+    tree_manager.build_burn_cnft(payer_pub_key, nft_owner_pub_key, index, data_hash, creator_hash)
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn build_delegate_cnft(tree_manager: TreeManager,
+    payer_pub_key: &str,
+    nft_owner_pub_key: &str,
+    new_delegate_pub_key: &str,
+    index: usize,
+    data_hash: &str,
+    creator_hash: &str
+    ) -> Result<UnsignedTransaction, CnftError>{
+    tree_manager.validate()?;
+    tree_manager.build_delegate_cnft(payer_pub_key, nft_owner_pub_key, new_delegate_pub_key, index, data_hash, creator_hash)
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn sign_transaction(unsigned_transaction: UnsignedTransaction, signer: SignerSpec) -> Result<UnsignedTransaction, CnftError>{
+    let mut unsigned_transaction = unsigned_transaction;
+    let signer = signer.resolve("signer")?;
+
+    unsigned_transaction.sign(&signer)?;
+
+    Ok(unsigned_transaction)
+
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn submit_signed_transaction(tree_manager: TreeManager,
+    unsigned_transaction: UnsignedTransaction,
+    signed_transaction: &str
+    ) -> Result<(TreeManager, OperationResult), CnftError>{
+    update(tree_manager, |tree_manager| tree_manager.submit_signed_transaction(&unsigned_transaction, signed_transaction))
+}
+
+
+
+#[rustler::nif]
+pub fn shared_tree_manager_new(tree_manager: TreeManager) -> Result<ResourceArc<SharedTreeManager>, CnftError>{
+    Ok(ResourceArc::new(SharedTreeManager::new(tree_manager)?))
+}
+
+
+
+#[rustler::nif]
+pub fn shared_tree_manager_snapshot(shared: ResourceArc<SharedTreeManager>) -> TreeManager{
+    shared.snapshot()
+}
+
+
+
+#[rustler::nif]
+pub fn shared_pending_reservations(shared: ResourceArc<SharedTreeManager>) -> usize{
+    shared.pending_reservations()
+}
+
+
+
+#[rustler::nif]
+pub fn shared_reserve_leaf(shared: ResourceArc<SharedTreeManager>) -> Result<usize, CnftError>{
+    shared.reserve()
+}
+
+
+
+#[rustler::nif]
+pub fn shared_commit_leaf(shared: ResourceArc<SharedTreeManager>, reservation: usize, index: usize, leaf_hash: &str) -> Result<Atom, CnftError>{
+    let leaf_hash = parse_hash(leaf_hash, "leaf_hash")?;
+    shared.commit(reservation, index, leaf_hash.to_vec())?;
+
+    Ok(atoms::ok())
+}
+
+
+
+#[rustler::nif]
+pub fn shared_rollback_leaf(shared: ResourceArc<SharedTreeManager>, reservation: usize) -> Result<Atom, CnftError>{
+    shared.rollback(reservation)?;
+
+    Ok(atoms::ok())
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn shared_mint_cnft(shared: ResourceArc<SharedTreeManager>, owner: SignerSpec, nft_owner_pub_key: &str, options: OperationOptions) -> Result<Outcome<OperationResult>, CnftError>{
+    let owner = owner.resolve("owner")?;
+
+    apply_or_simulate(&options,
+        || shared.simulate_mint_cnft(&owner, nft_owner_pub_key),
+        || shared.mint_cnft(&owner, nft_owner_pub_key)
+    )
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn subscribe_signature(ws_url: &str, signature: &str, pid: LocalPid) -> Result<(ResourceArc<Subscription>, u64), CnftError>{
+    let subscription = subscription::subscribe_signature(ws_url, signature, pid)?;
+    let id = subscription.id();
+
+    Ok((ResourceArc::new(subscription), id))
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn subscribe_tree(ws_url: &str, tree_address: &str, pid: LocalPid) -> Result<(ResourceArc<Subscription>, u64), CnftError>{
+    let subscription = subscription::subscribe_tree(ws_url, tree_address, pid)?;
+    let id = subscription.id();
+
+    Ok((ResourceArc::new(subscription), id))
+}
+
+
+
+#[rustler::nif]
+pub fn unsubscribe(subscription: ResourceArc<Subscription>) -> Atom{
+    subscription.unsubscribe();
+    atoms::ok()
+}
+
+
+
+#[rustler::nif]
+pub fn keypair_generate() -> ResourceArc<KeypairResource>{
+    ResourceArc::new(KeypairResource(Keypair::new()))
+}
+
+
+
+#[rustler::nif]
+pub fn keypair_from_base58(private_key: &str) -> Result<ResourceArc<KeypairResource>, CnftError>{
+    safely_from_base58_string(private_key)
+        .map(|keypair| ResourceArc::new(KeypairResource(keypair)))
+        .map_err(|_| CnftError::invalid_key("private_key"))
+}
+
+
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn keypair_from_file(path: &str) -> Result<ResourceArc<KeypairResource>, CnftError>{
+    read_keypair_file(path)
+        .map(|keypair| ResourceArc::new(KeypairResource(keypair)))
+        .map_err(|e| CnftError::invalid_argument("path", e))
+}
+
+
+
+#[rustler::nif]
+pub fn keypair_pubkey(keypair: ResourceArc<KeypairResource>) -> String{
+    keypair.0.pubkey().to_string()
+}
+
+
+
+#[rustler::nif]
+pub fn sign_reply(request: ResourceArc<SignRequest>, signature: Binary) -> Atom{
+    request.answer(Ok(signature.as_slice().to_vec()));
+    atoms::ok()
+}
+
+
+
+#[rustler::nif]
+pub fn sign_reject(request: ResourceArc<SignRequest>, reason: String) -> Atom{
+    request.answer(Err(reason));
+    atoms::ok()
+}
+
+
+/// Validates `tree_manager` and applies `operation` to it, returning the updated `TreeManager` along with the result.
+fn update<T>(
+    tree_manager: TreeManager,
+    operation: impl FnOnce(&mut TreeManager) -> Result<T, CnftError>
+) -> Result<(TreeManager, T), CnftError> {
+    tree_manager.validate()?;
+
+    let mut tree_manager = tree_manager;
+    let result = operation(&mut tree_manager)?;
+
+    Ok((tree_manager, result))
+}
+
+
+/// Same as `update`, for a `TreePool`.
+fn update_pool<T>(
+    tree_pool: TreePool,
+    operation: impl FnOnce(&mut TreePool) -> Result<T, CnftError>
+) -> Result<(TreePool, T), CnftError> {
+    tree_pool.validate()?;
+
+    let mut tree_pool = tree_pool;
+    let result = operation(&mut tree_pool)?;
+
+    Ok((tree_pool, result))
+}
+
+
+/// Same as `update`, unless `options` ask for a dry run: then `simulate` runs instead and the `TreeManager` is dropped
+/// unchanged.
+fn update_or_simulate<T, S>(
+    tree_manager: TreeManager,
+    options: &OperationOptions,
+    simulate: impl FnOnce(&TreeManager) -> Result<S, CnftError>,
+    operation: impl FnOnce(&mut TreeManager) -> Result<T, CnftError>
+) -> Result<Outcome<(TreeManager, T), S>, CnftError> {
+    if options.dry_run {
+        tree_manager.validate()?;
+        return Ok(Outcome::Simulated(simulate(&tree_manager)?));
+    }
+
+    update(tree_manager, operation).map(Outcome::Applied)
+}
+
+
+/// Runs `operation`, or `simulate` instead if `options` ask for a dry run, for operations which return no
+/// updated state.
+fn apply_or_simulate<T>(
+    options: &OperationOptions,
+    simulate: impl FnOnce() -> Result<SimulationResult, CnftError>,
+    operation: impl FnOnce() -> Result<T, CnftError>
+) -> Result<Outcome<T>, CnftError> {
+    if options.dry_run {
+        return Ok(Outcome::Simulated(simulate()?));
     }
 
+    operation().map(Outcome::Applied)
 }
 
 
@@ -0,0 +1,59 @@
+//! This module defines the options keyword list taken by the last argument of every operation NIF
+
+
+
+use rustler::{types::atom::Atom, Decoder, NifResult, Term};
+
+use crate::proof_source::ProofSource;
+
+
+mod atoms {
+    rustler::atoms! {
+        dry_run,
+        return_keypair,
+        sources,
+        canopy_depth
+    }
+}
+
+
+
+/// Options an operation accepts as a trailing keyword list, e.g. `[dry_run: true]`. Every key defaults to the
+/// behaviour of the operation without options. Keys an operation has no use for are ignored, except `sources` on
+/// `transfer_cnft_send_only`, which is rejected rather than left unused. Unknown keys are rejected.
+#[derive(Clone, Default)]
+pub struct OperationOptions {
+    /// Simulate the transaction instead of sending it, see `simulation`.
+    pub dry_run: bool,
+
+    /// Also return the base58 keypair of a created tree.
+    pub return_keypair: bool,
+
+    /// Where to take the proof of a transferred leaf from, the local tree when `None`.
+    pub sources: Option<Vec<ProofSource>>,
+
+    /// Number of top proof nodes left out of a proof, as the canopy of the tree stores them.
+    pub canopy_depth: usize
+}
+
+impl<'a> Decoder<'a> for OperationOptions {
+    fn decode(term: Term<'a>) -> NifResult<Self> {
+        let mut options = OperationOptions::default();
+
+        for (key, value) in term.decode::<Vec<(Atom, Term)>>()? {
+            if key == atoms::dry_run() {
+                options.dry_run = value.decode()?;
+            } else if key == atoms::return_keypair() {
+                options.return_keypair = value.decode()?;
+            } else if key == atoms::sources() {
+                options.sources = Some(value.decode()?);
+            } else if key == atoms::canopy_depth() {
+                options.canopy_depth = value.decode()?;
+            } else {
+                return Err(rustler::Error::BadArg);
+            }
+        }
+
+        Ok(options)
+    }
+}
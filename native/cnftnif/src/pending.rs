@@ -0,0 +1,276 @@
+//! This module implements fire-and-forget operations, sent without waiting for their confirmation
+//! and confirmed later, in bulk, with `confirm_pending`
+
+
+
+use std::collections::{hash_map::Entry, HashMap};
+use std::str::FromStr;
+
+use rustler::{Encoder, Env, NifStruct, Term};
+use solana_sdk::{instruction::Instruction, signature::Signature, signer::Signer};
+use spl_account_compression::EMPTY;
+
+use crate::backend::backend;
+use crate::error::CnftError;
+use crate::result::OperationResult;
+use crate::retry::send_only_with_retry;
+use crate::setup::{signed_transaction, TreeManager};
+use crate::transaction::{decode_transaction, encode_transaction, Operation};
+use crate::utils::parse_pubkey;
+
+
+mod atoms {
+    rustler::atoms! {
+        confirmed,
+        pending,
+        failed
+    }
+}
+
+
+
+/// A transaction which was sent without waiting for its confirmation, along with the leaf change it applies.
+
+#[derive(NifStruct, Clone)]
+#[module = "CnftNif.PendingChange"]
+pub struct PendingChange {
+    /// Signature of the transaction.
+    pub signature: String,
+
+    /// Base64 encoded, bincode serialized transaction, e.g. to send it again.
+    pub transaction: String,
+
+    /// The kind of leaf change the transaction applies.
+    pub operation: Operation,
+
+    /// Index of the leaf changed by the transaction. For mints this is where the leaf is expected,
+    /// the index it lands at is read from the transaction once it is confirmed.
+    pub leaf_index: usize,
+
+    /// Hash of the leaf once the transaction is confirmed.
+    pub leaf_hash: Vec<u8>
+}
+
+
+
+/// Status of a pending change, encoded as `{:confirmed, result}`, `:pending` or `{:failed, error}`.
+pub enum PendingStatus {
+    /// The transaction is confirmed and its leaf change recorded.
+    Confirmed(OperationResult),
+
+    /// The transaction is not confirmed yet and may still land.
+    Pending,
+
+    /// The transaction failed, or its blockhash expired before it landed. Its leaf change is discarded.
+    Failed(CnftError)
+}
+
+impl Encoder for PendingStatus {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            PendingStatus::Confirmed(result) => (atoms::confirmed(), result).encode(env),
+            PendingStatus::Pending => atoms::pending().encode(env),
+            PendingStatus::Failed(error) => (atoms::failed(), error).encode(env)
+        }
+    }
+}
+
+
+
+impl TreeManager {
+
+    /// Sends a mint like `mint_cnft` without waiting for its confirmation.
+    ///
+    /// The mint is counted in `pending_mints` until `confirm_pending` reports it confirmed or failed, so every mint
+    /// sent meanwhile is built for the next nonce and is a distinct transaction, even to the same recipient under
+    /// the same blockhash. Its leaf is only recorded once confirmed.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(PendingChange)` - The signature of the sent transaction along with the leaf it is expected to mint.
+    /// * `Err(CnftError)` - `CnftError::TreeFull` if the tree is full, pending mints included, or an error if the
+    ///   transaction cannot be sent. Nothing is counted then.
+    pub fn mint_cnft_send_only(&mut self, tree_owner: &dyn Signer, nft_owner: &str) -> Result<PendingChange, CnftError> {
+        let nft_owner = parse_pubkey(nft_owner, "nft_owner")?;
+
+        let (mint_ix, leaf) = self.mint_instruction(&tree_owner.pubkey(), &nft_owner)?;
+
+        let change = send_only(mint_ix, &[tree_owner], Operation::Mint, leaf.nonce() as usize, leaf.hash())?;
+        self.pending_mints += 1;
+
+        Ok(change)
+    }
+
+
+    /// Sends a transfer like `transfer_cnft` without waiting for its confirmation.
+    ///
+    /// The proof is taken from the confirmed leaves, so at most one change of any one leaf may be pending.
+    /// Changes of distinct leaves may all be pending at once, up to the changelog buffer size of the tree.
+    pub fn transfer_cnft_send_only(
+        &self,
+        tree_owner: &dyn Signer,
+        old_owner: &dyn Signer,
+        new_owner_pub_key: &str,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str
+    ) -> Result<PendingChange, CnftError> {
+        let new_owner = parse_pubkey(new_owner_pub_key, "new_owner_pub_key")?;
+
+        let (transfer_ix, leaf) = self.transfer_instruction(&old_owner.pubkey(), &new_owner, index, data_hash, creator_hash)?;
+
+        send_only(transfer_ix, &[tree_owner, old_owner], Operation::Transfer, index, leaf.hash())
+    }
+
+
+    /// Sends a burn like `burn_cnft` without waiting for its confirmation, see `transfer_cnft_send_only`.
+    pub fn burn_cnft_send_only(
+        &self,
+        tree_owner: &dyn Signer,
+        owner: &dyn Signer,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str
+    ) -> Result<PendingChange, CnftError> {
+        let burn_ix = self.burn_instruction(&owner.pubkey(), index, data_hash, creator_hash)?;
+
+        send_only(burn_ix, &[tree_owner, owner], Operation::Burn, index, EMPTY)
+    }
+
+
+    /// Sends a delegation like `delegate_cnft` without waiting for its confirmation, see `transfer_cnft_send_only`.
+    pub fn delegate_cnft_send_only(
+        &self,
+        tree_owner: &dyn Signer,
+        owner: &dyn Signer,
+        new_delegate_pub_key: &str,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str
+    ) -> Result<PendingChange, CnftError> {
+        let new_delegate = parse_pubkey(new_delegate_pub_key, "new_delegate_pub_key")?;
+
+        let (delegate_ix, leaf) = self.delegate_instruction(&owner.pubkey(), &new_delegate, index, data_hash, creator_hash)?;
+
+        send_only(delegate_ix, &[tree_owner, owner], Operation::Delegate, index, leaf.hash())
+    }
+
+
+    /// Checks the status of every pending change in bulk, recording the leaf changes of the confirmed ones.
+    ///
+    /// Blockhashes are checked before signatures: a transaction whose blockhash expired and which is still unknown
+    /// afterwards never lands, so its change is reported as failed with a `blockhash_not_found` error and can be
+    /// discarded. Confirmed changes are recorded in the order they were processed, and mints which are confirmed or
+    /// failed no longer count in `pending_mints`.
+    ///
+    /// # Parameters
+    ///
+    /// * `pending` - Changes returned by the `*_send_only` operations of this tree.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<PendingStatus>)` - The status of every change, in order. Changes still `Pending` are to be checked again later.
+    /// * `Err(CnftError)` - If a change does not belong to this tree, the statuses cannot be fetched or the leaf of a
+    ///   confirmed mint cannot be read back. Nothing is recorded then, and the changes can be confirmed again.
+    pub fn confirm_pending(&mut self, pending: &[PendingChange]) -> Result<Vec<PendingStatus>, CnftError> {
+        let tree_account = self.tree_pubkey()?;
+        let mut transactions = Vec::with_capacity(pending.len());
+
+        for change in pending {
+            let transaction = decode_transaction(&change.transaction)?;
+            let signature = Signature::from_str(&change.signature)
+                .map_err(|e| CnftError::invalid_argument("signature", e))?;
+
+            if transaction.signatures.first() != Some(&signature) {
+                return Err(CnftError::invalid_argument("pending", format!("{signature} is not the signature of its transaction")));
+            }
+            if change.leaf_index >= self.capacity() || change.leaf_hash.len() != 32 {
+                return Err(CnftError::invalid_argument("pending", "leaf change does not fit this tree"));
+            }
+            if !transaction.message.account_keys.contains(&tree_account) {
+                return Err(CnftError::invalid_argument("pending", format!("{signature} does not change this tree")));
+            }
+
+            transactions.push((signature, transaction));
+        }
+
+        let mut expired = HashMap::new();
+        for (_, transaction) in &transactions {
+            if let Entry::Vacant(entry) = expired.entry(transaction.message.recent_blockhash) {
+                let valid = backend().is_blockhash_valid(entry.key())?;
+                entry.insert(!valid);
+            }
+        }
+
+        let signatures: Vec<Signature> = transactions.iter().map(|(signature, _)| *signature).collect();
+        let statuses = backend().signature_statuses(&signatures)?;
+
+        let mut results: Vec<PendingStatus> = Vec::with_capacity(pending.len());
+        let mut confirmed = Vec::new();
+
+        for (item, ((signature, transaction), status)) in transactions.iter().zip(statuses).enumerate() {
+            match status {
+                Some((slot, None)) => {
+                    confirmed.push((slot, item));
+                    results.push(PendingStatus::Pending);
+                }
+                Some((_, Some(e))) => results.push(PendingStatus::Failed(CnftError::from_transaction_error(&e, Some(transaction), &backend().transaction_logs(signature)))),
+                None if expired[&transaction.message.recent_blockhash] => results.push(PendingStatus::Failed(
+                    CnftError::rpc("blockhash_not_found", format!("transaction {signature} expired before it landed"))
+                )),
+                None => results.push(PendingStatus::Pending)
+            }
+        }
+
+        confirmed.sort_by_key(|(slot, _)| *slot);
+
+        for (slot, item) in confirmed {
+            let change = &pending[item];
+            let signature = &signatures[item];
+
+            // A mint only fixes its leaf once processed, so the expected leaf is replaced by the one it reports.
+            // Every other operation proves the leaf of nonce `leaf_index`.
+            let (index, nonce, leaf_hash) = match change.operation {
+                Operation::Mint => self.minted_leaf(signature)?,
+                _ => (change.leaf_index, change.leaf_index as u64, change.leaf_hash.clone())
+            };
+
+            self.apply_leaf_update(change.operation, index, leaf_hash.clone());
+            results[item] = PendingStatus::Confirmed(self.operation_result((signature.to_string(), slot), index, nonce, &leaf_hash)?);
+        }
+
+        let settled_mints = pending.iter()
+            .zip(&results)
+            .filter(|(change, status)| change.operation == Operation::Mint && !matches!(status, PendingStatus::Pending))
+            .count();
+        self.pending_mints = self.pending_mints.saturating_sub(settled_mints);
+
+        Ok(results)
+    }
+}
+
+
+
+
+/// Signs `instruction` with `signers`, the first of which pays the fees, and sends it without waiting for its
+/// confirmation. Transient failures of the send itself are retried, see `retry::send_only_with_retry`.
+fn send_only(
+    instruction: Instruction,
+    signers: &[&dyn Signer],
+    operation: Operation,
+    leaf_index: usize,
+    leaf_hash: [u8; 32]
+) -> Result<PendingChange, CnftError> {
+    let payer = signers[0].pubkey();
+    let transaction = signed_transaction(&[instruction], &payer, signers)?;
+
+    let signature = send_only_with_retry(&transaction)?;
+
+    Ok(PendingChange {
+        signature: signature.to_string(),
+        transaction: encode_transaction(&transaction)?,
+        operation,
+        leaf_index,
+        leaf_hash: leaf_hash.to_vec()
+    })
+}
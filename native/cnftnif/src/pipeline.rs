@@ -0,0 +1,364 @@
+//! This module implements the operation pipeline, submitting many leaf changes concurrently against the same root
+
+
+
+use std::collections::{HashSet, VecDeque};
+
+use rustler::{types::tuple::get_tuple, Atom, Decoder, NifResult, Term};
+use solana_sdk::{instruction::Instruction, signer::Signer};
+use spl_account_compression::EMPTY;
+use spl_merkle_tree_reference::MerkleTree;
+
+use crate::backend::backend;
+use crate::batch::{send_concurrently, sign, BatchResults, BatchTransfer};
+use crate::error::CnftError;
+use crate::proof_source::accepted_roots;
+use crate::result::OperationResult;
+use crate::setup::TreeManager;
+use crate::signer::{NifSigner, SignerSpec};
+use crate::transaction::Operation;
+use crate::utils::parse_pubkey;
+
+
+mod atoms {
+    rustler::atoms! {
+        transfer,
+        burn,
+        delegate
+    }
+}
+
+
+
+/// Number of times an operation is sent before its error is returned, as long as it failed because the root
+/// of its proof fell out of the changelog buffer of the tree.
+const MAX_PROOF_ATTEMPTS: usize = 3;
+
+
+
+/// A change of an existing leaf, taking the same arguments as the matching `TreeManager` operation.
+pub enum PipelineOperation<'a> {
+    Transfer(BatchTransfer<'a>),
+
+    Burn {
+        owner: &'a dyn Signer,
+        index: usize,
+        data_hash: &'a str,
+        creator_hash: &'a str
+    },
+
+    Delegate {
+        owner: &'a dyn Signer,
+        new_delegate_pub_key: &'a str,
+        index: usize,
+        data_hash: &'a str,
+        creator_hash: &'a str
+    }
+}
+
+impl PipelineOperation<'_> {
+
+    /// Index of the leaf changed by the operation.
+    pub fn index(&self) -> usize {
+        match self {
+            PipelineOperation::Transfer(transfer) => transfer.index,
+            PipelineOperation::Burn { index, .. } | PipelineOperation::Delegate { index, .. } => *index
+        }
+    }
+
+
+    /// Signer of the current owner of the leaf.
+    pub(crate) fn owner(&self) -> &dyn Signer {
+        match self {
+            PipelineOperation::Transfer(transfer) => transfer.old_owner,
+            PipelineOperation::Burn { owner, .. } | PipelineOperation::Delegate { owner, .. } => *owner
+        }
+    }
+
+
+    fn operation(&self) -> Operation {
+        match self {
+            PipelineOperation::Transfer(_) => Operation::Transfer,
+            PipelineOperation::Burn { .. } => Operation::Burn,
+            PipelineOperation::Delegate { .. } => Operation::Delegate
+        }
+    }
+}
+
+
+
+/// Operation as described by the Elixir caller:
+/// `{:transfer, old_owner, new_owner_pub_key, index, data_hash, creator_hash}`,
+/// `{:burn, owner, index, data_hash, creator_hash}` or
+/// `{:delegate, owner, new_delegate_pub_key, index, data_hash, creator_hash}`.
+pub enum OperationSpec {
+    Transfer(SignerSpec, String, usize, String, String),
+    Burn(SignerSpec, usize, String, String),
+    Delegate(SignerSpec, String, usize, String, String)
+}
+
+impl<'a> Decoder<'a> for OperationSpec {
+    fn decode(term: Term<'a>) -> NifResult<Self> {
+        let tuple = get_tuple(term)?;
+        let tag: Atom = tuple.first().ok_or(rustler::Error::BadArg)?.decode()?;
+
+        match tuple.len() {
+            6 if tag == atoms::transfer() => Ok(OperationSpec::Transfer(
+                tuple[1].decode()?, tuple[2].decode()?, tuple[3].decode()?, tuple[4].decode()?, tuple[5].decode()?
+            )),
+            5 if tag == atoms::burn() => Ok(OperationSpec::Burn(
+                tuple[1].decode()?, tuple[2].decode()?, tuple[3].decode()?, tuple[4].decode()?
+            )),
+            6 if tag == atoms::delegate() => Ok(OperationSpec::Delegate(
+                tuple[1].decode()?, tuple[2].decode()?, tuple[3].decode()?, tuple[4].decode()?, tuple[5].decode()?
+            )),
+            _ => Err(rustler::Error::BadArg)
+        }
+    }
+}
+
+impl OperationSpec {
+
+    /// Resolves the signer of the current owner of the leaf.
+    pub fn resolve_owner(&self) -> Result<NifSigner, CnftError> {
+        match self {
+            OperationSpec::Transfer(old_owner, ..) => old_owner.resolve("old_owner"),
+            OperationSpec::Burn(owner, ..) | OperationSpec::Delegate(owner, ..) => owner.resolve("owner")
+        }
+    }
+
+
+    /// The operation described, signed by `owner`, which must have been resolved from this description.
+    pub fn operation<'a>(&'a self, owner: &'a dyn Signer) -> PipelineOperation<'a> {
+        match self {
+            OperationSpec::Transfer(_, new_owner_pub_key, index, data_hash, creator_hash) => PipelineOperation::Transfer(BatchTransfer {
+                old_owner: owner,
+                new_owner_pub_key,
+                index: *index,
+                data_hash,
+                creator_hash
+            }),
+            OperationSpec::Burn(_, index, data_hash, creator_hash) => PipelineOperation::Burn {
+                owner,
+                index: *index,
+                data_hash,
+                creator_hash
+            },
+            OperationSpec::Delegate(_, new_delegate_pub_key, index, data_hash, creator_hash) => PipelineOperation::Delegate {
+                owner,
+                new_delegate_pub_key,
+                index: *index,
+                data_hash,
+                creator_hash
+            }
+        }
+    }
+}
+
+
+
+impl TreeManager {
+
+    /// Submits the operations concurrently, one transaction each, in waves of up to `max_buffer_size`
+    /// operations proving their leaf against the same root.
+    ///
+    /// The on-chain tree fast-forwards a proof through the changes made since its root, as long as that root
+    /// is still in the changelog buffer. So every wave is built from the confirmed leaves, and the next one starts
+    /// once every operation of the wave is settled. An operation on a leaf which already has one in flight waits
+    /// for the next wave, since it depends on the outcome of the first.
+    ///
+    /// An operation whose proof is rejected because other changes pushed its root out of the changelog buffer is
+    /// sent again with a fresh proof, up to `MAX_PROOF_ATTEMPTS` times. Any other failure is returned as is.
+    ///
+    /// # Parameters
+    ///
+    /// * `tree_owner` - The signer of the tree owner, used as fee payer.
+    /// * `operations` - The operations, in the order they are applied to any one leaf.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(BatchResults)` - The result of every operation, in order. Confirmed operations are recorded in the
+    ///   local `nodes`.
+    /// * `Err(CnftError)` - If the local tree cannot be rebuilt from the `nodes`.
+    pub fn run_pipeline(&mut self, tree_owner: &dyn Signer, operations: &[PipelineOperation]) -> Result<BatchResults, CnftError> {
+        let payer = tree_owner.pubkey();
+        let tree_account = self.tree_pubkey()?;
+        let mut results: Vec<Option<Result<OperationResult, CnftError>>> = vec![None; operations.len()];
+        let mut attempts = vec![0; operations.len()];
+        let mut pending: VecDeque<usize> = (0..operations.len()).collect();
+
+        while !pending.is_empty() {
+            let snapshot = self.off_chain_merkle_tree()?;
+            let wave = next_wave(&mut pending, operations, self.max_buffer_size);
+            let blockhash = backend().latest_blockhash();
+
+            let mut in_flight = Vec::new();
+            let mut transactions = Vec::new();
+            for item in wave {
+                attempts[item] += 1;
+
+                let operation = &operations[item];
+                let signers: Vec<&dyn Signer> = if operation.owner().pubkey() == payer {
+                    vec![tree_owner]
+                } else {
+                    vec![tree_owner, operation.owner()]
+                };
+
+                let signed = self.pipeline_instruction(&snapshot, operation)
+                    .and_then(|(instruction, leaf_hash)| {
+                        let blockhash = blockhash.as_ref().map_err(Clone::clone)?;
+                        Ok((sign(&[instruction], &payer, &signers, *blockhash)?, leaf_hash))
+                    });
+
+                match signed {
+                    Ok((transaction, leaf_hash)) => {
+                        in_flight.push((item, leaf_hash));
+                        transactions.push(Ok(transaction));
+                    }
+                    Err(e) => results[item] = Some(Err(e))
+                }
+            }
+
+            let mut root_expired = None;
+            let mut retries = Vec::new();
+
+            for ((item, leaf_hash), outcome) in in_flight.into_iter().zip(send_concurrently(&transactions)) {
+                let index = operations[item].index();
+
+                match outcome {
+                    Ok((signature, slot)) => {
+                        self.apply_leaf_update(operations[item].operation(), index, leaf_hash.to_vec());
+                        results[item] = Some(Ok(OperationResult::leaf_change(signature.to_string(), slot, &tree_account, index, index as u64, &leaf_hash)));
+                    }
+                    Err(e) if e.is_stale_proof() && attempts[item] < MAX_PROOF_ATTEMPTS
+                        && *root_expired.get_or_insert_with(|| !accepted_roots(&tree_account).is_ok_and(|roots| roots.contains(&snapshot.root))) => {
+                        retries.push(item);
+                    }
+                    Err(e) => results[item] = Some(Err(e))
+                }
+            }
+
+            // Retried operations come before any operation deferred from this wave.
+            for item in retries.into_iter().rev() {
+                pending.push_front(item);
+            }
+        }
+
+        Ok(results.into_iter()
+            .map(|result| result.unwrap_or_else(|| Err(CnftError::invalid_state("pipeline", "the operation was not processed"))))
+            .collect())
+    }
+
+
+    /// Builds the instruction of a pipelined operation against `snapshot`, along with the hash of the new leaf.
+    pub(crate) fn pipeline_instruction(&self, snapshot: &MerkleTree, operation: &PipelineOperation) -> Result<(Instruction, [u8; 32]), CnftError> {
+        let proof = self.snapshot_proof_accounts(snapshot, operation.index())?;
+
+        match operation {
+            PipelineOperation::Transfer(transfer) => {
+                let new_owner = parse_pubkey(transfer.new_owner_pub_key, "new_owner_pub_key")?;
+                let (instruction, leaf) = self.transfer_instruction_with_proof(
+                    &transfer.old_owner.pubkey(), &new_owner, transfer.index, transfer.data_hash, transfer.creator_hash, proof
+                )?;
+
+                Ok((instruction, leaf.hash()))
+            }
+            PipelineOperation::Burn { owner, index, data_hash, creator_hash } => {
+                let instruction = self.burn_instruction_with_proof(&owner.pubkey(), *index, data_hash, creator_hash, proof)?;
+
+                Ok((instruction, EMPTY))
+            }
+            PipelineOperation::Delegate { owner, new_delegate_pub_key, index, data_hash, creator_hash } => {
+                let new_delegate = parse_pubkey(new_delegate_pub_key, "new_delegate_pub_key")?;
+                let (instruction, leaf) = self.delegate_instruction_with_proof(
+                    &owner.pubkey(), &new_delegate, *index, data_hash, creator_hash, proof
+                )?;
+
+                Ok((instruction, leaf.hash()))
+            }
+        }
+    }
+}
+
+
+
+
+/// Takes the next wave off `pending`: up to `max_in_flight` operations on distinct leaves, in order.
+/// Operations on a leaf already in the wave stay pending, in order.
+fn next_wave(pending: &mut VecDeque<usize>, operations: &[PipelineOperation], max_in_flight: usize) -> Vec<usize> {
+    let mut wave = Vec::new();
+    let mut leaves = HashSet::new();
+    let mut deferred = VecDeque::new();
+
+    while let Some(item) = pending.pop_front() {
+        if wave.len() >= max_in_flight.max(1) {
+            deferred.push_back(item);
+            continue;
+        }
+
+        if leaves.insert(operations[item].index()) {
+            wave.push(item);
+        } else {
+            deferred.push_back(item);
+        }
+    }
+
+    *pending = deferred;
+    wave
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use solana_sdk::signature::Keypair;
+
+    use super::{next_wave, PipelineOperation};
+
+
+    /// A burn of the leaf at every index of `leaves`.
+    fn burns<'a>(owner: &'a Keypair, leaves: &[usize]) -> Vec<PipelineOperation<'a>> {
+        leaves.iter()
+            .map(|index| PipelineOperation::Burn { owner, index: *index, data_hash: "", creator_hash: "" })
+            .collect()
+    }
+
+
+    #[test]
+    fn wave_is_capped_at_max_in_flight() {
+        let owner = Keypair::new();
+        let operations = burns(&owner, &[0, 1, 2, 3, 4]);
+        let mut pending: VecDeque<usize> = (0..operations.len()).collect();
+
+        assert_eq!(next_wave(&mut pending, &operations, 2), vec![0, 1]);
+        assert_eq!(pending, VecDeque::from([2, 3, 4]));
+        assert_eq!(next_wave(&mut pending, &operations, 0), vec![2]);
+    }
+
+
+    #[test]
+    fn operations_on_a_leaf_in_the_wave_are_deferred_in_order() {
+        let owner = Keypair::new();
+        let operations = burns(&owner, &[7, 3, 7, 7, 5]);
+        let mut pending: VecDeque<usize> = (0..operations.len()).collect();
+
+        assert_eq!(next_wave(&mut pending, &operations, 64), vec![0, 1, 4]);
+        assert_eq!(pending, VecDeque::from([2, 3]));
+        assert_eq!(next_wave(&mut pending, &operations, 64), vec![2]);
+        assert_eq!(next_wave(&mut pending, &operations, 64), vec![3]);
+        assert!(pending.is_empty());
+    }
+
+
+    #[test]
+    fn retried_operations_at_the_front_go_first() {
+        let owner = Keypair::new();
+        let operations = burns(&owner, &[0, 1, 2, 3]);
+        let mut pending = VecDeque::from([3, 1, 0, 2]);
+
+        assert_eq!(next_wave(&mut pending, &operations, 3), vec![3, 1, 0]);
+        assert_eq!(pending, VecDeque::from([2]));
+    }
+}
@@ -0,0 +1,171 @@
+//! This module implements tree pools, which roll minting over to a fresh merkle tree once the current one is nearly full
+
+
+
+use rustler::NifStruct;
+use solana_sdk::signer::Signer;
+
+use crate::error::CnftError;
+use crate::result::OperationResult;
+use crate::setup::TreeManager;
+use crate::simulation::SimulationResult;
+
+
+
+/// Outcome of a pool mint: the updated pool, handed back even if the mint fails, and the result of the mint.
+pub type PoolMint = (TreePool, Result<OperationResult, CnftError>);
+
+
+
+/// Represents a pool of merkle trees owned by the same tree owner.
+///
+/// Mints go to the active tree. Once the active tree has `rollover_threshold` or fewer leaves left, the next mint
+/// rolls over first: it creates a new tree and makes it the active one.
+
+#[derive(NifStruct, Clone)]
+#[module = "CnftNif.TreePool"]
+pub struct TreePool {
+    /// Every tree of the pool, in creation order. Trees which were rolled over from are kept for transfers and burns.
+    pub trees: Vec<TreeManager>,
+
+    /// Index of the tree in `trees` which receives new mints.
+    pub active: usize,
+
+    /// Remaining capacity of the active tree at or below which a new tree is created.
+    pub rollover_threshold: usize
+}
+
+impl TreePool {
+
+    /// Creates a pool whose active tree is `tree_manager`, which must already be created on chain.
+    ///
+    /// # Errors
+    ///
+    /// Returns `{:invalid_argument, :rollover_threshold, message}` if the threshold is not smaller than the capacity of a tree.
+    pub fn new(tree_manager: TreeManager, rollover_threshold: usize) -> Result<Self, CnftError> {
+        if rollover_threshold >= tree_manager.capacity() {
+            return Err(CnftError::invalid_argument(
+                "rollover_threshold",
+                format!("must be smaller than the tree capacity of {}", tree_manager.capacity())
+            ));
+        }
+
+        Ok(Self {
+            trees: vec![tree_manager],
+            active: 0,
+            rollover_threshold
+        })
+    }
+
+
+    /// Checks the pool handed over by the caller along with every tree it holds.
+    pub fn validate(&self) -> Result<(), CnftError> {
+        if self.active >= self.trees.len() {
+            return Err(CnftError::invalid_state("active", format!("no tree at index {}", self.active)));
+        }
+
+        self.trees.iter().try_for_each(TreeManager::validate)
+    }
+
+
+    /// The tree which receives new mints.
+    pub fn active_tree(&self) -> &TreeManager {
+        &self.trees[self.active]
+    }
+
+
+    /// Creates a new tree on chain and makes it the active one, if the active tree has `rollover_threshold` or fewer
+    /// leaves left. `mint_cnft` rolls over by itself, this only lets a rollover happen ahead of the mint which needs it.
+    ///
+    /// # Parameters
+    ///
+    /// * `tree_owner` - The signer of the tree owner. It pays for and owns the new tree.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(OperationResult))` - The result of the tree creation, if a new tree was needed.
+    /// * `Ok(None)` - If the active tree still has enough leaves left. Nothing is sent then.
+    /// * `Err(CnftError)` - An error if creating the new tree fails. The pool is left unchanged then.
+    pub fn rollover(&mut self, tree_owner: &dyn Signer) -> Result<Option<OperationResult>, CnftError> {
+        if self.active_tree().remaining_capacity() > self.rollover_threshold {
+            return Ok(None);
+        }
+
+        let mut tree_manager = TreeManager::default();
+        let (result, _) = tree_manager.create_tree(tree_owner)?;
+
+        self.trees.push(tree_manager);
+        self.active = self.trees.len() - 1;
+
+        Ok(Some(result))
+    }
+
+
+    /// Mints a cNFT into the active tree, rolling over to a new tree first if the active one is due for it.
+    ///
+    /// The pool is updated in place, so a tree created by the rollover is kept even if the mint which follows fails,
+    /// and retrying the mint never creates another tree.
+    ///
+    /// # Parameters
+    ///
+    /// * `tree_owner` - The signer of the tree owner. It pays for and owns a new tree.
+    /// * `nft_owner` - A string slice representing the public key of the recipient in base58 format.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(OperationResult)` - The result of the mint, whose `tree_address` tells which tree of the pool received it.
+    /// * `Err(CnftError)` - An error if creating the new tree or minting fails.
+    pub fn mint_cnft(&mut self, tree_owner: &dyn Signer, nft_owner: &str) -> Result<OperationResult, CnftError> {
+        self.rollover(tree_owner)?;
+
+        let active = self.active;
+        self.trees[active].mint_cnft(tree_owner, nft_owner)
+    }
+
+
+    /// Simulates `mint_cnft`. While a rollover is due the mint cannot be simulated before the new tree exists,
+    /// so the creation of the new tree is simulated instead.
+    pub fn simulate_mint_cnft(&self, tree_owner: &dyn Signer, nft_owner: &str) -> Result<SimulationResult, CnftError> {
+        if self.active_tree().remaining_capacity() <= self.rollover_threshold {
+            return TreeManager::default().simulate_create_tree(tree_owner);
+        }
+
+        self.active_tree().simulate_mint_cnft(tree_owner, nft_owner)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{signature::Keypair, signer::Signer};
+
+    use crate::backend::simulated_backend;
+    use crate::setup::TreeManager;
+
+    use super::TreePool;
+
+
+    #[test]
+    fn mint_rolls_over_once_the_active_tree_reaches_the_threshold() {
+        let _backend = simulated_backend();
+        let owner = Keypair::new();
+        let mut tree_manager = TreeManager::default();
+        tree_manager.create_tree(&owner).unwrap();
+        let mut pool = TreePool::new(tree_manager, 4).unwrap();
+        let nft_owner = Keypair::new().pubkey().to_string();
+
+        let first = pool.mint_cnft(&owner, &nft_owner).unwrap();
+        assert_eq!(pool.trees.len(), 1);
+
+        // Only the local count matters to the pool, the chain still accepts mints into the old tree.
+        pool.trees[0].minted = pool.trees[0].capacity() - 4;
+        let second = pool.mint_cnft(&owner, &nft_owner).unwrap();
+
+        assert_eq!(pool.trees.len(), 2);
+        assert_eq!(pool.active, 1);
+        assert_ne!(first.tree_address, second.tree_address);
+        assert_eq!(second.leaf_index, Some(0));
+        assert_eq!(pool.trees[1].minted, 1);
+    }
+}
@@ -0,0 +1,329 @@
+//! This module defines the proof types exchanged with Elixir, shaped like the DAS `getAssetProof` response
+
+
+
+use anchor_lang::{InstructionData, ToAccountMetas};
+use mpl_bubblegum::types::LeafSchema;
+use rustler::{NifStruct, NifUnitEnum, NifUntaggedEnum};
+use serde::Deserialize;
+use solana_sdk::{bs58, instruction::{AccountMeta, Instruction}, pubkey::Pubkey, signer::Signer};
+use spl_merkle_tree_reference::{recompute, MerkleTree};
+
+use crate::backend::backend;
+use crate::error::{CnftError, ProgramName};
+use crate::setup::{send_and_confirm, signed_transaction};
+use crate::utils::{parse_hash, parse_pubkey};
+
+
+
+/// Identifies a leaf of the tree, either by its index or by the base58 asset id of the cNFT stored in it.
+#[derive(NifUntaggedEnum, Clone)]
+pub enum LeafId {
+    Index(usize),
+    AssetId(String)
+}
+
+
+
+/// Proof of a leaf, in the shape of the DAS `getAssetProof` response. Every hash and key is base58 encoded.
+
+#[derive(NifStruct, Clone, Deserialize)]
+#[module = "CnftNif.AssetProof"]
+pub struct AssetProof {
+    /// Root of the merkle tree.
+    pub root: String,
+
+    /// Sibling nodes from the leaf up to the root, without the nodes stored in the canopy.
+    pub proof: Vec<String>,
+
+    /// Index of the leaf node in the whole tree, i.e. `2^max_depth + leaf_index`.
+    pub node_index: usize,
+
+    /// Hash of the leaf.
+    pub leaf: String,
+
+    /// Address of the merkle tree account.
+    pub tree_id: String
+}
+
+impl AssetProof {
+
+    /// Proof of the leaf at `index` of an off-chain copy of the tree `tree_id`.
+    /// The `canopy_depth` nodes next to the root are left out.
+    pub fn from_merkle_tree(merkle_tree: &MerkleTree, tree_id: &Pubkey, index: usize, canopy_depth: usize) -> AssetProof {
+        let mut proof = merkle_tree.get_proof_of_leaf(index);
+        let node_index = (1 << proof.len()) + index;
+        proof.truncate(proof.len().saturating_sub(canopy_depth));
+
+        AssetProof {
+            root: bs58::encode(merkle_tree.root).into_string(),
+            proof: proof.iter().map(|node| bs58::encode(node).into_string()).collect(),
+            node_index,
+            leaf: bs58::encode(merkle_tree.get_leaf(index)).into_string(),
+            tree_id: tree_id.to_string()
+        }
+    }
+
+
+    /// Index of the leaf, derived from `node_index`.
+    pub fn leaf_index(&self) -> Result<u32, CnftError> {
+        if self.node_index == 0 {
+            return Err(CnftError::invalid_argument("node_index", "must be at least 1"));
+        }
+
+        let depth = usize::BITS - 1 - self.node_index.leading_zeros();
+        u32::try_from(self.node_index - (1 << depth))
+            .map_err(|e| CnftError::invalid_argument("node_index", e))
+    }
+
+
+    /// The proof nodes as read-only accounts, ready to be appended to a Bubblegum or Account Compression instruction.
+    pub fn proof_accounts(&self) -> Result<Vec<AccountMeta>, CnftError> {
+        self.proof.iter()
+            .map(|node| Ok(AccountMeta::new_readonly(Pubkey::new_from_array(parse_hash(node, "proof")?), false)))
+            .collect()
+    }
+
+
+    /// Builds the spl-account-compression `verify_leaf` instruction proving the leaf against the root.
+    pub fn verify_leaf_instruction(&self) -> Result<Instruction, CnftError> {
+        let merkle_tree = parse_pubkey(&self.tree_id, "tree_id")?;
+
+        let data = spl_account_compression::instruction::VerifyLeaf {
+            root: parse_hash(&self.root, "root")?,
+            leaf: parse_hash(&self.leaf, "leaf")?,
+            index: self.leaf_index()?
+        }.data();
+
+        let mut accounts = spl_account_compression::accounts::VerifyLeaf { merkle_tree }.to_account_metas(None);
+        accounts.extend(self.proof_accounts()?);
+
+        Ok(Instruction { program_id: spl_account_compression::ID, accounts, data })
+    }
+
+
+    /// Checks on chain that the leaf is currently in the tree, by simulating or submitting a `verify_leaf` instruction.
+    ///
+    /// # Parameters
+    ///
+    /// * `payer` - The signer paying for the transaction. Nothing is paid when simulating.
+    /// * `mode` - Whether the instruction is only simulated or submitted.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - If the leaf is in the tree with this root and proof.
+    /// * `Ok(false)` - If the Account Compression program rejects the leaf.
+    /// * `Err(CnftError)` - If the proof is malformed or the transaction fails for any other reason.
+    pub fn verify_on_chain(&self, payer: &dyn Signer, mode: VerifyMode) -> Result<bool, CnftError> {
+        let instruction = self.verify_leaf_instruction()?;
+        let txn = signed_transaction(&[instruction], &payer.pubkey(), &[payer])?;
+
+        let result = match mode {
+            VerifyMode::Simulate => backend().simulate(&txn)
+                .and_then(|simulation| simulation.error.map_or(Ok(()), Err)),
+            VerifyMode::Submit => send_and_confirm(&txn).map(|_| ())
+        };
+
+        match result {
+            Ok(()) => Ok(true),
+            Err(CnftError::Program { program: ProgramName::AccountCompression, .. }) => Ok(false),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+
+
+
+/// How a `verify_leaf` instruction is run on chain.
+#[derive(NifUnitEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VerifyMode {
+    /// Simulate the transaction, nothing is written or paid.
+    Simulate,
+
+    /// Submit the transaction, leaving a record of the verification on chain.
+    Submit
+}
+
+
+
+
+/// The fields of a Bubblegum leaf, as claimed by a third party. Keys and hashes are base58 encoded.
+
+#[derive(NifStruct, Clone)]
+#[module = "CnftNif.Leaf"]
+pub struct Leaf {
+    /// Asset id of the cNFT.
+    pub asset_id: String,
+
+    /// Owner of the cNFT.
+    pub owner: String,
+
+    /// Delegate of the cNFT, the owner itself if there is none.
+    pub delegate: String,
+
+    /// Nonce of the cNFT, which is also the index of its leaf.
+    pub nonce: u64,
+
+    /// Hash of the metadata of the cNFT.
+    pub data_hash: String,
+
+    /// Hash of the creators of the cNFT.
+    pub creator_hash: String
+}
+
+impl Leaf {
+
+    /// Recomputes the `LeafSchema` hash of the leaf.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a key or hash is not valid base58 of the right length.
+    pub fn hash(&self) -> Result<[u8; 32], CnftError> {
+        let schema = LeafSchema::V1 {
+            id: parse_pubkey(&self.asset_id, "asset_id")?,
+            owner: parse_pubkey(&self.owner, "owner")?,
+            delegate: parse_pubkey(&self.delegate, "delegate")?,
+            nonce: self.nonce,
+            data_hash: parse_hash(&self.data_hash, "data_hash")?,
+            creator_hash: parse_hash(&self.creator_hash, "creator_hash")?
+        };
+
+        Ok(schema.hash())
+    }
+}
+
+
+
+
+/// Checks that `leaf` is included in the tree with root `root`, by walking `proof` up from the leaf at index `nonce`.
+///
+/// The proof must be complete, from the leaf up to the root. Proofs truncated for a canopy cannot be verified offline.
+///
+/// # Arguments
+///
+/// * `leaf` - The claimed leaf fields.
+/// * `proof` - Base58 encoded sibling nodes, from the leaf up to the root.
+/// * `root` - Base58 encoded root the leaf is claimed to be included in.
+///
+/// # Returns
+///
+/// * `Ok(true)` - If the proof recomputes `root`.
+/// * `Ok(false)` - If it does not, i.e. the leaf fields, the proof or the root are wrong.
+/// * `Err(CnftError)` - If any of the keys or hashes is malformed.
+pub fn verify_leaf_proof(leaf: &Leaf, proof: &[String], root: &str) -> Result<bool, CnftError> {
+    let leaf_hash = leaf.hash()?;
+    let root = parse_hash(root, "root")?;
+    let proof = proof.iter()
+        .map(|node| parse_hash(node, "proof"))
+        .collect::<Result<Vec<[u8; 32]>, CnftError>>()?;
+
+    let Ok(index) = u32::try_from(leaf.nonce) else {
+        return Ok(false);
+    };
+    // A proof of n nodes only covers the leaf indexes below 2^n.
+    if proof.len() > 32 || (proof.len() < 32 && index >> proof.len() != 0) {
+        return Ok(false);
+    }
+
+    Ok(recompute(leaf_hash, &proof, index) == root)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{bs58, pubkey::Pubkey};
+    use spl_merkle_tree_reference::MerkleTree;
+
+    use crate::error::CnftError;
+
+    use super::{verify_leaf_proof, Leaf};
+
+
+    const DEPTH: usize = 3;
+
+
+    fn encode(bytes: impl AsRef<[u8]>) -> String {
+        bs58::encode(bytes).into_string()
+    }
+
+
+    fn leaf(nonce: u64) -> Leaf {
+        let owner = Pubkey::new_unique().to_string();
+
+        Leaf {
+            asset_id: Pubkey::new_unique().to_string(),
+            delegate: owner.clone(),
+            owner,
+            nonce,
+            data_hash: encode([1; 32]),
+            creator_hash: encode([2; 32])
+        }
+    }
+
+
+    /// A tree holding `leaf` at its nonce, with the proof of that leaf and the root.
+    fn tree_with(leaf: &Leaf) -> (Vec<String>, String) {
+        let mut leaves = vec![[0; 32]; 1 << DEPTH];
+        leaves[leaf.nonce as usize] = leaf.hash().unwrap();
+        let merkle_tree = MerkleTree::new(&leaves);
+
+        let proof = merkle_tree.get_proof_of_leaf(leaf.nonce as usize).iter().map(encode).collect();
+        (proof, encode(merkle_tree.root))
+    }
+
+
+    #[test]
+    fn included_leaf_is_verified() {
+        let leaf = leaf(5);
+        let (proof, root) = tree_with(&leaf);
+
+        assert!(verify_leaf_proof(&leaf, &proof, &root).unwrap());
+    }
+
+
+    #[test]
+    fn wrong_leaf_fields_proof_or_root_are_not_verified() {
+        let leaf = leaf(5);
+        let (proof, root) = tree_with(&leaf);
+
+        let other_owner = Leaf { owner: Pubkey::new_unique().to_string(), ..leaf.clone() };
+        assert!(!verify_leaf_proof(&other_owner, &proof, &root).unwrap());
+
+        let other_nonce = Leaf { nonce: 4, ..leaf.clone() };
+        assert!(!verify_leaf_proof(&other_nonce, &proof, &root).unwrap());
+
+        let mut other_proof = proof.clone();
+        other_proof[1] = encode([3; 32]);
+        assert!(!verify_leaf_proof(&leaf, &other_proof, &root).unwrap());
+
+        assert!(!verify_leaf_proof(&leaf, &proof, &encode([3; 32])).unwrap());
+    }
+
+
+    #[test]
+    fn nonce_beyond_the_proof_is_not_verified() {
+        let leaf = leaf(5);
+        let (proof, root) = tree_with(&leaf);
+
+        // Index 13 walks the same path as 5 with three nodes, but is out of a tree of depth 3.
+        let out_of_tree = Leaf { nonce: 5 + (1 << DEPTH), ..leaf.clone() };
+        assert!(!verify_leaf_proof(&out_of_tree, &proof, &root).unwrap());
+
+        let out_of_u32 = Leaf { nonce: u64::from(u32::MAX) + 1, ..leaf };
+        assert!(!verify_leaf_proof(&out_of_u32, &proof, &root).unwrap());
+    }
+
+
+    #[test]
+    fn malformed_hashes_are_rejected() {
+        let leaf = leaf(5);
+        let (mut proof, root) = tree_with(&leaf);
+
+        assert!(matches!(verify_leaf_proof(&leaf, &proof, "not base58"), Err(CnftError::InvalidArgument { field, .. }) if field == "root"));
+
+        proof[0] = encode([1; 31]);
+        assert!(matches!(verify_leaf_proof(&leaf, &proof, &root), Err(CnftError::InvalidArgument { field, .. }) if field == "proof"));
+    }
+}
@@ -0,0 +1,235 @@
+//! This module resolves the proof of a leaf from a configurable list of sources, checked against the tree on chain
+
+
+
+use anchor_lang::AnchorDeserialize;
+use mpl_bubblegum::utils::get_asset_id;
+use rustler::NifTaggedEnum;
+use solana_sdk::{bs58, pubkey::Pubkey};
+use spl_account_compression::{state::{ConcurrentMerkleTreeHeader, CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1}, ConcurrentMerkleTree, EMPTY};
+use spl_merkle_tree_reference::{recompute, MerkleTree, Node};
+
+use crate::backend::backend;
+use crate::das::DasClient;
+use crate::error::CnftError;
+use crate::events;
+use crate::proof::{AssetProof, LeafId};
+use crate::setup::{TreeManager, MAX_BUFFER_SIZE, MAX_DEPTH};
+use crate::utils::parse_hash;
+
+
+
+/// Where the proof of a leaf is taken from.
+///
+/// In Elixir: `:local`, `{:das, url}` or `:chain_history`.
+#[derive(NifTaggedEnum, Clone)]
+pub enum ProofSource {
+    /// The `nodes` of the TreeManager. Only knows about changes made through this manager.
+    Local,
+
+    /// The `getAssetProof` method of a DAS endpoint.
+    Das(String),
+
+    /// The tree rebuilt from the changelog events of every transaction which touched it. Slow on busy trees.
+    ChainHistory
+}
+
+impl ProofSource {
+
+    fn name(&self) -> &'static str {
+        match self {
+            ProofSource::Local => "local",
+            ProofSource::Das(_) => "das",
+            ProofSource::ChainHistory => "chain_history"
+        }
+    }
+
+
+    /// Fetches the proof of the leaf at `index`, without checking it.
+    fn fetch(&self, tree_manager: &TreeManager, merkle_tree: &Pubkey, index: usize) -> Result<AssetProof, CnftError> {
+        match self {
+            ProofSource::Local => tree_manager.asset_proof(&LeafId::Index(index), 0),
+            ProofSource::Das(url) => DasClient::new(url).get_asset_proof(&get_asset_id(merkle_tree, index as u64).to_string()),
+            ProofSource::ChainHistory => {
+                let leaves = leaves_from_history(merkle_tree, tree_manager.max_depth)?;
+
+                Ok(AssetProof::from_merkle_tree(&MerkleTree::new(&leaves), merkle_tree, index, 0))
+            }
+        }
+    }
+}
+
+
+
+
+/// Tries `sources` in order and returns the first proof of `leaf` at `index` the tree still accepts.
+///
+/// A proof is accepted if it is a full proof of `leaf` in the tree of the manager, recomputes its root,
+/// and that root is the current root of the tree or one of the roots kept in its changelog buffer.
+///
+/// # Errors
+///
+/// Returns `CnftError::ProofUnavailable` with the reason of each source if none of them provides an accepted proof,
+/// or an error if the tree account cannot be read.
+pub fn resolve_proof(tree_manager: &TreeManager, index: usize, leaf: Node, sources: &[ProofSource]) -> Result<AssetProof, CnftError> {
+    if sources.is_empty() {
+        return Err(CnftError::invalid_argument("sources", "at least one proof source is needed"));
+    }
+
+    let merkle_tree = tree_manager.tree_pubkey()?;
+    let roots = accepted_roots(&merkle_tree)?;
+
+    let mut attempts = Vec::new();
+    for source in sources {
+        let proof = source.fetch(tree_manager, &merkle_tree, index)
+            .and_then(|proof| check_proof(&proof, &merkle_tree, index, leaf, tree_manager.max_depth, &roots).map(|_| proof));
+
+        match proof {
+            Ok(proof) => return Ok(proof),
+            Err(e) => attempts.push((source.name().to_string(), e.to_string()))
+        }
+    }
+
+    Err(CnftError::ProofUnavailable { attempts })
+}
+
+
+
+
+/// Roots the tree account at `merkle_tree` accepts proofs against: the roots of its changelog buffer, newest first.
+///
+/// # Errors
+///
+/// Returns an error if the account cannot be read or is not a `MAX_DEPTH = 14`, `MAX_BUFFER_SIZE = 64` tree.
+pub fn accepted_roots(merkle_tree: &Pubkey) -> Result<Vec<Node>, CnftError> {
+    let (header, tree_bytes) = read_tree_account(merkle_tree)?;
+    let tree = decode_tree(&header, &tree_bytes)?;
+
+    Ok((0..tree.buffer_size as usize)
+        .map(|i| tree.change_logs[(tree.active_index as usize + MAX_BUFFER_SIZE - i) % MAX_BUFFER_SIZE].root)
+        .collect())
+}
+
+
+
+
+/// Reads the account of the tree at `merkle_tree`, returning its header and the bytes of the tree which follow it.
+///
+/// # Errors
+///
+/// Returns an error if the account does not exist or does not start with a concurrent merkle tree header.
+pub(crate) fn read_tree_account(merkle_tree: &Pubkey) -> Result<(ConcurrentMerkleTreeHeader, Vec<u8>), CnftError> {
+    let data = backend().account_data(merkle_tree)?
+        .ok_or_else(|| CnftError::invalid_argument("merkle_tree", "the tree account does not exist"))?;
+
+    split_tree_account(data)
+}
+
+
+
+
+/// Splits the data of a tree account into its header and the bytes of the tree which follow it.
+///
+/// # Errors
+///
+/// Returns an error if the data does not start with a concurrent merkle tree header.
+pub(crate) fn split_tree_account(mut data: Vec<u8>) -> Result<(ConcurrentMerkleTreeHeader, Vec<u8>), CnftError> {
+    if data.len() < CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1 {
+        return Err(CnftError::invalid_argument("merkle_tree", "not a concurrent merkle tree account"));
+    }
+
+    let tree_bytes = data.split_off(CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1);
+    let header = ConcurrentMerkleTreeHeader::deserialize(&mut data.as_slice())
+        .map_err(|e| CnftError::invalid_argument("merkle_tree", e))?;
+
+    Ok((header, tree_bytes))
+}
+
+
+
+
+/// Decodes the concurrent merkle tree following `header` in a tree account.
+///
+/// # Errors
+///
+/// Returns an error if the tree is not a `MAX_DEPTH = 14`, `MAX_BUFFER_SIZE = 64` tree.
+pub(crate) fn decode_tree(header: &ConcurrentMerkleTreeHeader, tree_bytes: &[u8]) -> Result<Box<ConcurrentMerkleTree<MAX_DEPTH, MAX_BUFFER_SIZE>>, CnftError> {
+    if header.get_max_depth() as usize != MAX_DEPTH || header.get_max_buffer_size() as usize != MAX_BUFFER_SIZE {
+        return Err(CnftError::invalid_argument("merkle_tree", "only trees with max_depth 14 and max_buffer_size 64 are supported"));
+    }
+
+    // The tree sits right after the 56 byte header, which the account data does not guarantee to be aligned.
+    let size = std::mem::size_of::<ConcurrentMerkleTree<MAX_DEPTH, MAX_BUFFER_SIZE>>();
+    if tree_bytes.len() < size {
+        return Err(CnftError::invalid_argument("merkle_tree", "the tree account is too small"));
+    }
+    let mut aligned = vec![0u64; size.div_ceil(8)];
+    bytemuck::cast_slice_mut::<u64, u8>(&mut aligned)[..size].copy_from_slice(&tree_bytes[..size]);
+    let tree: &ConcurrentMerkleTree<MAX_DEPTH, MAX_BUFFER_SIZE> = bytemuck::from_bytes(&bytemuck::cast_slice::<u64, u8>(&aligned)[..size]);
+
+    Ok(Box::new(*tree))
+}
+
+
+
+
+/// Rebuilds the leaves of the tree at `merkle_tree` from the changelog events of every transaction which touched it.
+///
+/// # Errors
+///
+/// Returns an error if the history cannot be fetched, or if it has gaps, e.g. because the RPC node pruned old transactions.
+pub fn leaves_from_history(merkle_tree: &Pubkey, max_depth: usize) -> Result<Vec<Node>, CnftError> {
+    let backend = backend();
+
+    let mut changelogs = Vec::new();
+    for signature in backend.signatures_for_address(merkle_tree)? {
+        if let Some(transaction) = backend.confirmed_transaction(&signature)? {
+            changelogs.extend(events::changelogs(&transaction.noop_data, merkle_tree));
+        }
+    }
+    changelogs.sort_by_key(|changelog| changelog.seq);
+
+    // Sequence numbers start at 0 with the initialization of the tree and grow by one with every change.
+    if changelogs.iter().enumerate().any(|(i, changelog)| changelog.seq != i as u64) {
+        return Err(CnftError::invalid_argument("merkle_tree", "the transaction history of the tree is incomplete"));
+    }
+
+    let mut leaves = vec![EMPTY; 1 << max_depth];
+    for changelog in changelogs {
+        let leaf = changelog.path.first()
+            .ok_or_else(|| CnftError::invalid_argument("merkle_tree", "changelog event without a path"))?;
+        *leaves.get_mut(changelog.index as usize)
+            .ok_or_else(|| CnftError::invalid_argument("merkle_tree", "changelog event outside of the tree"))? = leaf.node;
+    }
+
+    Ok(leaves)
+}
+
+
+
+
+/// Checks that `proof` is a full proof of `leaf` at `index` in `merkle_tree`, against one of the accepted `roots`.
+fn check_proof(proof: &AssetProof, merkle_tree: &Pubkey, index: usize, leaf: Node, max_depth: usize, roots: &[Node]) -> Result<(), CnftError> {
+    if proof.tree_id != merkle_tree.to_string() || proof.leaf_index()? as usize != index {
+        return Err(CnftError::invalid_argument("proof", "the proof is for another leaf"));
+    }
+    if parse_hash(&proof.leaf, "leaf")? != leaf {
+        return Err(CnftError::invalid_argument("proof", format!("the leaf is {}, expected {}", proof.leaf, bs58::encode(leaf).into_string())));
+    }
+    if proof.proof.len() != max_depth {
+        return Err(CnftError::invalid_argument("proof", format!("expected a full proof of {max_depth} nodes, got {}", proof.proof.len())));
+    }
+
+    let root = parse_hash(&proof.root, "root")?;
+    let nodes = proof.proof.iter()
+        .map(|node| parse_hash(node, "proof"))
+        .collect::<Result<Vec<Node>, CnftError>>()?;
+    if recompute(leaf, &nodes, index as u32) != root {
+        return Err(CnftError::invalid_argument("proof", "the proof does not recompute its root"));
+    }
+    if !roots.contains(&root) {
+        return Err(CnftError::invalid_argument("root", "the root is no longer in the changelog buffer of the tree"));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,73 @@
+//! This module defines the result every confirmed operation returns to Elixir
+
+
+
+use mpl_bubblegum::{accounts::TreeConfig, utils::get_asset_id};
+use rustler::NifStruct;
+use solana_sdk::{bs58, pubkey::Pubkey};
+
+
+
+/// Outcome of a confirmed operation, with the addresses involved so callers never have to re-derive them.
+/// Keys and hashes are base58 encoded.
+
+#[derive(NifStruct, Clone, Debug)]
+#[module = "CnftNif.OperationResult"]
+pub struct OperationResult {
+    /// Signature of the transaction.
+    pub signature: String,
+
+    /// Slot the transaction was processed in.
+    pub slot: u64,
+
+    /// Address of the merkle tree account.
+    pub tree_address: String,
+
+    /// Address of the Bubblegum tree config PDA of the tree.
+    pub tree_config: String,
+
+    /// Index of the leaf changed by the operation, `nil` when creating a tree.
+    pub leaf_index: Option<usize>,
+
+    /// Nonce of the cNFT stored in the leaf, `nil` when creating a tree.
+    pub nonce: Option<u64>,
+
+    /// Asset id of the cNFT stored in the leaf, `nil` when creating a tree.
+    pub asset_id: Option<String>,
+
+    /// Hash of the leaf once the operation is confirmed, e.g. the leaf of the new owner after a transfer.
+    /// `nil` when creating a tree.
+    pub leaf_hash: Option<String>
+}
+
+impl OperationResult {
+
+    /// Result of a transaction which changed `tree_account` as a whole, e.g. created it.
+    pub fn new(signature: String, slot: u64, tree_account: &Pubkey) -> Self {
+        let (tree_config, _) = TreeConfig::find_pda(tree_account);
+
+        Self {
+            signature,
+            slot,
+            tree_address: tree_account.to_string(),
+            tree_config: tree_config.to_string(),
+            leaf_index: None,
+            nonce: None,
+            asset_id: None,
+            leaf_hash: None
+        }
+    }
+
+
+    /// Result of a transaction which set the leaf at `index` of `tree_account` to `leaf_hash`, holding the cNFT
+    /// with the given `nonce`. Minted nonces are read from the leaf schema event Bubblegum logged, see `events`.
+    pub fn leaf_change(signature: String, slot: u64, tree_account: &Pubkey, index: usize, nonce: u64, leaf_hash: &[u8]) -> Self {
+        Self {
+            leaf_index: Some(index),
+            nonce: Some(nonce),
+            asset_id: Some(get_asset_id(tree_account, nonce).to_string()),
+            leaf_hash: Some(bs58::encode(leaf_hash).into_string()),
+            ..Self::new(signature, slot, tree_account)
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! This module implements the retry policy applied to every transaction a TreeManager operation submits
+//!
+//! The policy is set process wide with the `set_retry_policy` NIF. Only transient failures are retried,
+//! see `CnftError::is_retryable`. A transaction is never rebuilt while it can still land, so an operation is
+//! never applied twice.
+
+
+
+use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use rustler::NifStruct;
+use solana_sdk::{signature::Signature, transaction::Transaction};
+
+use crate::backend::backend;
+use crate::error::CnftError;
+
+
+static POLICY: Lazy<RwLock<RetryPolicy>> = Lazy::new(|| RwLock::new(RetryPolicy::default()));
+
+
+
+/// How often and how patiently a transaction is sent again after a transient failure.
+
+#[derive(NifStruct, Clone, Copy, Debug, PartialEq, Eq)]
+#[module = "CnftNif.RetryPolicy"]
+pub struct RetryPolicy {
+    /// Number of times a transaction is sent at most, `1` disables retrying.
+    pub max_attempts: u32,
+
+    /// Wait before the first retry in milliseconds, doubled before every following one.
+    pub backoff_ms: u64,
+
+    /// Upper bound of the wait before a retry in milliseconds.
+    pub max_backoff_ms: u64
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff_ms: 500,
+            max_backoff_ms: 5_000
+        }
+    }
+}
+
+impl RetryPolicy {
+
+    /// Wait before the `retry`-th retry, starting at 1.
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self.backoff_ms.saturating_mul(1 << retry.saturating_sub(1).min(32));
+
+        Duration::from_millis(backoff.min(self.max_backoff_ms))
+    }
+}
+
+
+
+/// The retry policy currently in use.
+pub fn retry_policy() -> RetryPolicy {
+    *POLICY.read().unwrap_or_else(|e| e.into_inner())
+}
+
+
+/// Replaces the retry policy used by every following operation.
+///
+/// # Errors
+///
+/// Returns an error if `max_attempts` is 0.
+pub fn set_retry_policy(policy: RetryPolicy) -> Result<(), CnftError> {
+    if policy.max_attempts == 0 {
+        return Err(CnftError::invalid_argument("max_attempts", "must be at least 1"));
+    }
+
+    *POLICY.write().unwrap_or_else(|e| e.into_inner()) = policy;
+    Ok(())
+}
+
+
+
+
+/// Sends the transaction returned by `build` and waits for its confirmation, retrying transient failures.
+///
+/// Before every retry the earlier transaction is looked up, and returned if it landed in the meantime.
+/// While its blockhash is still valid it may land at any moment, so the very same transaction is sent again,
+/// which the cluster processes at most once. Only once its blockhash expired without it landing is `build`
+/// called again, for a transaction with a fresh blockhash and, where the operation resolves one, a fresh proof.
+///
+/// # Returns
+///
+/// * `Ok((Signature, u64))` - The signature of the transaction which landed and the slot it was processed in.
+/// * `Err(CnftError)` - The first error which is not retryable, or the last one once `max_attempts` are used up.
+pub(crate) fn send_with_retry(build: impl FnMut() -> Result<Transaction, CnftError>) -> Result<(Signature, u64), CnftError> {
+    send(build, false)
+}
+
+
+/// Like `send_with_retry`, also calling `build` again right away when Account Compression rejected the proof
+/// of the transaction, see `CnftError::is_stale_proof`. `build` must then resolve a fresh proof from the chain.
+pub(crate) fn send_with_proof_retry(build: impl FnMut() -> Result<Transaction, CnftError>) -> Result<(Signature, u64), CnftError> {
+    send(build, true)
+}
+
+
+fn send(mut build: impl FnMut() -> Result<Transaction, CnftError>, rebuild_stale_proof: bool) -> Result<(Signature, u64), CnftError> {
+    let policy = retry_policy();
+    let mut transaction = build()?;
+    let mut attempt = 1;
+
+    loop {
+        let error = match backend().send_and_confirm(&transaction) {
+            Ok(confirmation) => return Ok(confirmation),
+            Err(e) => e
+        };
+        let stale_proof = rebuild_stale_proof && error.is_stale_proof();
+        if attempt >= policy.max_attempts || !(error.is_retryable() || stale_proof) {
+            return Err(error);
+        }
+
+        // The rejected transaction can never succeed, whether it was dropped by the preflight or landed failed.
+        if stale_proof {
+            attempt += 1;
+            transaction = build()?;
+            continue;
+        }
+
+        thread::sleep(policy.backoff(attempt));
+        attempt += 1;
+
+        // The blockhash is checked first: once it expired the transaction can no longer land,
+        // so a signature still unknown afterwards never lands.
+        let signature = transaction.signatures[0];
+        let expired = backend().is_blockhash_valid(&transaction.message.recent_blockhash).map(|valid| !valid);
+
+        match backend().signature_status(&signature) {
+            Ok(Some((slot, None))) => return Ok((signature, slot)),
+            Ok(Some((_, Some(e)))) => return Err(CnftError::from_transaction_error(&e, Some(&transaction), &backend().transaction_logs(&signature))),
+            Ok(None) if expired == Ok(true) => transaction = build()?,
+            _ => {}
+        }
+    }
+}
+
+
+/// Sends `transaction` without waiting for its confirmation, retrying transient failures of the send itself.
+///
+/// The very same transaction is sent every time, which the cluster processes at most once, so a send reported
+/// as already processed succeeded.
+pub(crate) fn send_only_with_retry(transaction: &Transaction) -> Result<Signature, CnftError> {
+    let policy = retry_policy();
+    let mut attempt = 1;
+
+    loop {
+        let error = match backend().send(transaction) {
+            Ok(signature) => return Ok(signature),
+            Err(CnftError::Rpc { kind, .. }) if kind == "already_processed" => return Ok(transaction.signatures[0]),
+            Err(e) => e
+        };
+        if attempt >= policy.max_attempts || !error.is_retryable() {
+            return Err(error);
+        }
+
+        thread::sleep(policy.backoff(attempt));
+        attempt += 1;
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use solana_sdk::{hash::Hash, pubkey::Pubkey, signature::{Keypair, Signature}, signer::Signer, transaction::Transaction};
+
+    use crate::backend::{backend, set_backend, simulated_backend, ChainBackend, ConfirmedTransaction, SignatureStatus};
+    use crate::error::{CnftError, ProgramName};
+    use crate::simulation::SimulationResult;
+    use crate::simulator::SimulatedBackend;
+
+    use super::{send, RetryPolicy};
+
+
+    /// A simulated chain whose sends fail as scripted.
+    #[derive(Default)]
+    struct FlakyBackend {
+        chain: SimulatedBackend,
+
+        /// Errors the following sends fail with, the transaction landing anyway if `true`.
+        failures: Mutex<VecDeque<(CnftError, bool)>>,
+
+        /// Whether every blockhash is reported as expired.
+        expired: bool,
+
+        /// Signatures of every transaction sent, in order.
+        sent: Mutex<Vec<Signature>>
+    }
+
+    impl FlakyBackend {
+        fn install(failures: Vec<(CnftError, bool)>, expired: bool) -> Arc<FlakyBackend> {
+            let backend = Arc::new(FlakyBackend { failures: Mutex::new(failures.into()), expired, ..FlakyBackend::default() });
+            set_backend(backend.clone());
+
+            backend
+        }
+
+        fn sent(&self) -> Vec<Signature> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    impl ChainBackend for FlakyBackend {
+        fn minimum_balance_for_rent_exemption(&self, data_len: usize) -> Result<u64, CnftError> {
+            self.chain.minimum_balance_for_rent_exemption(data_len)
+        }
+
+        fn latest_blockhash(&self) -> Result<Hash, CnftError> {
+            self.chain.latest_blockhash()
+        }
+
+        fn send_and_confirm(&self, transaction: &Transaction) -> Result<(Signature, u64), CnftError> {
+            self.sent.lock().unwrap().push(transaction.signatures[0]);
+
+            match self.failures.lock().unwrap().pop_front() {
+                Some((error, landed)) => {
+                    if landed {
+                        self.chain.send_and_confirm(transaction)?;
+                    }
+                    Err(error)
+                }
+                None => self.chain.send_and_confirm(transaction)
+            }
+        }
+
+        fn send(&self, transaction: &Transaction) -> Result<Signature, CnftError> {
+            self.send_and_confirm(transaction).map(|(signature, _)| signature)
+        }
+
+        fn is_blockhash_valid(&self, blockhash: &Hash) -> Result<bool, CnftError> {
+            Ok(!self.expired && self.chain.is_blockhash_valid(blockhash)?)
+        }
+
+        fn signature_statuses(&self, signatures: &[Signature]) -> Result<Vec<SignatureStatus>, CnftError> {
+            self.chain.signature_statuses(signatures)
+        }
+
+        fn simulate(&self, transaction: &Transaction) -> Result<SimulationResult, CnftError> {
+            self.chain.simulate(transaction)
+        }
+
+        fn account_data(&self, pubkey: &Pubkey) -> Result<Option<Vec<u8>>, CnftError> {
+            self.chain.account_data(pubkey)
+        }
+
+        fn signatures_for_address(&self, address: &Pubkey) -> Result<Vec<Signature>, CnftError> {
+            self.chain.signatures_for_address(address)
+        }
+
+        fn confirmed_transaction(&self, signature: &Signature) -> Result<Option<ConfirmedTransaction>, CnftError> {
+            self.chain.confirmed_transaction(signature)
+        }
+    }
+
+
+    fn transient() -> (CnftError, bool) {
+        (CnftError::rpc("timeout", "request timed out"), false)
+    }
+
+
+    fn stale_proof() -> (CnftError, bool) {
+        let error = CnftError::Program {
+            program: ProgramName::AccountCompression,
+            code: 6001,
+            name: "ConcurrentMerkleTreeError".to_string(),
+            logs: Vec::new()
+        };
+
+        (error, false)
+    }
+
+
+    /// Sends a transaction of `payer`, returning the outcome and how often the transaction was built.
+    fn send_counting_builds(payer: &Keypair, rebuild_stale_proof: bool) -> (Result<(Signature, u64), CnftError>, usize) {
+        let mut builds = 0;
+        let result = send(|| {
+            builds += 1;
+            let blockhash = backend().latest_blockhash()?;
+            Ok(Transaction::new_signed_with_payer(&[], Some(&payer.pubkey()), &[payer], blockhash))
+        }, rebuild_stale_proof);
+
+        (result, builds)
+    }
+
+
+    #[test]
+    fn same_transaction_is_resent_while_its_blockhash_is_valid() {
+        let _backend = simulated_backend();
+        let backend = FlakyBackend::install(vec![transient()], false);
+
+        let (result, builds) = send_counting_builds(&Keypair::new(), false);
+
+        let (signature, _) = result.unwrap();
+        assert_eq!(builds, 1);
+        assert_eq!(backend.sent(), vec![signature, signature]);
+    }
+
+
+    #[test]
+    fn transaction_which_landed_despite_the_error_is_not_resent() {
+        let _backend = simulated_backend();
+        let backend = FlakyBackend::install(vec![(CnftError::rpc("timeout", "request timed out"), true)], false);
+
+        let (result, builds) = send_counting_builds(&Keypair::new(), false);
+
+        assert_eq!(result.unwrap().0, backend.sent()[0]);
+        assert_eq!(builds, 1);
+        assert_eq!(backend.sent().len(), 1);
+    }
+
+
+    #[test]
+    fn transaction_is_rebuilt_once_its_blockhash_expired() {
+        let _backend = simulated_backend();
+        let backend = FlakyBackend::install(vec![transient()], true);
+
+        let (result, builds) = send_counting_builds(&Keypair::new(), false);
+
+        assert!(result.is_ok());
+        assert_eq!(builds, 2);
+        assert_eq!(backend.sent().len(), 2);
+    }
+
+
+    #[test]
+    fn stale_proof_is_rebuilt_only_when_asked_for() {
+        let _backend = simulated_backend();
+        FlakyBackend::install(vec![stale_proof()], false);
+
+        let (result, builds) = send_counting_builds(&Keypair::new(), true);
+        assert!(result.is_ok());
+        assert_eq!(builds, 2);
+
+        FlakyBackend::install(vec![stale_proof()], false);
+
+        let (result, builds) = send_counting_builds(&Keypair::new(), false);
+        assert!(result.is_err_and(|e| e.is_stale_proof()));
+        assert_eq!(builds, 1);
+    }
+
+
+    #[test]
+    fn errors_are_returned_once_not_retryable_or_out_of_attempts() {
+        let _backend = simulated_backend();
+        let backend = FlakyBackend::install(vec![(CnftError::rpc("invalid_params", "rejected"), false)], false);
+
+        let (result, _) = send_counting_builds(&Keypair::new(), false);
+        assert!(matches!(result, Err(CnftError::Rpc { kind, .. }) if kind == "invalid_params"));
+        assert_eq!(backend.sent().len(), 1);
+
+        let attempts = RetryPolicy::default().max_attempts as usize;
+        let backend = FlakyBackend::install(vec![transient(); attempts], false);
+
+        let (result, _) = send_counting_builds(&Keypair::new(), false);
+        assert!(matches!(result, Err(CnftError::Rpc { kind, .. }) if kind == "timeout"));
+        assert_eq!(backend.sent().len(), attempts);
+    }
+
+
+    #[test]
+    fn backoff_doubles_up_to_its_bound() {
+        let policy = RetryPolicy { max_attempts: 10, backoff_ms: 500, max_backoff_ms: 3_000 };
+
+        let backoffs: Vec<Duration> = (1..=5).map(|retry| policy.backoff(retry)).collect();
+        assert_eq!(backoffs, [500, 1_000, 2_000, 3_000, 3_000].map(Duration::from_millis));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(3_000));
+    }
+}
@@ -1,28 +1,35 @@
 //! This module implements the core logic for our cNFT NIFs
 
 
-use std::str::FromStr;
-
-use anyhow::{anyhow, bail};
-use mpl_bubblegum::{accounts::TreeConfig, hash::{hash_creators, hash_metadata}, instructions::{CreateTreeConfigBuilder, MintV1Builder, TransferBuilder}, programs::{SPL_ACCOUNT_COMPRESSION_ID, SPL_NOOP_ID}, types::{LeafSchema, MetadataArgs, TokenProgramVersion, TokenStandard}, utils::get_asset_id};
-use once_cell::sync::Lazy;
-use rustler::NifStruct;
-use solana_client::rpc_client::RpcClient;
-use solana_sdk::{commitment_config::CommitmentConfig, instruction::AccountMeta, pubkey::Pubkey, signature::Keypair, signer::Signer, system_instruction, transaction::Transaction};
-use spl_account_compression::{state::CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1, ConcurrentMerkleTree};
+
+use mpl_bubblegum::{accounts::TreeConfig, hash::{hash_creators, hash_metadata}, instructions::{BurnBuilder, CreateTreeConfigBuilder, DelegateBuilder, MintV1Builder, TransferBuilder}, programs::{SPL_ACCOUNT_COMPRESSION_ID, SPL_NOOP_ID}, types::{LeafSchema, MetadataArgs, TokenProgramVersion, TokenStandard}, utils::get_asset_id};
+use rustler::{Encoder, Env, NifStruct, Term};
+use solana_sdk::{instruction::{AccountMeta, Instruction}, message::Message, pubkey::Pubkey, signature::{Keypair, Signature}, signer::Signer, system_instruction, transaction::Transaction};
+use spl_account_compression::{state::CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1, ConcurrentMerkleTree, EMPTY};
 use spl_merkle_tree_reference::{MerkleTree, Node};
 
-use crate::utils::{base58_to_array, convert_nodes, safely_from_base58_string};
+use crate::backend::backend;
+use crate::error::CnftError;
+use crate::events::{self, LeafUpdate};
+use crate::proof::{AssetProof, LeafId};
+use crate::proof_source::{leaves_from_history, read_tree_account, resolve_proof, ProofSource};
+use crate::result::OperationResult;
+use crate::retry::{send_with_proof_retry, send_with_retry};
+use crate::transaction::{Operation, UnsignedTransaction};
+use crate::utils::{convert_nodes, parse_hash, parse_pubkey};
+
 
+/// Depth of the merkle trees created by a TreeManager.
+pub(crate) const MAX_DEPTH: usize = 14;
 
-static RPC_CLIENT: Lazy<RpcClient> = Lazy::new(|| {
-    RpcClient::new_with_commitment(
-        "https://solana-devnet.g.alchemy.com/v2/IA5XqK-rU0LYpFekBWARC-2_lWQNqmFG",
-        CommitmentConfig::confirmed()
-    )
-});
+/// Number of concurrent changes the merkle trees created by a TreeManager can absorb.
+pub(crate) const MAX_BUFFER_SIZE: usize = 64;
 
 
+/// Where the proof of a rebuilt transaction is resolved from: the local tree if the chain still accepts it,
+/// otherwise the tree rebuilt from the chain history.
+const REBUILD_SOURCES: [ProofSource; 2] = [ProofSource::Local, ProofSource::ChainHistory];
+
 
 /// Represents the TreeManager used for managing the Off chain Merkle tree.
 
@@ -35,7 +42,9 @@ pub struct TreeManager {
     /// The maximum buffer size.
     pub max_buffer_size: usize,
 
-    /// Serialized representation of the merkle tree account keypair
+    /// Serialized representation of the merkle tree account keypair until the tree is created.
+    ///
+    /// Once the tree exists on chain only its 32 byte public key is kept, the secret key is never needed again.
     pub serialized_tree_account: Vec<u8>,
 
     /// Serialized representation of Tree nodes of the merkle tree.
@@ -46,62 +55,235 @@ pub struct TreeManager {
     pub nodes: Vec<Vec<u8>>,
 
     /// The number of minted cNFTS, also used for generating asset id and nonce field in Leaf Schema
-    pub minted: usize
+    pub minted: usize,
+
+    /// Mints sent with `mint_cnft_send_only` which are neither confirmed nor failed yet. They count against the
+    /// capacity of the tree, and every mint is built for the next nonce after them, so each is a distinct transaction.
+    pub pending_mints: usize
 }
 
 impl Default for TreeManager {
     fn default() -> Self {
         let keypair = Keypair::new();
-        let nodes = (0..16384).map(|_| vec![0; 32]).collect();
+        let nodes = (0..1 << MAX_DEPTH).map(|_| vec![0; 32]).collect();
         Self {
-            max_depth: 14, 
-            max_buffer_size: 64,
+            max_depth: MAX_DEPTH,
+            max_buffer_size: MAX_BUFFER_SIZE,
             serialized_tree_account: keypair.to_bytes().to_vec(),
-            nodes: nodes,
-            minted: 0
+            nodes,
+            minted: 0,
+            pending_mints: 0
+        }
+    }
+}
+
+/// What creating a tree returns to Elixir: `{tree_manager, result}`, or `{tree_manager, result, tree_keypair}`
+/// when the base58 keypair of the tree was asked for.
+pub enum CreatedTree {
+    Tree(TreeManager, OperationResult),
+    WithKeypair(TreeManager, OperationResult, String)
+}
+
+impl Encoder for CreatedTree {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            CreatedTree::Tree(tree_manager, result) => (tree_manager, result).encode(env),
+            CreatedTree::WithKeypair(tree_manager, result, tree_keypair) => (tree_manager, result, tree_keypair).encode(env)
         }
     }
 }
 
+
+
 impl TreeManager {
-    
+
+    /// Builds a TreeManager for a tree which already exists on chain, e.g. one created by another program or manager.
+    ///
+    /// The depth and buffer size are read from the header of the tree account and the minted count from its
+    /// Bubblegum `TreeConfig`. Only the public key of the tree is kept, so the tree cannot be created again
+    /// but cNFTs can be minted into it and transferred, burnt or delegated as usual.
+    ///
+    /// # Parameters
+    ///
+    /// * `tree_address` - The base58 public key of the merkle tree account.
+    /// * `rebuild_nodes` - Whether to rebuild the `nodes` from the transaction history of the tree.
+    ///   Otherwise they start out empty, and proofs of existing leaves have to come from another `ProofSource`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(TreeManager)` - The TreeManager of the existing tree.
+    /// * `Err(CnftError)` - An error if the accounts cannot be read, are not a Bubblegum tree,
+    ///   or the tree is not a `MAX_DEPTH = 14`, `MAX_BUFFER_SIZE = 64` tree.
+    pub fn attach(tree_address: &str, rebuild_nodes: bool) -> Result<TreeManager, CnftError> {
+        let merkle_tree = parse_pubkey(tree_address, "tree_address")?;
+
+        let (header, _) = read_tree_account(&merkle_tree)?;
+        let max_depth = header.get_max_depth() as usize;
+        let max_buffer_size = header.get_max_buffer_size() as usize;
+        if max_depth != MAX_DEPTH || max_buffer_size != MAX_BUFFER_SIZE {
+            return Err(CnftError::invalid_argument("tree_address", "only trees with max_depth 14 and max_buffer_size 64 are supported"));
+        }
+
+        let (tree_config, _) = TreeConfig::find_pda(&merkle_tree);
+        let tree_config_data = backend().account_data(&tree_config)?
+            .ok_or_else(|| CnftError::invalid_argument("tree_address", "the tree has no Bubblegum tree config"))?;
+        let tree_config = TreeConfig::from_bytes(&tree_config_data)
+            .map_err(|e| CnftError::invalid_argument("tree_address", e))?;
+
+        let nodes = if rebuild_nodes {
+            leaves_from_history(&merkle_tree, max_depth)?.iter().map(|leaf| leaf.to_vec()).collect()
+        } else {
+            (0..1 << max_depth).map(|_| EMPTY.to_vec()).collect()
+        };
+
+        Ok(TreeManager {
+            max_depth,
+            max_buffer_size,
+            serialized_tree_account: merkle_tree.to_bytes().to_vec(),
+            nodes,
+            minted: tree_config.num_minted as usize,
+            pending_mints: 0
+        })
+    }
+
+
+    /// Checks that the TreeManager handed over by the caller is consistent before any operation uses it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CnftError::InvalidState` if:
+    /// - The tree is not a `MAX_DEPTH = 14`, `MAX_BUFFER_SIZE = 64` tree.
+    /// - The number of nodes does not match the depth, or a node is not 32 bytes long.
+    /// - The serialized tree account is neither a valid 64 byte keypair nor a 32 byte public key.
+    /// - More cNFTs are recorded as minted or pending than the tree can hold.
+    pub fn validate(&self) -> Result<(), CnftError> {
+        if self.max_depth != MAX_DEPTH || self.max_buffer_size != MAX_BUFFER_SIZE {
+            return Err(CnftError::invalid_state("max_depth", "only trees with max_depth 14 and max_buffer_size 64 are supported"));
+        }
+
+        let capacity = 1usize << self.max_depth;
+        if self.nodes.len() != capacity {
+            return Err(CnftError::invalid_state("nodes", format!("expected {capacity} nodes, got {}", self.nodes.len())));
+        }
+
+        if let Some(index) = self.nodes.iter().position(|node| node.len() != 32) {
+            return Err(CnftError::invalid_state("nodes", format!("node {index} is {} bytes long, expected 32", self.nodes[index].len())));
+        }
+
+        if self.serialized_tree_account.len() != 32 && Keypair::from_bytes(&self.serialized_tree_account).is_err() {
+            return Err(CnftError::invalid_state("serialized_tree_account", "expected a 64 byte keypair or a 32 byte public key"));
+        }
+
+        if self.minted > capacity {
+            return Err(CnftError::invalid_state("minted", format!("{} cNFTs recorded as minted, tree holds {capacity}", self.minted)));
+        }
+
+        if self.minted + self.pending_mints > capacity {
+            return Err(CnftError::invalid_state("pending_mints", format!("{} mints pending on top of {} minted, tree holds {capacity}", self.pending_mints, self.minted)));
+        }
+
+        Ok(())
+    }
+
+
     /// Get Number of minted cnfts
     pub fn get_minted (&self) -> usize {
         self.minted
     }
 
+    /// Number of leaves the tree can hold
+    pub fn capacity(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Number of cNFTs which can still be minted into the tree, pending mints aside
+    pub fn remaining_capacity(&self) -> usize {
+        self.capacity().saturating_sub(self.minted + self.pending_mints)
+    }
+
+    /// Nonce the next mint is built for, after every confirmed and pending mint
+    fn next_nonce(&self) -> usize {
+        self.minted + self.pending_mints
+    }
+
     /// Get proof of the off-chain merkle tree
     pub fn get_proof (&self, merkle_tree: &MerkleTree, index: usize) -> Vec<Node>{
         merkle_tree.get_proof_of_leaf(index)
     }
 
+
+    /// Index of the leaf identified by `leaf`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the index is outside of the tree, or if no cNFT with the asset id was minted into this tree.
+    pub fn leaf_index(&self, leaf: &LeafId) -> Result<usize, CnftError> {
+        match leaf {
+            LeafId::Index(index) if *index < self.capacity() => Ok(*index),
+            LeafId::Index(index) => Err(CnftError::invalid_argument("index", format!("leaf {index} is outside of the tree"))),
+            LeafId::AssetId(asset_id) => {
+                let asset_id = parse_pubkey(asset_id, "asset_id")?;
+                let tree_account = self.tree_pubkey()?;
+
+                // Leaves are appended in mint order, so the nonce of an asset is also the index of its leaf.
+                (0..self.minted)
+                    .find(|nonce| get_asset_id(&tree_account, *nonce as u64) == asset_id)
+                    .ok_or_else(|| CnftError::invalid_argument("asset_id", "no cNFT with this asset id was minted into the tree"))
+            }
+        }
+    }
+
+
+    /// Builds the proof of a leaf in the shape of the DAS `getAssetProof` response.
+    ///
+    /// # Parameters
+    ///
+    /// * `leaf` - The index of the leaf or the asset id of the cNFT stored in it.
+    /// * `canopy_depth` - Depth of the canopy of the tree. That many nodes next to the root are left out of the proof.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(AssetProof)` - The root, the proof, the leaf and the tree id, base58 encoded.
+    /// * `Err(CnftError)` - An error if the leaf cannot be found or the canopy is deeper than the tree.
+    pub fn asset_proof(&self, leaf: &LeafId, canopy_depth: usize) -> Result<AssetProof, CnftError> {
+        if canopy_depth > self.max_depth {
+            return Err(CnftError::invalid_argument("canopy_depth", format!("must not exceed the max depth of {}", self.max_depth)));
+        }
+
+        let index = self.leaf_index(leaf)?;
+        let off_chain_merkle_tree = self.off_chain_merkle_tree()?;
+
+        Ok(AssetProof::from_merkle_tree(&off_chain_merkle_tree, &self.tree_pubkey()?, index, canopy_depth))
+    }
+
     
     /// Creates a new Merkle tree on Solana by initializing the tree account and configuration.
     /// 
     /// For now it only supports tress with MAX_DEPTH = 14 and MAX_BUFFER_SIZE = 64
     ///
     ///
-    /// This function uses the owner's private key (in base58 format) to derive the tree owner,
+    /// This function uses the owner's signer as payer and tree creator,
     /// calculates the required size for the tree account (including the concurrent Merkle tree header),
     /// fetches the minimum balance for rent exemption, and constructs the necessary system and configuration
     /// instructions. It then signs and sends the transaction, returning the transaction signature as a string.
     ///
     /// # Parameters
     ///
-    /// * `owner_private_key` - A string slice representing the owner's private key in base58 format.
-    ///                         This key is used both as the payer for account creation and as the tree creator.
+    /// * `tree_owner` - The signer of the tree owner.
+    ///   It is used both as the payer for account creation and as the tree creator.
     /// 
     /// # Returns
     ///
-    /// * `Ok(String)` - On success, returns the transaction signature as a string.
-    /// * `Err(String)`  - Returns an error message if any step (decoding, rent calculation, transaction sending, etc.) fails.
+    /// * `Ok((OperationResult, Keypair))` - On success, returns the transaction signature, slot and tree addresses, and the tree keypair.
+    ///   The TreeManager only keeps the public key of the tree from then on, so this is the last chance to store the keypair.
+    /// * `Err(CnftError)`  - Returns an error if any step (decoding, rent calculation, transaction sending, etc.) fails.
     ///
     /// # Example
     ///
     /// ```rust
     /// // Assuming `tree_manager` is a mutable instance of TreeManager.
-    /// match tree_manager.create_tree("owner_private_key_in_base58") {
-    ///     Ok(txn_sig) => println!("Transaction signature: {}", txn_sig),
+    /// match tree_manager.create_tree(&tree_owner_keypair) {
+    ///     Ok((result, _tree_keypair)) => println!("Transaction signature: {}", result.signature),
     ///     Err(err) => eprintln!("Failed to create tree: {}", err),
     /// }
     /// ```
@@ -109,32 +291,47 @@ impl TreeManager {
     /// # Errors
     ///
     /// Returns an error if:
-    /// - The serialized tree account fails to convert into a `Keypair`.
-    /// - The provided `owner_private_key` is empty or cannot be decoded.
+    /// - The serialized tree account fails to convert into a `Keypair`, e.g. because the tree was already created or attached.
     /// - The rent exemption balance cannot be retrieved.
     /// - The transaction fails to be signed or confirmed.
-    pub fn create_tree(&mut self, owner_private_key: &str) -> Result<String, String> {
-        const MAX_DEPTH: usize= 14;
-        const MAX_BUFFER_SIZE: usize = 64;
-        let tree_account = Keypair::from_bytes(self.serialized_tree_account.as_slice()).map_err(|e| e.to_string())?;
+    pub fn create_tree(&mut self, tree_owner: &dyn Signer) -> Result<(OperationResult, Keypair), CnftError> {
+        let (tree_account, instructions) = self.create_tree_instructions(&tree_owner.pubkey())?;
+
+        let (sig, slot) = send_built(|| signed_transaction(
+            &instructions,
+            &tree_owner.pubkey(),
+            &[&tree_account, tree_owner]
+        ))?;
 
+        self.serialized_tree_account = tree_account.pubkey().to_bytes().to_vec();
+    
+        Ok((OperationResult::new(sig, slot, &tree_account.pubkey()), tree_account))
         
-        if owner_private_key.trim().is_empty() {
-            return Err("owner_private_key parameter must be provided".to_string());
+    }
+
+
+    /// Builds the instructions of `create_tree`, creating the tree account and its Bubblegum tree config, along with
+    /// the keypair of the tree account, which has to sign them.
+    pub(crate) fn create_tree_instructions(&self, tree_owner: &Pubkey) -> Result<(Keypair, Vec<Instruction>), CnftError> {
+        if self.serialized_tree_account.len() == 32 {
+            return Err(CnftError::invalid_state("serialized_tree_account", "the tree was already created or attached, there is no keypair to create it with"));
         }
-        let tree_owner = safely_from_base58_string(owner_private_key).map_err(|e| e.to_string())?;  
+
+        let tree_account = Keypair::from_bytes(self.serialized_tree_account.as_slice())
+            .map_err(|_| CnftError::invalid_key("serialized_tree_account"))?;
+
     
         let size = CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1 + 
             std::mem::size_of::<ConcurrentMerkleTree<MAX_DEPTH, MAX_BUFFER_SIZE>>();
         
-        let rent = RPC_CLIENT.get_minimum_balance_for_rent_exemption(size).map_err(|e| e.to_string())?;
+        let rent = backend().minimum_balance_for_rent_exemption(size)?;
         
         
         let (tree_config, _) = TreeConfig::find_pda(&tree_account.pubkey());
     
         
         let tree_account_ix = system_instruction::create_account(
-            &tree_owner.pubkey(),
+            tree_owner,
             &tree_account.pubkey(),
             rent,
             size as u64,
@@ -143,9 +340,9 @@ impl TreeManager {
      
         let tree_config_ix = CreateTreeConfigBuilder::new()
             .tree_config(tree_config)
-            .payer(tree_owner.pubkey())
+            .payer(*tree_owner)
             .merkle_tree(tree_account.pubkey())
-            .tree_creator(tree_owner.pubkey())
+            .tree_creator(*tree_owner)
             .log_wrapper(SPL_NOOP_ID)
             .compression_program(SPL_ACCOUNT_COMPRESSION_ID)
             .system_program(solana_program::system_program::id())
@@ -153,47 +350,64 @@ impl TreeManager {
             .max_buffer_size(MAX_BUFFER_SIZE as u32)
             .public(false)
             .instruction();
-    
-    
-        let tree_txn = Transaction::new_signed_with_payer(
-            &[tree_account_ix, tree_config_ix],
-            Some(&tree_owner.pubkey()),
-            &[&tree_account, &tree_owner],
-            RPC_CLIENT.get_latest_blockhash().map_err(|e| e.to_string())? 
-        );
-    
-        let sig = RPC_CLIENT.send_and_confirm_transaction(&tree_txn).map_err(|e| e.to_string())?;
-    
-        Ok(sig.to_string())
-        
+
+
+        Ok((tree_account, vec![tree_account_ix, tree_config_ix]))
+    }
+
+
+    /// Drops the secret key of the tree from a TreeManager serialized before the secret key stopped being kept.
+    ///
+    /// The keypair is only replaced by the public key of the tree if the tree account exists on chain,
+    /// the keypair of a tree which was not created yet is still needed by `create_tree`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` - If the secret key was dropped.
+    /// * `Ok(false)` - If there was no secret key, or the tree does not exist yet.
+    /// * `Err(CnftError)` - An error if the tree account cannot be read.
+    pub fn migrate(&mut self) -> Result<bool, CnftError> {
+        if self.serialized_tree_account.len() == 32 {
+            return Ok(false);
+        }
+
+        let tree_account = self.tree_pubkey()?;
+        if backend().account_data(&tree_account)?.is_none() {
+            return Ok(false);
+        }
+
+        self.serialized_tree_account = tree_account.to_bytes().to_vec();
+
+        Ok(true)
     }
 
 
-    
-    
     /// Mints a new Compressed NFT (cNFT) to the specified owner within the Merkle tree.
     ///
     /// This function constructs a new metadata entry, signs a mint transaction, and submits it to the Solana blockchain.
     /// The function also updates the nodes of the current TreeManager instance after successfull minting.
+    ///
+    /// The new leaf is taken from the changelog and leaf schema events of the confirmed transaction, as it lands
+    /// wherever the tree is at: not at `minted` if someone else minted into the tree, or if an earlier mint was
+    /// confirmed without being recorded.
     /// 
     ///
     /// # Parameters
     ///
-    /// * `owner_private_key` - A string slice representing the private key of the tree owner in base58 format.
-    ///                         This key is used to sign the transaction.
+    /// * `tree_owner` - The signer of the tree owner, used to sign the transaction.
     /// * `nft_owner` - A string slice representing the public key of the recipient in base58 format.
     ///
     /// # Returns
     ///
-    /// * `Ok(String)` - On success, returns the transaction signature of the mint operation.
-    /// * `Err(anyhow::Error)` - Returns an error if any step fails (invalid keys, transaction failure, etc.).
+    /// * `Ok(OperationResult)` - On success, returns the signature and slot of the mint along with the leaf index, nonce and asset id of the cNFT.
+    /// * `Err(CnftError)` - Returns an error if any step fails (invalid keys, transaction failure, etc.).
     ///
     /// # Example
     ///
     /// ```rust
     /// // Assuming `tree_manager` is a mutable instance of TreeManager.
-    /// match tree_manager.mint_cnft("owner_private_key_in_base58", "recipient_pubkey_in_base58") {
-    ///     Ok(txn_sig) => println!("Minted successfully. Transaction signature: {}", txn_sig),
+    /// match tree_manager.mint_cnft(&tree_owner_keypair, "recipient_pubkey_in_base58") {
+    ///     Ok(result) => println!("Minted successfully. Transaction signature: {}", result.signature),
     ///     Err(err) => eprintln!("Minting failed: {}", err),
     /// }
     /// ```
@@ -201,85 +415,25 @@ impl TreeManager {
     /// # Errors
     ///
     /// Returns an error if:
+    /// - The tree is full (`CnftError::TreeFull`), checked before the transaction is submitted.
     /// - The serialized tree account fails to convert into a `Keypair`.
-    /// - The `owner_private_key` is empty or invalid.
     /// - The `nft_owner` public key is invalid.
     /// - The transaction fails to be signed or confirmed.
-    pub fn mint_cnft(&mut self, owner_private_key: &str, nft_owner: &str) -> Result<String, anyhow::Error> {
+    /// - The confirmed transaction or its leaf cannot be read back (`CnftError::InvalidState`). The cNFT is minted
+    ///   then, but not recorded.
+    pub fn mint_cnft(&mut self, tree_owner: &dyn Signer, nft_owner: &str) -> Result<OperationResult, CnftError> {
 
-        let tree_account = Keypair::from_bytes(self.serialized_tree_account.as_slice())
-            .map_err(|e| anyhow!("Error: {}, {}:{}", e, file!(), line!()))?;
-        
-        if owner_private_key.trim().is_empty() {
-            bail!("owner_private_key parameter must be provided, {}:{}", file!(), line!());
-        }
-        let tree_owner = safely_from_base58_string(owner_private_key)
-            .map_err(|e| anyhow!("Error: {}, {}:{}", e, file!(), line!()))?;  
+        let nft_owner = parse_pubkey(nft_owner, "nft_owner")?;
 
-        let nft_owner = Pubkey::from_str(nft_owner)
-            .map_err(|e| anyhow!("Error: {}, {}:{}", e, file!(), line!()))?;
-        
-        let (tree_config, _) = TreeConfig::find_pda(&tree_account.pubkey());
+        let (mint_ix, _) = self.mint_instruction(&tree_owner.pubkey(), &nft_owner)?;
     
-    
-        let metadata = MetadataArgs {
-            name: format!("Prajjwal's cnft {}", self.minted),
-            symbol: String::from("PcNFT"),
-            uri: String::from("https://cdn.100xdevs.com/metadata.json"),
-            seller_fee_basis_points: 0,
-            primary_sale_happened: true,
-            is_mutable: true,
-            edition_nonce: None,
-            token_standard: Some(TokenStandard::NonFungible),
-            collection: None,
-            uses: None,
-            token_program_version: TokenProgramVersion::Original,
-            creators: vec![],
-        };
+        let (signature, slot) = send_with_retry(|| signed_transaction(std::slice::from_ref(&mint_ix), &tree_owner.pubkey(), &[tree_owner]))?;
 
-    
-        
-        let mint_ix = MintV1Builder::new()
-            .leaf_delegate(nft_owner)
-            .leaf_owner(nft_owner)
-            .merkle_tree(tree_account.pubkey())
-            .payer(tree_owner.pubkey())
-            .tree_config(tree_config)
-            .tree_creator_or_delegate(tree_owner.pubkey())
-            .metadata(metadata.clone())
-            .instruction();
-    
-        let mint_txn = Transaction::new_signed_with_payer(
-            &[mint_ix],
-            Some(&tree_owner.pubkey()),
-            &[&tree_owner],
-            RPC_CLIENT.get_latest_blockhash().map_err(|e| anyhow!("Error: {}, {}:{}", e, file!(), line!()))? 
-        );
-   
-    
-        let sig = RPC_CLIENT.send_and_confirm_transaction(&mint_txn)
-            .map_err(|e| anyhow!("Error: {}, {}:{}", e, file!(), line!()))?;
+        let (index, nonce, leaf_hash) = self.minted_leaf(&signature)?;
 
-        let minted_nonce = self.get_minted();
-        let data_hash = hash_metadata(&metadata)
-            .map_err(|e| anyhow!("Error: {}, {}:{}", e, file!(), line!()))?;
-        let creator_hash = hash_creators(&metadata.creators);
-        let asset_id  = get_asset_id(&tree_account.pubkey(), minted_nonce as u64);
-        
-        let leaf = LeafSchema::V1 { 
-            id: asset_id,
-            owner: nft_owner,
-            delegate: nft_owner, 
-            nonce: minted_nonce as u64, 
-            data_hash: data_hash, 
-            creator_hash: creator_hash 
-        };
-
-        self.nodes[minted_nonce] = leaf.hash().to_vec();
+        self.apply_leaf_update(Operation::Mint, index, leaf_hash.clone());
 
-        self.minted += 1;
-
-        Ok(sig.to_string())
+        self.operation_result((signature.to_string(), slot), index, nonce, &leaf_hash)
     }
 
 
@@ -292,8 +446,8 @@ impl TreeManager {
     ///
     /// # Arguments
     ///
-    /// * `tree_owner_private_key` - The private key of the tree owner, used to authorize the transfer.
-    /// * `old_owner_private_key` - The private key of the current NFT owner, required for signing the transfer.
+    /// * `tree_owner` - The signer of the tree owner, used as fee payer.
+    /// * `old_owner` - The signer of the current NFT owner, required for signing the transfer.
     /// * `new_owner_pub_key` - The public key of the new NFT owner who will receive the transferred NFT.
     /// * `index` - The index of the NFT within the Merkle tree.
     /// * `data_hash` - The base58-encoded hash of the NFT metadata.
@@ -301,31 +455,476 @@ impl TreeManager {
     ///
     /// # Returns
     ///
-    /// * `Ok(String)` - A transaction signature confirming the successful transfer.
-    /// * `Err(anyhow::Error)` - An error if the transfer fails.
+    /// * `Ok(OperationResult)` - The signature and slot of the transfer along with the hash of the new leaf.
+    /// * `Err(CnftError)` - An error if the transfer fails.
     ///
     /// # Errors
     ///
     /// This function can fail due to:
-    /// - Invalid or empty private keys.
     /// - Errors while converting the provided keys and hashes.
     /// - Issues with generating the Merkle proof.
     /// - Transaction failures on the Solana blockchain.
-
     pub fn transfer_cnft(
         &mut self,
-        tree_owner_private_key: &str, 
-        old_owner_private_key: &str, 
+        tree_owner: &dyn Signer, 
+        old_owner: &dyn Signer, 
         new_owner_pub_key: &str,
         index: usize,
         data_hash: &str,
         creator_hash: &str
-    ) -> Result<String, anyhow::Error> {
-        
-        let leaves: [Node; 16384] = convert_nodes(self.nodes.clone());
-        let off_chain_merkle_tree = MerkleTree::new(&leaves);
+    ) -> Result<OperationResult, CnftError> {
+
+
+        let new_owner = parse_pubkey(new_owner_pub_key, "new_owner_pub_key")?;
+
+
+        let (transfer_ix, leaf) = self.transfer_instruction(&old_owner.pubkey(), &new_owner, index, data_hash, creator_hash)?;
+
+
+        let confirmation = send_rebuilding(
+            transfer_ix,
+            || {
+                let proof = self.resolved_proof_accounts(&old_owner.pubkey(), index, data_hash, creator_hash, &REBUILD_SOURCES)?;
+                Ok(self.transfer_instruction_with_proof(&old_owner.pubkey(), &new_owner, index, data_hash, creator_hash, proof)?.0)
+            },
+            &tree_owner.pubkey(),
+            &[old_owner, tree_owner]
+        )?;
+
+        self.apply_leaf_update(Operation::Transfer, index, leaf.hash().to_vec());
+
+        self.operation_result(confirmation, index, leaf.nonce(), &leaf.hash())
+
+    }
+
+
+
+
+    /// Transfers a compressed NFT (cNFT) like `transfer_cnft`, taking the proof from the first of `sources`
+    /// which provides one the tree still accepts.
+    ///
+    /// This allows transferring cNFTs whose leaf the local `nodes` do not know about, e.g. cNFTs minted into
+    /// the tree by someone else. The leaf is recorded in the local `nodes` once the transfer is confirmed.
+    ///
+    /// # Arguments
+    ///
+    /// * `tree_owner` - The signer of the tree owner, used as fee payer.
+    /// * `old_owner` - The signer of the current NFT owner, which must also be its delegate.
+    /// * `new_owner_pub_key` - The public key of the new NFT owner.
+    /// * `index` - The index of the NFT within the Merkle tree.
+    /// * `data_hash` - The base58-encoded hash of the NFT metadata.
+    /// * `creator_hash` - The base58-encoded hash of the NFT creators.
+    /// * `sources` - The proof sources to try, in order.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(OperationResult)` - The signature and slot of the transfer along with the hash of the new leaf.
+    /// * `Err(CnftError)` - `CnftError::ProofUnavailable` if no source provides an accepted proof,
+    ///   or an error if the transfer fails.
+    #[allow(clippy::too_many_arguments)]
+    pub fn transfer_cnft_with_sources(
+        &mut self,
+        tree_owner: &dyn Signer,
+        old_owner: &dyn Signer,
+        new_owner_pub_key: &str,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str,
+        sources: &[ProofSource]
+    ) -> Result<OperationResult, CnftError> {
+
+        let new_owner = parse_pubkey(new_owner_pub_key, "new_owner_pub_key")?;
+        let index = self.leaf_index(&LeafId::Index(index))?;
+
+        let (transfer_ix, leaf) = self.transfer_instruction_with_sources(&old_owner.pubkey(), &new_owner, index, data_hash, creator_hash, sources)?;
+
+        // The proof is resolved again if the transaction has to be rebuilt, from the chain history as a last resort.
+        let rebuild_sources: Vec<ProofSource> = sources.iter().cloned().chain(REBUILD_SOURCES).collect();
+        let confirmation = send_rebuilding(
+            transfer_ix,
+            || Ok(self.transfer_instruction_with_sources(&old_owner.pubkey(), &new_owner, index, data_hash, creator_hash, &rebuild_sources)?.0),
+            &tree_owner.pubkey(),
+            &[old_owner, tree_owner]
+        )?;
+
+        self.apply_leaf_update(Operation::Transfer, index, leaf.hash().to_vec());
+
+        self.operation_result(confirmation, index, leaf.nonce(), &leaf.hash())
+    }
+
+
+
+
+    /// Burns a compressed NFT (cNFT), replacing its leaf in the Merkle tree with an empty node.
+    ///
+    /// # Arguments
+    ///
+    /// * `tree_owner` - The signer of the tree owner, used as fee payer.
+    /// * `owner` - The signer of the current NFT owner, required for signing the burn.
+    /// * `index` - The index of the NFT within the Merkle tree.
+    /// * `data_hash` - The base58-encoded hash of the NFT metadata.
+    /// * `creator_hash` - The base58-encoded hash of the NFT creators.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(OperationResult)` - The signature and slot of the burn, the leaf hash being the empty node.
+    /// * `Err(CnftError)` - An error if the burn fails.
+    pub fn burn_cnft(
+        &mut self,
+        tree_owner: &dyn Signer,
+        owner: &dyn Signer,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str
+    ) -> Result<OperationResult, CnftError> {
+
+        let burn_ix = self.burn_instruction(&owner.pubkey(), index, data_hash, creator_hash)?;
+
+        let confirmation = send_rebuilding(
+            burn_ix,
+            || {
+                let proof = self.resolved_proof_accounts(&owner.pubkey(), index, data_hash, creator_hash, &REBUILD_SOURCES)?;
+                self.burn_instruction_with_proof(&owner.pubkey(), index, data_hash, creator_hash, proof)
+            },
+            &tree_owner.pubkey(),
+            &[owner, tree_owner]
+        )?;
+
+        self.apply_leaf_update(Operation::Burn, index, EMPTY.to_vec());
+
+        // The burn proved the leaf of nonce `index`, see `burn_instruction_with_proof`.
+        self.operation_result(confirmation, index, index as u64, &EMPTY)
+    }
+
+
+
+
+    /// Sets a new leaf delegate for a compressed NFT (cNFT).
+    ///
+    /// # Arguments
+    ///
+    /// * `tree_owner` - The signer of the tree owner, used as fee payer.
+    /// * `owner` - The signer of the current NFT owner, required for signing.
+    /// * `new_delegate_pub_key` - The public key of the new leaf delegate.
+    /// * `index` - The index of the NFT within the Merkle tree.
+    /// * `data_hash` - The base58-encoded hash of the NFT metadata.
+    /// * `creator_hash` - The base58-encoded hash of the NFT creators.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(OperationResult)` - The signature and slot of the delegation along with the hash of the new leaf.
+    /// * `Err(CnftError)` - An error if the delegation fails.
+    pub fn delegate_cnft(
+        &mut self,
+        tree_owner: &dyn Signer,
+        owner: &dyn Signer,
+        new_delegate_pub_key: &str,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str
+    ) -> Result<OperationResult, CnftError> {
+
+        let new_delegate = parse_pubkey(new_delegate_pub_key, "new_delegate_pub_key")?;
+
+        let (delegate_ix, leaf) = self.delegate_instruction(&owner.pubkey(), &new_delegate, index, data_hash, creator_hash)?;
+
+        let confirmation = send_rebuilding(
+            delegate_ix,
+            || {
+                let proof = self.resolved_proof_accounts(&owner.pubkey(), index, data_hash, creator_hash, &REBUILD_SOURCES)?;
+                Ok(self.delegate_instruction_with_proof(&owner.pubkey(), &new_delegate, index, data_hash, creator_hash, proof)?.0)
+            },
+            &tree_owner.pubkey(),
+            &[owner, tree_owner]
+        )?;
+
+        self.apply_leaf_update(Operation::Delegate, index, leaf.hash().to_vec());
+
+        self.operation_result(confirmation, index, leaf.nonce(), &leaf.hash())
+    }
+
+
+
+
+    /// Builds an unsigned mint transaction to be signed outside of Rust, e.g. by a wallet.
+    ///
+    /// The leaf index of the returned transaction is the current `minted` counter, so the
+    /// transaction must be submitted before another cNFT is minted into this tree.
+    ///
+    /// # Arguments
+    ///
+    /// * `tree_owner_pub_key` - The public key of the tree owner, which pays for and authorizes the mint.
+    /// * `nft_owner_pub_key` - The public key of the recipient.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(UnsignedTransaction)` - The serialized transaction and the list of required signers.
+    /// * `Err(CnftError)` - An error if a key is invalid or the blockhash cannot be fetched.
+    pub fn build_mint_cnft(&self, tree_owner_pub_key: &str, nft_owner_pub_key: &str) -> Result<UnsignedTransaction, CnftError> {
+        let tree_owner = parse_pubkey(tree_owner_pub_key, "tree_owner_pub_key")?;
+        let nft_owner = parse_pubkey(nft_owner_pub_key, "nft_owner_pub_key")?;
+
+        let (mint_ix, leaf) = self.mint_instruction(&tree_owner, &nft_owner)?;
+
+        UnsignedTransaction::new(
+            &unsigned_transaction(mint_ix, &tree_owner)?,
+            Operation::Mint,
+            leaf.nonce() as usize,
+            leaf.hash().to_vec()
+        )
+    }
+
+
+    /// Builds an unsigned transfer transaction to be signed outside of Rust, e.g. by a wallet.
+    ///
+    /// # Arguments
+    ///
+    /// * `payer_pub_key` - The public key of the fee payer.
+    /// * `owner_pub_key` - The public key of the current NFT owner.
+    /// * `new_owner_pub_key` - The public key of the new NFT owner.
+    /// * `index` - The index of the NFT within the Merkle tree.
+    /// * `data_hash` - The base58-encoded hash of the NFT metadata.
+    /// * `creator_hash` - The base58-encoded hash of the NFT creators.
+    pub fn build_transfer_cnft(
+        &self,
+        payer_pub_key: &str,
+        owner_pub_key: &str,
+        new_owner_pub_key: &str,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str
+    ) -> Result<UnsignedTransaction, CnftError> {
+        let payer = parse_pubkey(payer_pub_key, "payer_pub_key")?;
+        let owner = parse_pubkey(owner_pub_key, "owner_pub_key")?;
+        let new_owner = parse_pubkey(new_owner_pub_key, "new_owner_pub_key")?;
+
+        let (transfer_ix, leaf) = self.transfer_instruction(&owner, &new_owner, index, data_hash, creator_hash)?;
+
+        UnsignedTransaction::new(
+            &unsigned_transaction(transfer_ix, &payer)?,
+            Operation::Transfer,
+            index,
+            leaf.hash().to_vec()
+        )
+    }
+
+
+    /// Builds an unsigned burn transaction to be signed outside of Rust, e.g. by a wallet.
+    ///
+    /// # Arguments
+    ///
+    /// * `payer_pub_key` - The public key of the fee payer.
+    /// * `owner_pub_key` - The public key of the current NFT owner.
+    /// * `index` - The index of the NFT within the Merkle tree.
+    /// * `data_hash` - The base58-encoded hash of the NFT metadata.
+    /// * `creator_hash` - The base58-encoded hash of the NFT creators.
+    pub fn build_burn_cnft(
+        &self,
+        payer_pub_key: &str,
+        owner_pub_key: &str,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str
+    ) -> Result<UnsignedTransaction, CnftError> {
+        let payer = parse_pubkey(payer_pub_key, "payer_pub_key")?;
+        let owner = parse_pubkey(owner_pub_key, "owner_pub_key")?;
+
+        let burn_ix = self.burn_instruction(&owner, index, data_hash, creator_hash)?;
+
+        UnsignedTransaction::new(
+            &unsigned_transaction(burn_ix, &payer)?,
+            Operation::Burn,
+            index,
+            EMPTY.to_vec()
+        )
+    }
+
+
+    /// Builds an unsigned delegate transaction to be signed outside of Rust, e.g. by a wallet.
+    ///
+    /// # Arguments
+    ///
+    /// * `payer_pub_key` - The public key of the fee payer.
+    /// * `owner_pub_key` - The public key of the current NFT owner.
+    /// * `new_delegate_pub_key` - The public key of the new leaf delegate.
+    /// * `index` - The index of the NFT within the Merkle tree.
+    /// * `data_hash` - The base58-encoded hash of the NFT metadata.
+    /// * `creator_hash` - The base58-encoded hash of the NFT creators.
+    pub fn build_delegate_cnft(
+        &self,
+        payer_pub_key: &str,
+        owner_pub_key: &str,
+        new_delegate_pub_key: &str,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str
+    ) -> Result<UnsignedTransaction, CnftError> {
+        let payer = parse_pubkey(payer_pub_key, "payer_pub_key")?;
+        let owner = parse_pubkey(owner_pub_key, "owner_pub_key")?;
+        let new_delegate = parse_pubkey(new_delegate_pub_key, "new_delegate_pub_key")?;
+
+        let (delegate_ix, leaf) = self.delegate_instruction(&owner, &new_delegate, index, data_hash, creator_hash)?;
+
+        UnsignedTransaction::new(
+            &unsigned_transaction(delegate_ix, &payer)?,
+            Operation::Delegate,
+            index,
+            leaf.hash().to_vec()
+        )
+    }
+
+
+
+
+    /// Verifies and submits a transaction which was built by one of the `build_*` functions
+    /// and signed outside of Rust.
+    ///
+    /// The signed transaction must carry exactly the message that was built, and every
+    /// required signature must be present and valid. Once the transaction is confirmed the
+    /// leaf update it carries is applied to the nodes of the current TreeManager instance.
+    ///
+    /// # Arguments
+    ///
+    /// * `unsigned` - The `UnsignedTransaction` returned by the `build_*` function.
+    /// * `signed_transaction` - The base64-encoded, fully signed transaction.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(OperationResult)` - The signature and slot of the transaction along with the leaf it changed.
+    /// * `Err(CnftError)` - An error if verification or submission fails.
+    pub fn submit_signed_transaction(&mut self, unsigned: &UnsignedTransaction, signed_transaction: &str) -> Result<OperationResult, CnftError> {
+        if unsigned.leaf_index >= self.nodes.len() || unsigned.leaf_hash.len() != 32 {
+            return Err(CnftError::invalid_argument("unsigned_transaction", "leaf change does not fit this tree"));
+        }
+
+        let signed_txn = unsigned.verify_signed(signed_transaction)?;
+
+        let (signature, slot) = send_and_confirm(&signed_txn)?;
+
+        // A mint only fixes its leaf once processed, so the leaf it was built with is replaced by the one it reports.
+        // Every other operation proves the leaf of nonce `leaf_index`.
+        let (index, nonce, leaf_hash) = match unsigned.operation {
+            Operation::Mint => self.minted_leaf(&signature.parse().map_err(|e| CnftError::invalid_argument("signature", e))?)?,
+            _ => (unsigned.leaf_index, unsigned.leaf_index as u64, unsigned.leaf_hash.clone())
+        };
+
+        self.apply_leaf_update(unsigned.operation, index, leaf_hash.clone());
+
+        self.operation_result((signature, slot), index, nonce, &leaf_hash)
+    }
+
+
 
-        let proof: Vec<AccountMeta> = self.get_proof(&off_chain_merkle_tree, index)
+
+    /// Records a confirmed leaf change in the nodes of the current TreeManager instance.
+    ///
+    /// Bubblegum appends the leaf of the n-th mint at index n, so a mint at `index` means `index + 1` cNFTs were minted.
+    pub(crate) fn apply_leaf_update(&mut self, operation: Operation, index: usize, leaf_hash: Vec<u8>) {
+        self.nodes[index] = leaf_hash;
+
+        if operation == Operation::Mint {
+            self.minted = self.minted.max(index + 1);
+        }
+    }
+
+
+    /// The leaves the confirmed transaction `signature` wrote into the tree, in the order they were written,
+    /// as reported by its events.
+    ///
+    /// Fails with the RPC error if the transaction cannot be fetched, or `CnftError::InvalidState` if the backend
+    /// does not serve it, e.g. because the RPC node has not caught up yet.
+    pub(crate) fn confirmed_leaves(&self, signature: &Signature) -> Result<Vec<LeafUpdate>, CnftError> {
+        let tree_account = self.tree_pubkey()?;
+
+        let transaction = backend().confirmed_transaction(signature)?
+            .ok_or_else(|| CnftError::invalid_state("transaction", format!("{signature} was confirmed but cannot be fetched back")))?;
+
+        Ok(events::leaf_updates(&transaction.noop_data, &tree_account)
+            .into_iter()
+            .filter(|update| (update.index as usize) < self.capacity())
+            .collect())
+    }
+
+
+    /// Index, nonce and hash of the leaf the confirmed mint `signature` wrote into the tree, as reported by its
+    /// events. The leaf the mint was built for is never assumed, as mints land wherever the tree is at.
+    ///
+    /// Fails as `confirmed_leaves` does, or with `CnftError::InvalidState` if the transaction reports no minted leaf.
+    pub(crate) fn minted_leaf(&self, signature: &Signature) -> Result<(usize, u64, Vec<u8>), CnftError> {
+        self.confirmed_leaves(signature)?
+            .pop()
+            .and_then(|update| Some((update.index as usize, update.nonce()?, update.leaf_hash.to_vec())))
+            .ok_or_else(|| CnftError::invalid_state("leaf", format!("the mint was confirmed in {signature} but its leaf could not be read back")))
+    }
+
+
+    /// Result of a confirmed transaction which set the leaf at `index` to `leaf_hash`, holding the cNFT of `nonce`.
+    pub(crate) fn operation_result(&self, (signature, slot): (String, u64), index: usize, nonce: u64, leaf_hash: &[u8]) -> Result<OperationResult, CnftError> {
+        Ok(OperationResult::leaf_change(signature, slot, &self.tree_pubkey()?, index, nonce, leaf_hash))
+    }
+
+
+    /// Public key of the merkle tree account
+    pub fn tree_pubkey(&self) -> Result<Pubkey, CnftError> {
+        if let Ok(pubkey) = <[u8; 32]>::try_from(self.serialized_tree_account.as_slice()) {
+            return Ok(Pubkey::new_from_array(pubkey));
+        }
+
+        let tree_account = Keypair::from_bytes(self.serialized_tree_account.as_slice())
+            .map_err(|_| CnftError::invalid_key("serialized_tree_account"))?;
+
+        Ok(tree_account.pubkey())
+    }
+
+
+    /// Metadata of the next cNFT minted into this tree
+    fn next_metadata(&self) -> MetadataArgs {
+        MetadataArgs {
+            name: format!("Prajjwal's cnft {}", self.next_nonce()),
+            symbol: String::from("PcNFT"),
+            uri: String::from("https://cdn.100xdevs.com/metadata.json"),
+            seller_fee_basis_points: 0,
+            primary_sale_happened: true,
+            is_mutable: true,
+            edition_nonce: None,
+            token_standard: Some(TokenStandard::NonFungible),
+            collection: None,
+            uses: None,
+            token_program_version: TokenProgramVersion::Original,
+            creators: vec![],
+        }
+    }
+
+
+    /// Builds the off-chain merkle tree from the nodes of the current TreeManager instance.
+    pub(crate) fn off_chain_merkle_tree(&self) -> Result<MerkleTree, CnftError> {
+        let leaves: Vec<Node> = convert_nodes(self.nodes.clone())?;
+
+        Ok(MerkleTree::new(&leaves))
+    }
+
+
+    /// Builds the off-chain merkle tree and returns its root along with the proof of the leaf
+    /// at `index`, as accounts ready to be appended to a bubblegum instruction.
+    fn proof_accounts(&self, index: usize) -> Result<([u8; 32], Vec<AccountMeta>), CnftError> {
+        if index >= self.nodes.len() {
+            return Err(CnftError::invalid_argument("index", format!("leaf {index} is outside of the tree")));
+        }
+
+        let off_chain_merkle_tree = self.off_chain_merkle_tree()?;
+
+        self.snapshot_proof_accounts(&off_chain_merkle_tree, index)
+    }
+
+
+    /// Root of `snapshot`, an off-chain merkle tree built from this TreeManager, along with the proof of the leaf
+    /// at `index` as accounts ready to be appended to a bubblegum instruction.
+    pub(crate) fn snapshot_proof_accounts(&self, snapshot: &MerkleTree, index: usize) -> Result<([u8; 32], Vec<AccountMeta>), CnftError> {
+        if index >= self.nodes.len() {
+            return Err(CnftError::invalid_argument("index", format!("leaf {index} is outside of the tree")));
+        }
+
+        let proof: Vec<AccountMeta> = self.get_proof(snapshot, index)
             .iter()
             .map(|node| AccountMeta {
                 pubkey: Pubkey::new_from_array(*node),
@@ -334,82 +933,332 @@ impl TreeManager {
             })
             .collect();
 
-        let data_hash_as_array = base58_to_array(data_hash)
-            .map_err(|e| anyhow!("Error while converting data hash: {}, {}:{}", e, file!(), line!()))?;
+        Ok((snapshot.root, proof))
+    }
 
-        let creator_hash_as_array = base58_to_array(creator_hash)
-            .map_err(|e| anyhow!("Error while converting creator hash: {}, {}:{}", e, file!(), line!()))?;
-        
-        let tree_account = Keypair::from_bytes(self.serialized_tree_account.as_slice())
-            .map_err(|e| anyhow!("Error: {}, {}:{}", e, file!(), line!()))?;
-        
-        if tree_owner_private_key.trim().is_empty() {
-            bail!("owner_private_key parameter must be provided, {}:{}", file!(), line!());
+
+    /// Builds the `MintV1` instruction for the next cNFT along with the leaf it will create.
+    pub(crate) fn mint_instruction(&self, tree_owner: &Pubkey, nft_owner: &Pubkey) -> Result<(Instruction, LeafSchema), CnftError> {
+        if self.remaining_capacity() == 0 {
+            return Err(CnftError::TreeFull { capacity: self.capacity() });
         }
-        let tree_owner = safely_from_base58_string(tree_owner_private_key)
-            .map_err(|e| anyhow!("Error: {}, {}:{}", e, file!(), line!()))?;  
 
+        let tree_account = self.tree_pubkey()?;
 
-        if old_owner_private_key.trim().is_empty() {
-                bail!("old_owner_private_key parameter must be provided, {}:{}", file!(), line!());
-        }
-        let old_owner = safely_from_base58_string(old_owner_private_key)
-                .map_err(|e| anyhow!("Error: {}, {}:{}", e, file!(), line!()))?;  
-        
+        let metadata = self.next_metadata();
 
-        let new_owner = Pubkey::from_str(new_owner_pub_key)
-            .map_err(|e| anyhow!("Error: {}, {}:{}", e, file!(), line!()))?;
+        let minted_nonce = self.next_nonce();
+        let data_hash = hash_metadata(&metadata)
+            .map_err(|e| CnftError::invalid_argument("metadata", e))?;
+        let creator_hash = hash_creators(&metadata.creators);
+        let asset_id  = get_asset_id(&tree_account, minted_nonce as u64);
 
+        let mint_ix = self.mint_v1_instruction(tree_owner, nft_owner, metadata)?;
 
-        let (tree_config, _) = TreeConfig::find_pda(&tree_account.pubkey());
+        let leaf = LeafSchema::V1 { 
+            id: asset_id,
+            owner: *nft_owner,
+            delegate: *nft_owner, 
+            nonce: minted_nonce as u64, 
+            data_hash, 
+            creator_hash 
+        };
 
-        
+        Ok((mint_ix, leaf))
+    }
+
+
+    /// Builds the `MintV1` instruction minting a cNFT with `metadata` to `nft_owner`, who is also its delegate.
+    pub(crate) fn mint_v1_instruction(&self, tree_owner: &Pubkey, nft_owner: &Pubkey, metadata: MetadataArgs) -> Result<Instruction, CnftError> {
+        let tree_account = self.tree_pubkey()?;
+        let (tree_config, _) = TreeConfig::find_pda(&tree_account);
+
+        Ok(MintV1Builder::new()
+            .leaf_delegate(*nft_owner)
+            .leaf_owner(*nft_owner)
+            .merkle_tree(tree_account)
+            .payer(*tree_owner)
+            .tree_config(tree_config)
+            .tree_creator_or_delegate(*tree_owner)
+            .metadata(metadata)
+            .instruction())
+    }
 
 
+    /// Builds the `Transfer` instruction for the leaf at `index` along with the leaf it will create.
+    pub(crate) fn transfer_instruction(
+        &self,
+        owner: &Pubkey,
+        new_owner: &Pubkey,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str
+    ) -> Result<(Instruction, LeafSchema), CnftError> {
+        let proof = self.proof_accounts(index)?;
+
+        self.transfer_instruction_with_proof(owner, new_owner, index, data_hash, creator_hash, proof)
+    }
+
+
+    /// Builds the `Transfer` instruction for the leaf at `index` against the given root and proof accounts.
+    pub(crate) fn transfer_instruction_with_proof(
+        &self,
+        owner: &Pubkey,
+        new_owner: &Pubkey,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str,
+        (root, proof): ([u8; 32], Vec<AccountMeta>)
+    ) -> Result<(Instruction, LeafSchema), CnftError> {
+        let data_hash_as_array = parse_hash(data_hash, "data_hash")?;
+
+        let creator_hash_as_array = parse_hash(creator_hash, "creator_hash")?;
+
+        let tree_account = self.tree_pubkey()?;
+        let (tree_config, _) = TreeConfig::find_pda(&tree_account);
+
         let transfer_ix = TransferBuilder::new()
             .tree_config(tree_config)
-            .leaf_owner(old_owner.pubkey(), true)
-            .leaf_delegate(old_owner.pubkey(), false)
-            .new_leaf_owner(new_owner)
-            .merkle_tree(tree_account.pubkey())
+            .leaf_owner(*owner, true)
+            .leaf_delegate(*owner, false)
+            .new_leaf_owner(*new_owner)
+            .merkle_tree(tree_account)
             .log_wrapper(SPL_NOOP_ID)
             .compression_program(SPL_ACCOUNT_COMPRESSION_ID)
             .system_program(solana_program::system_program::id())
-            .root(off_chain_merkle_tree.root)
+            .root(root)
             .data_hash(data_hash_as_array)
             .creator_hash(creator_hash_as_array)
             .nonce(index as u64)
             .index(index as u32)
             .add_remaining_accounts(&proof)
             .instruction();
-        
 
-        let transfer_txn = Transaction::new_signed_with_payer(
-            &[transfer_ix],
-            Some(&tree_owner.pubkey()),
-            &[&old_owner, &tree_owner],
-            RPC_CLIENT.get_latest_blockhash().map_err(|e| anyhow!("Error: {}, {}:{}", e, file!(), line!()))? 
-        );
+        let leaf = LeafSchema::V1 { 
+            id: get_asset_id(&tree_account, index as u64),
+            owner: *new_owner,
+            delegate: *new_owner, 
+            nonce: index as u64, 
+            data_hash: data_hash_as_array, 
+            creator_hash: creator_hash_as_array 
+        };
 
+        Ok((transfer_ix, leaf))
+    }
 
-        let sig = RPC_CLIENT.send_and_confirm_transaction(&transfer_txn)
-        .map_err(|e| anyhow!("Error: {}, {}:{}", e, file!(), line!()))?;
 
-    
-        let asset_id  = get_asset_id(&tree_account.pubkey(), index as u64);
-        
+    /// Builds the `Transfer` instruction of `transfer_cnft_with_sources`, taking the proof from the first of `sources`
+    /// which provides one the tree still accepts.
+    pub(crate) fn transfer_instruction_with_sources(
+        &self,
+        old_owner: &Pubkey,
+        new_owner: &Pubkey,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str,
+        sources: &[ProofSource]
+    ) -> Result<(Instruction, LeafSchema), CnftError> {
+        let proof = self.resolved_proof_accounts(old_owner, index, data_hash, creator_hash, sources)?;
+
+        self.transfer_instruction_with_proof(old_owner, new_owner, index, data_hash, creator_hash, proof)
+    }
+
+
+    /// Root and proof accounts of the leaf `owner` holds at `index` as its own delegate, taken from the first of
+    /// `sources` which provides a proof the tree still accepts, see `proof_source::resolve_proof`.
+    pub(crate) fn resolved_proof_accounts(
+        &self,
+        owner: &Pubkey,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str,
+        sources: &[ProofSource]
+    ) -> Result<([u8; 32], Vec<AccountMeta>), CnftError> {
+        let tree_account = self.tree_pubkey()?;
+        let previous_leaf = LeafSchema::V1 {
+            id: get_asset_id(&tree_account, index as u64),
+            owner: *owner,
+            delegate: *owner,
+            nonce: index as u64,
+            data_hash: parse_hash(data_hash, "data_hash")?,
+            creator_hash: parse_hash(creator_hash, "creator_hash")?
+        };
+
+        let proof = resolve_proof(self, index, previous_leaf.hash(), sources)?;
+
+        Ok((parse_hash(&proof.root, "root")?, proof.proof_accounts()?))
+    }
+
+
+    /// Builds the `Burn` instruction for the leaf at `index`.
+    pub(crate) fn burn_instruction(
+        &self,
+        owner: &Pubkey,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str
+    ) -> Result<Instruction, CnftError> {
+        let proof = self.proof_accounts(index)?;
+
+        self.burn_instruction_with_proof(owner, index, data_hash, creator_hash, proof)
+    }
+
+
+    /// Builds the `Burn` instruction for the leaf at `index` against the given root and proof accounts.
+    pub(crate) fn burn_instruction_with_proof(
+        &self,
+        owner: &Pubkey,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str,
+        (root, proof): ([u8; 32], Vec<AccountMeta>)
+    ) -> Result<Instruction, CnftError> {
+        let data_hash_as_array = parse_hash(data_hash, "data_hash")?;
+
+        let creator_hash_as_array = parse_hash(creator_hash, "creator_hash")?;
+
+        let tree_account = self.tree_pubkey()?;
+        let (tree_config, _) = TreeConfig::find_pda(&tree_account);
+
+        let burn_ix = BurnBuilder::new()
+            .tree_config(tree_config)
+            .leaf_owner(*owner, true)
+            .leaf_delegate(*owner, false)
+            .merkle_tree(tree_account)
+            .log_wrapper(SPL_NOOP_ID)
+            .compression_program(SPL_ACCOUNT_COMPRESSION_ID)
+            .system_program(solana_program::system_program::id())
+            .root(root)
+            .data_hash(data_hash_as_array)
+            .creator_hash(creator_hash_as_array)
+            .nonce(index as u64)
+            .index(index as u32)
+            .add_remaining_accounts(&proof)
+            .instruction();
+
+        Ok(burn_ix)
+    }
+
+
+    /// Builds the `Delegate` instruction for the leaf at `index` along with the leaf it will create.
+    pub(crate) fn delegate_instruction(
+        &self,
+        owner: &Pubkey,
+        new_delegate: &Pubkey,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str
+    ) -> Result<(Instruction, LeafSchema), CnftError> {
+        let proof = self.proof_accounts(index)?;
+
+        self.delegate_instruction_with_proof(owner, new_delegate, index, data_hash, creator_hash, proof)
+    }
+
+
+    /// Builds the `Delegate` instruction for the leaf at `index` against the given root and proof accounts.
+    pub(crate) fn delegate_instruction_with_proof(
+        &self,
+        owner: &Pubkey,
+        new_delegate: &Pubkey,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str,
+        (root, proof): ([u8; 32], Vec<AccountMeta>)
+    ) -> Result<(Instruction, LeafSchema), CnftError> {
+        let data_hash_as_array = parse_hash(data_hash, "data_hash")?;
+
+        let creator_hash_as_array = parse_hash(creator_hash, "creator_hash")?;
+
+        let tree_account = self.tree_pubkey()?;
+        let (tree_config, _) = TreeConfig::find_pda(&tree_account);
+
+        let delegate_ix = DelegateBuilder::new()
+            .tree_config(tree_config)
+            .leaf_owner(*owner)
+            .previous_leaf_delegate(*owner)
+            .new_leaf_delegate(*new_delegate)
+            .merkle_tree(tree_account)
+            .log_wrapper(SPL_NOOP_ID)
+            .compression_program(SPL_ACCOUNT_COMPRESSION_ID)
+            .system_program(solana_program::system_program::id())
+            .root(root)
+            .data_hash(data_hash_as_array)
+            .creator_hash(creator_hash_as_array)
+            .nonce(index as u64)
+            .index(index as u32)
+            .add_remaining_accounts(&proof)
+            .instruction();
+
         let leaf = LeafSchema::V1 { 
-            id: asset_id,
-            owner: new_owner,
-            delegate: new_owner, 
+            id: get_asset_id(&tree_account, index as u64),
+            owner: *owner,
+            delegate: *new_delegate, 
             nonce: index as u64, 
             data_hash: data_hash_as_array, 
             creator_hash: creator_hash_as_array 
         };
 
-        self.nodes[index] = leaf.hash().to_vec();
+        Ok((delegate_ix, leaf))
+    }
+}
 
-        Ok(sig.to_string())
 
-    }
+/// Signs the instructions into a transaction using the latest blockhash.
+///
+/// Unlike `Transaction::new_signed_with_payer`, a failing signer (e.g. an external signer that timed out)
+/// is reported as an error instead of a panic.
+pub(crate) fn signed_transaction(instructions: &[Instruction], payer: &Pubkey, signers: &[&dyn Signer]) -> Result<Transaction, CnftError> {
+    let blockhash = backend().latest_blockhash()?;
+
+    let mut txn = Transaction::new_unsigned(Message::new(instructions, Some(payer)));
+    txn.try_sign(signers, blockhash)?;
+
+    Ok(txn)
+}
+
+
+/// Sends the transaction and waits for its confirmation, decoding program errors of its instructions.
+/// Transient failures are retried with the same transaction, see `retry::send_with_retry`.
+/// Returns the signature and the slot the transaction was processed in.
+pub(crate) fn send_and_confirm(txn: &Transaction) -> Result<(String, u64), CnftError> {
+    send_built(|| Ok(txn.clone()))
+}
+
+
+/// Like `send_and_confirm`, taking the transaction from `build`, which is called again for a transaction
+/// with a fresh blockhash once an earlier one expired without landing.
+pub(crate) fn send_built(build: impl FnMut() -> Result<Transaction, CnftError>) -> Result<(String, u64), CnftError> {
+    send_with_retry(build)
+        .map(|(sig, slot)| (sig.to_string(), slot))
+}
+
+
+/// Like `send_built`, signing `instruction` into the first transaction and an instruction from `rebuild` into every
+/// rebuilt one. Besides an expired blockhash, a transaction is rebuilt once the tree rejected its proof, so `rebuild`
+/// must resolve a proof the tree on chain accepts, e.g. from `REBUILD_SOURCES`, see `retry::send_with_proof_retry`.
+fn send_rebuilding(
+    instruction: Instruction,
+    mut rebuild: impl FnMut() -> Result<Instruction, CnftError>,
+    payer: &Pubkey,
+    signers: &[&dyn Signer]
+) -> Result<(String, u64), CnftError> {
+    let mut instruction = Some(instruction);
+
+    send_with_proof_retry(|| {
+        let instruction = match instruction.take() {
+            Some(instruction) => instruction,
+            None => rebuild()?
+        };
+
+        signed_transaction(&[instruction], payer, signers)
+    })
+    .map(|(sig, slot)| (sig.to_string(), slot))
+}
+
+
+/// Wraps a single instruction into an unsigned transaction using the latest blockhash.
+fn unsigned_transaction(instruction: Instruction, payer: &Pubkey) -> Result<Transaction, CnftError> {
+    let blockhash = backend().latest_blockhash()?;
+
+    Ok(Transaction::new_unsigned(Message::new_with_blockhash(&[instruction], Some(payer), &blockhash)))
 }
@@ -0,0 +1,266 @@
+//! This module implements the shared TreeManager, a lock-protected handle many BEAM processes can mint through
+
+
+
+use std::collections::BTreeSet;
+use std::sync::{Mutex, MutexGuard};
+
+use solana_sdk::signer::Signer;
+
+use crate::error::CnftError;
+use crate::result::OperationResult;
+use crate::setup::TreeManager;
+use crate::simulation::SimulationResult;
+use crate::transaction::Operation;
+use crate::utils::parse_hash;
+
+
+
+/// A TreeManager held by the BEAM as a resource, so every process minting into the tree sees the same state.
+///
+/// A mint first reserves a leaf, which counts against the capacity of the tree until it is committed with the
+/// confirmed leaf or rolled back. The lock is only held while reserving, committing and rolling back, never while
+/// a transaction is in flight.
+pub struct SharedTreeManager {
+    state: Mutex<SharedState>
+}
+
+#[rustler::resource_impl]
+impl rustler::Resource for SharedTreeManager {}
+
+
+struct SharedState {
+    tree_manager: TreeManager,
+
+    /// Leaf indexes handed out and not yet committed or rolled back.
+    reservations: BTreeSet<usize>
+}
+
+
+
+impl SharedTreeManager {
+
+    /// Shares `tree_manager`, which must already be created on chain.
+    pub fn new(tree_manager: TreeManager) -> Result<Self, CnftError> {
+        tree_manager.validate()?;
+
+        Ok(Self {
+            state: Mutex::new(SharedState { tree_manager, reservations: BTreeSet::new() })
+        })
+    }
+
+
+    fn lock(&self) -> MutexGuard<'_, SharedState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+
+    /// A copy of the committed state, e.g. to build proofs for transfers.
+    pub fn snapshot(&self) -> TreeManager {
+        self.lock().tree_manager.clone()
+    }
+
+
+    /// Number of reserved leaves not yet committed or rolled back.
+    pub fn pending_reservations(&self) -> usize {
+        self.lock().reservations.len()
+    }
+
+
+    /// Reserves the next free leaf index.
+    ///
+    /// Bubblegum appends leaves in the order mints land, so the reserved index is where the leaf is expected,
+    /// and a mint landing ahead of an earlier reservation takes its place. What is guaranteed is that the tree
+    /// has room for every reserved leaf.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CnftError::TreeFull` if every remaining leaf is already reserved.
+    pub fn reserve(&self) -> Result<usize, CnftError> {
+        let mut state = self.lock();
+        let tree_manager = &state.tree_manager;
+
+        if tree_manager.minted + state.reservations.len() >= tree_manager.capacity() {
+            return Err(CnftError::TreeFull { capacity: tree_manager.capacity() });
+        }
+
+        let index = (tree_manager.minted..)
+            .find(|index| !state.reservations.contains(index))
+            .unwrap_or(tree_manager.minted);
+        state.reservations.insert(index);
+
+        Ok(index)
+    }
+
+
+    /// Releases `reservation` and records the confirmed mint of `leaf_hash` at `index`, the index the leaf
+    /// actually landed at.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `reservation` is not pending or `index` is outside of the tree.
+    pub fn commit(&self, reservation: usize, index: usize, leaf_hash: Vec<u8>) -> Result<(), CnftError> {
+        let mut state = self.lock();
+
+        if index >= state.tree_manager.capacity() {
+            return Err(CnftError::invalid_argument("index", format!("leaf {index} is outside of the tree")));
+        }
+        if !state.reservations.remove(&reservation) {
+            return Err(CnftError::invalid_state("reservation", format!("leaf {reservation} is not reserved")));
+        }
+
+        state.tree_manager.apply_leaf_update(Operation::Mint, index, leaf_hash);
+
+        Ok(())
+    }
+
+
+    /// Releases `reservation` without recording anything, e.g. because the mint failed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `reservation` is not pending.
+    pub fn rollback(&self, reservation: usize) -> Result<(), CnftError> {
+        if !self.lock().reservations.remove(&reservation) {
+            return Err(CnftError::invalid_state("reservation", format!("leaf {reservation} is not reserved")));
+        }
+
+        Ok(())
+    }
+
+
+    /// Mints a cNFT like `TreeManager::mint_cnft`, reserving its leaf first, then committing the confirmed leaf
+    /// or rolling the reservation back if the mint fails.
+    ///
+    /// # Arguments
+    ///
+    /// * `tree_owner` - The signer of the tree owner, used to authorize the minting.
+    /// * `nft_owner` - The public key of the recipient.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(OperationResult)` - The signature and slot of the mint along with the leaf it landed at.
+    /// * `Err(CnftError)` - `CnftError::TreeFull` if every remaining leaf is reserved, or an error if the mint fails.
+    pub fn mint_cnft(&self, tree_owner: &dyn Signer, nft_owner: &str) -> Result<OperationResult, CnftError> {
+        let reservation = self.reserve()?;
+
+        // The copy mints as if the reserved leaf were the next one, so the nonce it expects is the reserved index.
+        let mut tree_manager = self.snapshot();
+        tree_manager.minted = reservation;
+
+        let minted = tree_manager.mint_cnft(tree_owner, nft_owner)
+            .and_then(|result| {
+                let index = result.leaf_index.ok_or_else(|| CnftError::invalid_state("leaf_index", "the mint reported no leaf"))?;
+                let leaf_hash = parse_hash(result.leaf_hash.as_deref().unwrap_or_default(), "leaf_hash")?;
+
+                Ok((result, index, leaf_hash))
+            });
+
+        match minted {
+            Ok((result, index, leaf_hash)) => {
+                self.commit(reservation, index, leaf_hash.to_vec())?;
+                Ok(result)
+            }
+            Err(e) => {
+                self.rollback(reservation)?;
+                Err(e)
+            }
+        }
+    }
+
+
+    /// Simulates `mint_cnft` without reserving anything. Reserved leaves count against the capacity of the tree,
+    /// as with `reserve`.
+    pub fn simulate_mint_cnft(&self, tree_owner: &dyn Signer, nft_owner: &str) -> Result<SimulationResult, CnftError> {
+        let tree_manager = {
+            let state = self.lock();
+            let mut tree_manager = state.tree_manager.clone();
+            tree_manager.minted += state.reservations.len();
+            tree_manager
+        };
+
+        tree_manager.simulate_mint_cnft(tree_owner, nft_owner)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{signature::Keypair, signer::Signer};
+
+    use crate::backend::simulated_backend;
+    use crate::error::CnftError;
+    use crate::setup::TreeManager;
+
+    use super::SharedTreeManager;
+
+
+    #[test]
+    fn reservations_are_distinct_and_count_against_the_capacity() {
+        let mut tree_manager = TreeManager::default();
+        tree_manager.minted = tree_manager.capacity() - 2;
+        let shared = SharedTreeManager::new(tree_manager).unwrap();
+
+        let first = shared.reserve().unwrap();
+        let second = shared.reserve().unwrap();
+
+        assert_eq!((first, second), (shared.snapshot().capacity() - 2, shared.snapshot().capacity() - 1));
+        assert_eq!(shared.pending_reservations(), 2);
+        assert!(matches!(shared.reserve(), Err(CnftError::TreeFull { .. })));
+    }
+
+
+    #[test]
+    fn commit_records_the_leaf_where_it_landed() {
+        let shared = SharedTreeManager::new(TreeManager::default()).unwrap();
+        let first = shared.reserve().unwrap();
+        let second = shared.reserve().unwrap();
+
+        // The second mint landed first, taking leaf 0.
+        shared.commit(second, 0, vec![1; 32]).unwrap();
+
+        let snapshot = shared.snapshot();
+        assert_eq!(snapshot.minted, 1);
+        assert_eq!(snapshot.nodes[0], vec![1; 32]);
+        assert_eq!(shared.pending_reservations(), 1);
+        assert!(matches!(shared.commit(second, 1, vec![1; 32]), Err(CnftError::InvalidState { field, .. }) if field == "reservation"));
+        assert!(matches!(shared.commit(first, snapshot.capacity(), vec![1; 32]), Err(CnftError::InvalidArgument { field, .. }) if field == "index"));
+        assert_eq!(shared.pending_reservations(), 1);
+    }
+
+
+    #[test]
+    fn rollback_frees_the_reserved_leaf() {
+        let shared = SharedTreeManager::new(TreeManager::default()).unwrap();
+        let first = shared.reserve().unwrap();
+        shared.reserve().unwrap();
+
+        shared.rollback(first).unwrap();
+
+        assert_eq!(shared.pending_reservations(), 1);
+        assert_eq!(shared.snapshot().minted, 0);
+        assert_eq!(shared.reserve().unwrap(), first);
+        assert!(matches!(shared.rollback(5), Err(CnftError::InvalidState { field, .. }) if field == "reservation"));
+    }
+
+
+    #[test]
+    fn mint_commits_its_reservation_and_rolls_it_back_on_failure() {
+        let _backend = simulated_backend();
+        let owner = Keypair::new();
+        let mut tree_manager = TreeManager::default();
+        tree_manager.create_tree(&owner).unwrap();
+        let shared = SharedTreeManager::new(tree_manager).unwrap();
+
+        let result = shared.mint_cnft(&owner, &Keypair::new().pubkey().to_string()).unwrap();
+
+        assert_eq!(result.leaf_index, Some(0));
+        assert_eq!(shared.snapshot().minted, 1);
+        assert_eq!(shared.pending_reservations(), 0);
+
+        assert!(shared.mint_cnft(&Keypair::new(), &owner.pubkey().to_string()).is_err());
+        assert_eq!(shared.snapshot().minted, 1);
+        assert_eq!(shared.pending_reservations(), 0);
+    }
+}
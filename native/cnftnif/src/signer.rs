@@ -0,0 +1,338 @@
+//! This module implements the signers used to authorize TreeManager operations
+//!
+//! A signer is passed from Elixir as one of:
+//! - a keypair resource created by `keypair_generate`, `keypair_from_file` or `keypair_from_base58`.
+//!   The secret key of such a keypair never leaves Rust.
+//! - `{:keypair_file, path}` pointing to a solana-keygen JSON keypair file.
+//! - `{:external, pid, pub_key}` or `{:external, pid, pub_key, timeout_ms}`, which asks the Elixir process `pid`
+//!   to sign the message.
+//! - a base58 encoded private key string, kept for backwards compatibility.
+
+
+
+use std::{sync::{Condvar, Mutex}, thread, time::Duration};
+
+use rustler::{types::tuple::get_tuple, Atom, Decoder, Encoder, Env, LocalPid, NifResult, OwnedBinary, OwnedEnv, ResourceArc, Term};
+use solana_sdk::{pubkey::Pubkey, signature::{read_keypair_file, Keypair, Signature}, signer::{Signer, SignerError}};
+
+use crate::{error::CnftError, utils::{parse_pubkey, safely_from_base58_string}};
+
+
+mod atoms {
+    rustler::atoms! {
+        keypair_file,
+        external,
+        cnft_sign_request
+    }
+}
+
+
+/// Time an external signer is given to answer a sign request, unless the signer specifies its own.
+const DEFAULT_EXTERNAL_SIGNER_TIMEOUT_MS: u64 = 30_000;
+
+
+
+/// A keypair held by the BEAM as an opaque resource. The secret key is never encoded back to Elixir.
+pub struct KeypairResource(pub Keypair);
+
+#[rustler::resource_impl]
+impl rustler::Resource for KeypairResource {}
+
+
+
+/// A pending request sent to an external signer, answered through `sign_reply` or `sign_reject`.
+pub struct SignRequest {
+    reply: Mutex<Option<Result<Vec<u8>, String>>>,
+    answered: Condvar
+}
+
+#[rustler::resource_impl]
+impl rustler::Resource for SignRequest {}
+
+impl SignRequest {
+
+    fn new() -> Self {
+        Self {
+            reply: Mutex::new(None),
+            answered: Condvar::new()
+        }
+    }
+
+
+    /// Stores the answer of the external signer and wakes up the waiting operation.
+    pub fn answer(&self, reply: Result<Vec<u8>, String>) {
+        let mut slot = self.reply.lock().unwrap_or_else(|e| e.into_inner());
+        *slot = Some(reply);
+        self.answered.notify_all();
+    }
+
+
+    /// Waits up to `timeout` for the answer, which must be a valid signature of `message` by `pubkey`.
+    fn signature(&self, pubkey: &Pubkey, message: &[u8], timeout: Duration) -> Result<Signature, SignerError> {
+        let slot = self.reply.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut slot, _) = self.answered
+            .wait_timeout_while(slot, timeout, |reply| reply.is_none())
+            .unwrap_or_else(|e| e.into_inner());
+
+        match slot.take() {
+            Some(Ok(bytes)) => {
+                let signature = Signature::try_from(bytes.as_slice())
+                    .map_err(|_| SignerError::Custom("external signer returned a malformed signature".to_string()))?;
+
+                if !signature.verify(pubkey.as_ref(), message) {
+                    return Err(SignerError::Custom("external signer returned an invalid signature".to_string()));
+                }
+                Ok(signature)
+            },
+            Some(Err(reason)) => Err(SignerError::UserCancel(reason)),
+            None => Err(SignerError::Connection("external signer timed out".to_string()))
+        }
+    }
+}
+
+
+
+/// Signer as described by the Elixir caller, resolved into a `NifSigner` when an operation needs it.
+pub enum SignerSpec {
+    Base58(String),
+    Keypair(ResourceArc<KeypairResource>),
+    KeypairFile(String),
+    External { pid: LocalPid, pub_key: String, timeout_ms: u64 }
+}
+
+impl<'a> Decoder<'a> for SignerSpec {
+    fn decode(term: Term<'a>) -> NifResult<Self> {
+        if let Ok(resource) = term.decode::<ResourceArc<KeypairResource>>() {
+            return Ok(SignerSpec::Keypair(resource));
+        }
+
+        if let Ok(secret) = term.decode::<String>() {
+            return Ok(SignerSpec::Base58(secret));
+        }
+
+        let tuple = get_tuple(term)?;
+        let tag: Atom = tuple.first().ok_or(rustler::Error::BadArg)?.decode()?;
+
+        match tuple.len() {
+            2 if tag == atoms::keypair_file() => Ok(SignerSpec::KeypairFile(tuple[1].decode()?)),
+            3 if tag == atoms::external() => Ok(SignerSpec::External {
+                pid: tuple[1].decode()?,
+                pub_key: tuple[2].decode()?,
+                timeout_ms: DEFAULT_EXTERNAL_SIGNER_TIMEOUT_MS
+            }),
+            4 if tag == atoms::external() => Ok(SignerSpec::External {
+                pid: tuple[1].decode()?,
+                pub_key: tuple[2].decode()?,
+                timeout_ms: tuple[3].decode()?
+            }),
+            _ => Err(rustler::Error::BadArg)
+        }
+    }
+}
+
+impl SignerSpec {
+
+    /// Resolves the description into a signer, loading keypair files and decoding keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `field` - Name of the parameter the signer was passed as, used in error messages.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - A base58 private key is empty or invalid (`{:invalid_key, field}`).
+    /// - A keypair file cannot be read (`{:invalid_argument, field, message}`).
+    /// - The public key of an external signer is invalid (`{:invalid_key, field}`).
+    pub fn resolve(&self, field: &str) -> Result<NifSigner, CnftError> {
+        match self {
+            SignerSpec::Base58(secret) => {
+                let keypair = safely_from_base58_string(secret)
+                    .map_err(|_| CnftError::invalid_key(field))?;
+                Ok(NifSigner::Owned(keypair))
+            },
+            SignerSpec::Keypair(resource) => Ok(NifSigner::Resource(resource.clone())),
+            SignerSpec::KeypairFile(path) => {
+                let keypair = read_keypair_file(path)
+                    .map_err(|e| CnftError::invalid_argument(field, format!("cannot read keypair file {path}: {e}")))?;
+                Ok(NifSigner::Owned(keypair))
+            },
+            SignerSpec::External { pid, pub_key, timeout_ms } => {
+                let pubkey = parse_pubkey(pub_key, field)?;
+                Ok(NifSigner::External(ExternalSigner {
+                    pid: *pid,
+                    pubkey,
+                    timeout: Duration::from_millis(*timeout_ms)
+                }))
+            }
+        }
+    }
+}
+
+
+
+/// A resolved signer, usable wherever the Solana SDK expects a `Signer`.
+pub enum NifSigner {
+    Owned(Keypair),
+    Resource(ResourceArc<KeypairResource>),
+    External(ExternalSigner)
+}
+
+impl Signer for NifSigner {
+    fn try_pubkey(&self) -> Result<Pubkey, SignerError> {
+        match self {
+            NifSigner::Owned(keypair) => keypair.try_pubkey(),
+            NifSigner::Resource(resource) => resource.0.try_pubkey(),
+            NifSigner::External(external) => external.try_pubkey()
+        }
+    }
+
+    fn try_sign_message(&self, message: &[u8]) -> Result<Signature, SignerError> {
+        match self {
+            NifSigner::Owned(keypair) => keypair.try_sign_message(message),
+            NifSigner::Resource(resource) => resource.0.try_sign_message(message),
+            NifSigner::External(external) => external.try_sign_message(message)
+        }
+    }
+
+    fn is_interactive(&self) -> bool {
+        matches!(self, NifSigner::External(_))
+    }
+}
+
+
+
+/// Signer which delegates signing to an Elixir process.
+///
+/// The process receives `{:cnft_sign_request, request, message, pub_key}` where `message` is the binary to sign,
+/// and answers with `CnftNif.sign_reply(request, signature)` or `CnftNif.sign_reject(request, reason)`.
+/// The process must not be the one calling the NIF, since that process is blocked until the answer arrives.
+pub struct ExternalSigner {
+    pid: LocalPid,
+    pubkey: Pubkey,
+    timeout: Duration
+}
+
+impl Signer for ExternalSigner {
+    fn try_pubkey(&self) -> Result<Pubkey, SignerError> {
+        Ok(self.pubkey)
+    }
+
+    fn try_sign_message(&self, message: &[u8]) -> Result<Signature, SignerError> {
+        let request = ResourceArc::new(SignRequest::new());
+
+        // Messages cannot be sent from a thread managed by the VM, so the request is sent from a short-lived thread.
+        let sent = thread::scope(|scope| {
+            scope.spawn(|| {
+                OwnedEnv::new().send_and_clear(&self.pid, |env| {
+                    (atoms::cnft_sign_request(), request.clone(), binary(env, message), self.pubkey.to_string())
+                })
+            }).join()
+        });
+
+        if !matches!(sent, Ok(Ok(()))) {
+            return Err(SignerError::Connection("external signer process is not alive".to_string()));
+        }
+
+        request.signature(&self.pubkey, message, self.timeout)
+    }
+
+    fn is_interactive(&self) -> bool {
+        true
+    }
+}
+
+
+/// Copies `bytes` into an Elixir binary.
+fn binary<'a>(env: Env<'a>, bytes: &[u8]) -> Term<'a> {
+    let mut owned = OwnedBinary::new(bytes.len()).expect("failed to allocate binary");
+    owned.as_mut_slice().copy_from_slice(bytes);
+    owned.release(env).encode(env)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use solana_sdk::{signature::{write_keypair_file, Keypair, Signature}, signer::{Signer, SignerError}};
+
+    use crate::error::CnftError;
+
+    use super::{SignRequest, SignerSpec};
+
+
+    const MESSAGE: &[u8] = b"message to sign";
+
+
+    /// Waits for the answer to a request which `answer` gives from another thread, as an Elixir signer would.
+    fn handshake(keypair: &Keypair, answer: impl FnOnce(&SignRequest) + Send + 'static) -> Result<Signature, SignerError> {
+        let request = Arc::new(SignRequest::new());
+        let answering = request.clone();
+        let signer = thread::spawn(move || answer(&answering));
+
+        let signature = request.signature(&keypair.pubkey(), MESSAGE, Duration::from_secs(5));
+        signer.join().unwrap();
+        signature
+    }
+
+
+    #[test]
+    fn answered_signature_is_returned() {
+        let keypair = Keypair::new();
+        let expected = keypair.sign_message(MESSAGE);
+
+        let signature = handshake(&keypair, move |request| request.answer(Ok(expected.as_ref().to_vec())));
+
+        assert_eq!(signature.unwrap(), expected);
+    }
+
+
+    #[test]
+    fn rejected_request_is_a_user_cancel() {
+        let signature = handshake(&Keypair::new(), |request| request.answer(Err("declined".to_string())));
+
+        assert_eq!(signature, Err(SignerError::UserCancel("declined".to_string())));
+    }
+
+
+    #[test]
+    fn malformed_or_foreign_signatures_are_refused() {
+        let keypair = Keypair::new();
+        let foreign = Keypair::new().sign_message(MESSAGE);
+
+        let malformed = handshake(&keypair, |request| request.answer(Ok(vec![1; 10])));
+        let invalid = handshake(&keypair, move |request| request.answer(Ok(foreign.as_ref().to_vec())));
+
+        assert!(matches!(malformed, Err(SignerError::Custom(message)) if message.contains("malformed")));
+        assert!(matches!(invalid, Err(SignerError::Custom(message)) if message.contains("invalid")));
+    }
+
+
+    #[test]
+    fn unanswered_request_times_out() {
+        let request = SignRequest::new();
+
+        let signature = request.signature(&Keypair::new().pubkey(), MESSAGE, Duration::from_millis(10));
+
+        assert!(matches!(signature, Err(SignerError::Connection(message)) if message.contains("timed out")));
+    }
+
+
+    #[test]
+    fn keys_and_keypair_files_resolve_to_their_signer() {
+        let keypair = Keypair::new();
+        let path = std::env::temp_dir().join(format!("cnft-signer-{}.json", keypair.pubkey()));
+        write_keypair_file(&keypair, &path).unwrap();
+
+        let from_file = SignerSpec::KeypairFile(path.to_string_lossy().into_owned()).resolve("owner");
+        let from_base58 = SignerSpec::Base58(keypair.to_base58_string()).resolve("owner");
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(from_file.unwrap().pubkey(), keypair.pubkey());
+        assert_eq!(from_base58.unwrap().pubkey(), keypair.pubkey());
+        assert!(matches!(SignerSpec::Base58("not a key".to_string()).resolve("owner"), Err(CnftError::InvalidKey { field }) if field == "owner"));
+        assert!(matches!(SignerSpec::KeypairFile("/no/such/file".to_string()).resolve("owner"), Err(CnftError::InvalidArgument { field, .. }) if field == "owner"));
+    }
+}
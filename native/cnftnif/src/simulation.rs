@@ -0,0 +1,250 @@
+//! This module implements dry runs, building and signing the transaction of an operation and simulating it
+//! instead of sending it, so nothing is paid and the TreeManager is left unchanged
+
+
+
+use rustler::{types::{atom::Atom, map::map_new}, Encoder, Env, Term};
+use solana_sdk::{instruction::Instruction, signer::Signer};
+
+use crate::backend::backend;
+use crate::batch::BatchTransfer;
+use crate::error::CnftError;
+use crate::hash::Metadata;
+use crate::pipeline::PipelineOperation;
+use crate::proof_source::ProofSource;
+use crate::setup::{signed_transaction, TreeManager};
+use crate::utils::parse_pubkey;
+
+
+mod atoms {
+    rustler::atoms! {
+        units_consumed,
+        logs,
+        error
+    }
+}
+
+
+const MODULE: &str = "Elixir.CnftNif.SimulationResult";
+
+
+
+/// What a simulated transaction would do, encoded as `%CnftNif.SimulationResult{}`.
+#[derive(Clone, Debug)]
+pub struct SimulationResult {
+    /// Compute units the transaction consumed. The `:simulated` backend estimates them, see `simulator`.
+    pub units_consumed: Option<u64>,
+
+    /// Logs of the programs the transaction invoked.
+    pub logs: Vec<String>,
+
+    /// The decoded error the transaction would fail with, `nil` if it would succeed.
+    pub error: Option<CnftError>
+}
+
+impl Encoder for SimulationResult {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let module = Atom::from_str(env, MODULE).expect("valid module name");
+
+        map_new(env)
+            .map_put(rustler::types::atom::__struct__(), module).expect("a map")
+            .map_put(atoms::units_consumed(), self.units_consumed).expect("a map")
+            .map_put(atoms::logs(), &self.logs).expect("a map")
+            .map_put(atoms::error(), &self.error).expect("a map")
+    }
+}
+
+
+
+/// Simulation of every entry of a batch, in order. An entry fails on its own if its transaction cannot be built.
+pub type BatchSimulations = Vec<Result<SimulationResult, CnftError>>;
+
+
+
+/// Outcome of an operation which may be a dry run: whatever the operation returns, or the simulation.
+pub enum Outcome<T, S = SimulationResult> {
+    Applied(T),
+    Simulated(S)
+}
+
+impl<T: Encoder, S: Encoder> Encoder for Outcome<T, S> {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            Outcome::Applied(result) => result.encode(env),
+            Outcome::Simulated(simulation) => simulation.encode(env)
+        }
+    }
+}
+
+
+
+impl TreeManager {
+
+    /// Simulates `create_tree`. The keypair of the tree is kept, so the tree can still be created with it.
+    pub fn simulate_create_tree(&self, tree_owner: &dyn Signer) -> Result<SimulationResult, CnftError> {
+        let (tree_account, instructions) = self.create_tree_instructions(&tree_owner.pubkey())?;
+
+        simulate(&instructions, &[tree_owner, &tree_account])
+    }
+
+
+    /// Simulates `mint_cnft`.
+    pub fn simulate_mint_cnft(&self, tree_owner: &dyn Signer, nft_owner: &str) -> Result<SimulationResult, CnftError> {
+        let nft_owner = parse_pubkey(nft_owner, "nft_owner")?;
+
+        let (mint_ix, _) = self.mint_instruction(&tree_owner.pubkey(), &nft_owner)?;
+
+        simulate(&[mint_ix], &[tree_owner])
+    }
+
+
+    /// Simulates `transfer_cnft`.
+    pub fn simulate_transfer_cnft(
+        &self,
+        tree_owner: &dyn Signer,
+        old_owner: &dyn Signer,
+        new_owner_pub_key: &str,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str
+    ) -> Result<SimulationResult, CnftError> {
+        let new_owner = parse_pubkey(new_owner_pub_key, "new_owner_pub_key")?;
+
+        let (transfer_ix, _) = self.transfer_instruction(&old_owner.pubkey(), &new_owner, index, data_hash, creator_hash)?;
+
+        simulate(&[transfer_ix], &[tree_owner, old_owner])
+    }
+
+
+    /// Simulates `transfer_cnft_with_sources`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn simulate_transfer_cnft_with_sources(
+        &self,
+        tree_owner: &dyn Signer,
+        old_owner: &dyn Signer,
+        new_owner_pub_key: &str,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str,
+        sources: &[ProofSource]
+    ) -> Result<SimulationResult, CnftError> {
+        let new_owner = parse_pubkey(new_owner_pub_key, "new_owner_pub_key")?;
+
+        let (transfer_ix, _) = self.transfer_instruction_with_sources(&old_owner.pubkey(), &new_owner, index, data_hash, creator_hash, sources)?;
+
+        simulate(&[transfer_ix], &[tree_owner, old_owner])
+    }
+
+
+    /// Simulates `burn_cnft`.
+    pub fn simulate_burn_cnft(
+        &self,
+        tree_owner: &dyn Signer,
+        owner: &dyn Signer,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str
+    ) -> Result<SimulationResult, CnftError> {
+        let burn_ix = self.burn_instruction(&owner.pubkey(), index, data_hash, creator_hash)?;
+
+        simulate(&[burn_ix], &[tree_owner, owner])
+    }
+
+
+    /// Simulates `delegate_cnft`.
+    pub fn simulate_delegate_cnft(
+        &self,
+        tree_owner: &dyn Signer,
+        owner: &dyn Signer,
+        new_delegate_pub_key: &str,
+        index: usize,
+        data_hash: &str,
+        creator_hash: &str
+    ) -> Result<SimulationResult, CnftError> {
+        let new_delegate = parse_pubkey(new_delegate_pub_key, "new_delegate_pub_key")?;
+
+        let (delegate_ix, _) = self.delegate_instruction(&owner.pubkey(), &new_delegate, index, data_hash, creator_hash)?;
+
+        simulate(&[delegate_ix], &[tree_owner, owner])
+    }
+
+
+    /// Simulates `mint_batch`, every entry in a transaction of its own.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CnftError::TreeFull` if the tree cannot hold every entry.
+    pub fn simulate_mint_batch(&self, tree_owner: &dyn Signer, entries: &[(String, Metadata)]) -> Result<BatchSimulations, CnftError> {
+        if entries.len() > self.remaining_capacity() {
+            return Err(CnftError::TreeFull { capacity: self.capacity() });
+        }
+
+        Ok(entries.iter()
+            .map(|(recipient, metadata)| {
+                let mint_ix = self.batch_mint_instruction(&tree_owner.pubkey(), recipient, metadata)?;
+                simulate(&[mint_ix], &[tree_owner])
+            })
+            .collect())
+    }
+
+
+    /// Simulates `transfer_batch`, every transfer in a transaction of its own proving its leaf against the
+    /// current tree, as if it were the only one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the local tree cannot be rebuilt from the `nodes`.
+    pub fn simulate_transfer_batch(&self, tree_owner: &dyn Signer, transfers: &[BatchTransfer]) -> Result<BatchSimulations, CnftError> {
+        let snapshot = self.off_chain_merkle_tree()?;
+
+        Ok(transfers.iter()
+            .map(|transfer| {
+                let (transfer_ix, _) = self.batch_transfer_instruction(&snapshot, &tree_owner.pubkey(), transfer)?;
+                simulate(&[transfer_ix], &signers(tree_owner, transfer.old_owner))
+            })
+            .collect())
+    }
+
+
+    /// Simulates `run_pipeline`, every operation in a transaction of its own proving its leaf against the
+    /// current tree, as if it were the only one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the local tree cannot be rebuilt from the `nodes`.
+    pub fn simulate_pipeline(&self, tree_owner: &dyn Signer, operations: &[PipelineOperation]) -> Result<BatchSimulations, CnftError> {
+        let snapshot = self.off_chain_merkle_tree()?;
+
+        Ok(operations.iter()
+            .map(|operation| {
+                let (instruction, _) = self.pipeline_instruction(&snapshot, operation)?;
+                simulate(&[instruction], &signers(tree_owner, operation.owner()))
+            })
+            .collect())
+    }
+}
+
+
+
+
+/// Signs `instructions` with `signers`, the first of which pays the fees, and simulates the transaction.
+///
+/// # Errors
+///
+/// Returns an error if the transaction cannot be signed or simulated. An error the transaction itself
+/// would fail with is returned in the `SimulationResult`.
+pub(crate) fn simulate(instructions: &[Instruction], signers: &[&dyn Signer]) -> Result<SimulationResult, CnftError> {
+    let transaction = signed_transaction(instructions, &signers[0].pubkey(), signers)?;
+
+    backend().simulate(&transaction)
+}
+
+
+/// The tree owner paying the fees, followed by `owner` unless it is the tree owner itself.
+fn signers<'a>(tree_owner: &'a dyn Signer, owner: &'a dyn Signer) -> Vec<&'a dyn Signer> {
+    if owner.pubkey() == tree_owner.pubkey() {
+        vec![tree_owner]
+    } else {
+        vec![tree_owner, owner]
+    }
+}
@@ -0,0 +1,959 @@
+//! This module implements an in-memory Solana, used as a chain backend without any network access
+//!
+//! The simulator executes the instructions the TreeManager sends:
+//! - System program `CreateAccount`.
+//! - Account Compression `VerifyLeaf`.
+//! - Bubblegum `CreateTreeConfig`, `MintV1`, `Transfer`, `Burn` and `Delegate`, applied to the concurrent merkle tree
+//!   stored in the tree account data, exactly as the Account Compression program lays it out.
+//!
+//! Transactions are atomic, signatures and blockhashes are checked, and failures are reported with the same
+//! `TransactionError` and program error codes the real programs return. Changelog and Bubblegum leaf schema events
+//! are logged through the noop program and processed transactions are kept, as on a real chain. Program invocations,
+//! CPIs included, and Anchor errors are logged as the runtime and the programs log them. Lamport balances are not tracked.
+//!
+//! Programs are emulated natively rather than executed, so compute units are not metered. A simulation estimates
+//! them instead, charging `UNITS_PER_INVOCATION` for every program invocation, CPIs included.
+
+
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::thread;
+
+use anchor_lang::{AnchorDeserialize, AnchorSerialize, Discriminator};
+use mpl_bubblegum::{accounts::TreeConfig, errors::{MplBubblegumError, SplAccountCompressionError}, hash::{hash_creators, hash_metadata}, instructions::{BurnInstructionArgs, CreateTreeConfigInstructionArgs, DelegateInstructionArgs, MintV1InstructionArgs, TransferInstructionArgs}, types::{DecompressibleState, LeafSchema, Version}, utils::get_asset_id, InstructionName, LeafSchemaEvent};
+use num_traits::FromPrimitive;
+use solana_sdk::{hash::{hashv, Hash}, instruction::InstructionError, program_error::ProgramError, pubkey::Pubkey, rent::Rent, signature::Signature, system_instruction::{SystemError, SystemInstruction}, transaction::{Transaction, TransactionError}};
+use spl_account_compression::{events::{AccountCompressionEvent, ApplicationDataEvent, ApplicationDataEventV1}, concurrent_tree_wrapper::{merkle_tree_append_leaf, merkle_tree_initialize_empty, merkle_tree_prove_leaf, merkle_tree_set_leaf, tree_bytes_uninitialized, ProveLeafArgs, SetLeafArgs}, instruction::VerifyLeaf, state::{merkle_tree_get_size, ConcurrentMerkleTreeHeader, CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1}, AccountCompressionError, EMPTY};
+
+use crate::backend::{ChainBackend, ConfirmedTransaction, SignatureStatus};
+use crate::error::CnftError;
+use crate::simulation::SimulationResult;
+
+
+/// Number of blockhashes a transaction may reference, as on mainnet.
+const MAX_RECENT_BLOCKHASHES: usize = 150;
+
+/// Compute units a simulation charges for every program invocation, about what a Bubblegum instruction and its
+/// CPIs consume on mainnet.
+const UNITS_PER_INVOCATION: u64 = 15_000;
+
+/// Stack size of the thread executing a transaction. The account compression functions match over every supported
+/// tree size, which needs far more stack than a scheduler thread has, notably in debug builds.
+const EXECUTION_STACK_SIZE: usize = 64 * 1024 * 1024;
+
+
+
+/// An account of the simulated chain.
+#[derive(Clone)]
+struct SimulatedAccount {
+    owner: Pubkey,
+    data: Vec<u8>
+}
+
+
+type Accounts = HashMap<Pubkey, SimulatedAccount>;
+
+
+/// State of the simulated chain.
+struct SimulatedState {
+    accounts: Accounts,
+    recent_blockhashes: VecDeque<Hash>,
+    transactions: HashMap<Signature, ConfirmedTransaction>,
+    /// Every processed transaction with the accounts it referenced, oldest first.
+    history: Vec<(Signature, Vec<Pubkey>)>,
+    slot: u64
+}
+
+
+/// Outcome of executing a transaction, before it is committed.
+struct Execution {
+    accounts: Accounts,
+    noop_data: Vec<Vec<u8>>
+}
+
+
+
+/// In-memory Solana backend.
+pub struct SimulatedBackend {
+    state: Mutex<SimulatedState>
+}
+
+impl Default for SimulatedBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimulatedBackend {
+
+    /// Creates an empty chain.
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(SimulatedState {
+                accounts: HashMap::new(),
+                recent_blockhashes: VecDeque::from([Hash::new_unique()]),
+                transactions: HashMap::new(),
+                history: Vec::new(),
+                slot: 0
+            })
+        }
+    }
+
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SimulatedState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl ChainBackend for SimulatedBackend {
+    fn minimum_balance_for_rent_exemption(&self, data_len: usize) -> Result<u64, CnftError> {
+        Ok(Rent::default().minimum_balance(data_len))
+    }
+
+    fn latest_blockhash(&self) -> Result<Hash, CnftError> {
+        let state = self.lock();
+        Ok(*state.recent_blockhashes.back().expect("at least one blockhash"))
+    }
+
+    fn send_and_confirm(&self, transaction: &Transaction) -> Result<(Signature, u64), CnftError> {
+        let mut guard = self.lock();
+        let state: &mut SimulatedState = &mut guard;
+
+        let mut logs = Vec::new();
+
+        on_execution_thread(|| state.execute(transaction, &mut logs))?
+            .map_err(|e| CnftError::from_transaction_error(&e, Some(transaction), &logs))
+    }
+
+    fn send(&self, transaction: &Transaction) -> Result<Signature, CnftError> {
+        // Transactions are processed as they arrive, failures are reported like a preflight check would.
+        self.send_and_confirm(transaction).map(|(signature, _)| signature)
+    }
+
+    fn is_blockhash_valid(&self, blockhash: &Hash) -> Result<bool, CnftError> {
+        Ok(self.lock().recent_blockhashes.contains(blockhash))
+    }
+
+    fn signature_statuses(&self, signatures: &[Signature]) -> Result<Vec<SignatureStatus>, CnftError> {
+        let state = self.lock();
+
+        // Failed transactions are not committed, only successful ones are known.
+        Ok(signatures.iter()
+            .map(|signature| state.transactions.get(signature).map(|transaction| (transaction.slot, None)))
+            .collect())
+    }
+
+    fn simulate(&self, transaction: &Transaction) -> Result<SimulationResult, CnftError> {
+        let guard = self.lock();
+        let state: &SimulatedState = &guard;
+
+        let mut logs = Vec::new();
+        let error = on_execution_thread(|| state.run(transaction, &mut logs))?.err();
+
+        Ok(SimulationResult {
+            units_consumed: Some(estimated_units(&logs)),
+            error: error.map(|e| CnftError::from_transaction_error(&e, Some(transaction), &logs)),
+            logs
+        })
+    }
+
+    fn account_data(&self, pubkey: &Pubkey) -> Result<Option<Vec<u8>>, CnftError> {
+        Ok(self.lock().accounts.get(pubkey).map(|account| account.data.clone()))
+    }
+
+    fn signatures_for_address(&self, address: &Pubkey) -> Result<Vec<Signature>, CnftError> {
+        Ok(self.lock().history
+            .iter()
+            .filter(|(_, account_keys)| account_keys.contains(address))
+            .map(|(signature, _)| *signature)
+            .collect())
+    }
+
+    fn confirmed_transaction(&self, signature: &Signature) -> Result<Option<ConfirmedTransaction>, CnftError> {
+        Ok(self.lock().transactions.get(signature).cloned())
+    }
+}
+
+
+
+impl SimulatedState {
+
+    /// Checks and executes a transaction, committing its account changes only if every instruction succeeds.
+    /// The messages the programs log are added to `logs`, failed or not.
+    fn execute(&mut self, transaction: &Transaction, logs: &mut Vec<String>) -> Result<(Signature, u64), TransactionError> {
+        transaction.verify()?;
+
+        let signature = transaction.signatures[0];
+        if self.transactions.contains_key(&signature) {
+            return Err(TransactionError::AlreadyProcessed);
+        }
+
+        let execution = self.run(transaction, logs)?;
+
+        self.accounts.extend(execution.accounts);
+        self.transactions.insert(signature, ConfirmedTransaction { slot: self.slot, noop_data: execution.noop_data, logs: logs.clone() });
+        self.history.push((signature, transaction.message.account_keys.clone()));
+        let slot = self.slot;
+        self.advance();
+
+        Ok((signature, slot))
+    }
+
+
+    /// Executes the instructions of a transaction without committing anything, returning the changed accounts
+    /// and the events logged through the noop program. The messages the programs log are added to `logs`.
+    /// Signatures are not checked, as with the default `simulateTransaction` of an RPC node.
+    fn run(&self, transaction: &Transaction, logs: &mut Vec<String>) -> Result<Execution, TransactionError> {
+        if !self.recent_blockhashes.contains(&transaction.message.recent_blockhash) {
+            return Err(TransactionError::BlockhashNotFound);
+        }
+
+        let message = &transaction.message;
+        let mut staged: Accounts = message.account_keys
+            .iter()
+            .filter_map(|key| self.accounts.get(key).map(|account| (*key, account.clone())))
+            .collect();
+        let mut noop_data = Vec::new();
+
+        for (index, compiled) in message.instructions.iter().enumerate() {
+            let program_id = message.account_keys[compiled.program_id_index as usize];
+            let context = InstructionContext {
+                accounts: compiled.accounts
+                    .iter()
+                    .map(|i| (message.account_keys[*i as usize], message.is_signer(*i as usize)))
+                    .collect(),
+                data: &compiled.data,
+                slot: self.slot,
+                noop_data: RefCell::new(Vec::new()),
+                logs: RefCell::new(Vec::new()),
+                cpi_failed: Cell::new(false)
+            };
+
+            logs.push(format!("Program {program_id} invoke [1]"));
+            let result = context.process(&program_id, &mut staged);
+            logs.extend(context.logs.take());
+
+            if let Err(e) = result {
+                // A failed CPI already logged why, the error is only passed on.
+                if context.cpi_failed.get() {
+                    logs.push(format!("Program {program_id} failed: {e}"));
+                } else {
+                    logs.extend(failure_logs(&program_id, &e));
+                }
+                return Err(TransactionError::InstructionError(index as u8, e));
+            }
+
+            logs.push(format!("Program {program_id} success"));
+            noop_data.extend(context.noop_data.into_inner());
+        }
+
+        Ok(Execution { accounts: staged, noop_data })
+    }
+
+
+    /// Moves to the next slot with a fresh blockhash.
+    fn advance(&mut self) {
+        self.slot += 1;
+        self.recent_blockhashes.push_back(Hash::new_unique());
+        if self.recent_blockhashes.len() > MAX_RECENT_BLOCKHASHES {
+            self.recent_blockhashes.pop_front();
+        }
+    }
+}
+
+
+
+/// A single instruction being executed: its accounts, whether each one signed, and its data.
+struct InstructionContext<'a> {
+    accounts: Vec<(Pubkey, bool)>,
+    data: &'a [u8],
+    slot: u64,
+    /// Events logged through the noop program so far.
+    noop_data: RefCell<Vec<Vec<u8>>>,
+    /// Messages logged by the CPIs made so far.
+    logs: RefCell<Vec<String>>,
+    /// Whether a CPI failed, in which case its failure is already logged.
+    cpi_failed: Cell<bool>
+}
+
+impl InstructionContext<'_> {
+
+    fn process(&self, program_id: &Pubkey, accounts: &mut Accounts) -> Result<(), InstructionError> {
+        if *program_id == solana_sdk::system_program::ID {
+            self.process_system(accounts)
+        } else if *program_id == mpl_bubblegum::ID {
+            self.process_bubblegum(accounts)
+        } else if *program_id == spl_account_compression::ID {
+            self.process_compression(accounts)
+        } else {
+            Err(InstructionError::UnsupportedProgramId)
+        }
+    }
+
+
+    /// Runs `f` as a CPI into `program_id`, logging it as the runtime does.
+    fn invoke<T>(&self, program_id: &Pubkey, f: impl FnOnce() -> Result<T, InstructionError>) -> Result<T, InstructionError> {
+        self.logs.borrow_mut().push(format!("Program {program_id} invoke [2]"));
+
+        let result = f();
+        match &result {
+            Ok(_) => self.logs.borrow_mut().push(format!("Program {program_id} success")),
+            Err(e) => {
+                self.logs.borrow_mut().extend(failure_logs(program_id, e));
+                self.cpi_failed.set(true);
+            }
+        }
+
+        result
+    }
+
+
+    fn key(&self, index: usize) -> Result<Pubkey, InstructionError> {
+        self.accounts.get(index).map(|(key, _)| *key).ok_or(InstructionError::NotEnoughAccountKeys)
+    }
+
+
+    fn is_signer(&self, index: usize) -> bool {
+        self.accounts.get(index).is_some_and(|(_, signer)| *signer)
+    }
+
+
+    /// Logs an Account Compression event through the noop program, as the programs do with a CPI.
+    fn emit(&self, event: AccountCompressionEvent) -> Result<(), InstructionError> {
+        let data = event.try_to_vec().map_err(|_| InstructionError::InvalidAccountData)?;
+        self.noop_data.borrow_mut().push(data);
+        Ok(())
+    }
+
+
+    /// Logs the leaf schema event Bubblegum wraps into Account Compression application data before writing a leaf.
+    fn emit_leaf(&self, leaf: &LeafSchema) -> Result<(), InstructionError> {
+        let application_data = LeafSchemaEvent::new(Version::V1, leaf.clone(), leaf.hash())
+            .try_to_vec()
+            .map_err(|_| InstructionError::InvalidAccountData)?;
+
+        self.emit(AccountCompressionEvent::ApplicationData(ApplicationDataEvent::V1(ApplicationDataEventV1 { application_data })))
+    }
+
+
+    /// Keys of the accounts from `start` onwards, as proof nodes.
+    fn proof(&self, start: usize) -> Vec<[u8; 32]> {
+        self.accounts.iter().skip(start).map(|(key, _)| key.to_bytes()).collect()
+    }
+
+
+    fn process_system(&self, accounts: &mut Accounts) -> Result<(), InstructionError> {
+        let instruction: SystemInstruction = bincode::deserialize(self.data)
+            .map_err(|_| InstructionError::InvalidInstructionData)?;
+
+        match instruction {
+            SystemInstruction::CreateAccount { space, owner, .. } => {
+                let new_account = self.key(1)?;
+                if !self.is_signer(0) || !self.is_signer(1) {
+                    return Err(InstructionError::MissingRequiredSignature);
+                }
+                if accounts.contains_key(&new_account) {
+                    return Err(InstructionError::Custom(SystemError::AccountAlreadyInUse as u32));
+                }
+
+                accounts.insert(new_account, SimulatedAccount { owner, data: vec![0; space as usize] });
+                Ok(())
+            },
+            _ => Err(InstructionError::InvalidInstructionData)
+        }
+    }
+
+
+    fn process_bubblegum(&self, accounts: &mut Accounts) -> Result<(), InstructionError> {
+        if self.data.len() < 8 {
+            return Err(InstructionError::InvalidInstructionData);
+        }
+        let mut args = &self.data[8..];
+
+        match mpl_bubblegum::get_instruction_type(self.data) {
+            InstructionName::CreateTree => self.create_tree(accounts, &deserialize(&mut args)?),
+            InstructionName::MintV1 => self.mint_v1(accounts, &deserialize(&mut args)?),
+            InstructionName::Transfer => self.transfer(accounts, &deserialize(&mut args)?),
+            InstructionName::Burn => self.burn(accounts, &deserialize(&mut args)?),
+            InstructionName::Delegate => self.delegate(accounts, &deserialize(&mut args)?),
+            _ => Err(InstructionError::InvalidInstructionData)
+        }
+    }
+
+
+    /// Only `VerifyLeaf` may be called directly, every other instruction needs the tree authority.
+    fn process_compression(&self, accounts: &mut Accounts) -> Result<(), InstructionError> {
+        if self.data.len() < 8 || self.data[..8] != VerifyLeaf::DISCRIMINATOR {
+            return Err(InstructionError::InvalidInstructionData);
+        }
+        let args: VerifyLeaf = deserialize(&mut &self.data[8..])?;
+
+        let merkle_tree = self.key(0)?;
+        let prove = ProveLeafArgs {
+            current_root: args.root,
+            leaf: args.leaf,
+            proof_vec: self.proof(1),
+            index: args.index
+        };
+
+        with_tree(accounts, &merkle_tree, |header, tree_bytes| {
+            header.assert_valid_leaf_index(prove.index)?;
+            merkle_tree_prove_leaf(header, merkle_tree, tree_bytes, &prove).map(|_| ())
+        })
+    }
+
+
+    /// Accounts: tree_config, merkle_tree, payer, tree_creator, ...
+    fn create_tree(&self, accounts: &mut Accounts, args: &CreateTreeConfigInstructionArgs) -> Result<(), InstructionError> {
+        let (tree_config, merkle_tree, tree_creator) = (self.key(0)?, self.key(1)?, self.key(3)?);
+        if !self.is_signer(2) || !self.is_signer(3) {
+            return Err(InstructionError::MissingRequiredSignature);
+        }
+        if TreeConfig::find_pda(&merkle_tree).0 != tree_config {
+            return Err(InstructionError::InvalidSeeds);
+        }
+        self.invoke(&solana_sdk::system_program::ID, || {
+            if accounts.contains_key(&tree_config) {
+                return Err(InstructionError::Custom(SystemError::AccountAlreadyInUse as u32));
+            }
+            Ok(())
+        })?;
+
+        let tree_account = accounts.get_mut(&merkle_tree).ok_or(InstructionError::UninitializedAccount)?;
+        if tree_account.owner != spl_account_compression::ID {
+            return Err(bubblegum_error(MplBubblegumError::IncorrectOwner));
+        }
+
+        self.invoke(&spl_account_compression::ID, || {
+            let (mut header_bytes, rest) = split_header(&mut tree_account.data)?;
+            let mut header = ConcurrentMerkleTreeHeader::deserialize(&mut &*header_bytes)
+                .map_err(|_| InstructionError::InvalidAccountData)?;
+            if header.get_max_depth() != 0 {
+                return Err(compression_error(AccountCompressionError::TreeAlreadyInitialized));
+            }
+
+            header.initialize(args.max_depth, args.max_buffer_size, &tree_config, self.slot);
+            let size = merkle_tree_get_size(&header).map_err(compression_error)?;
+            if rest.len() < size {
+                return Err(InstructionError::AccountDataTooSmall);
+            }
+            if !tree_bytes_uninitialized(&rest[..size]) {
+                return Err(compression_error(AccountCompressionError::TreeAlreadyInitialized));
+            }
+
+            header.serialize(&mut header_bytes).map_err(|_| InstructionError::AccountDataTooSmall)?;
+            let event = merkle_tree_initialize_empty(&header, merkle_tree, &mut rest[..size]).map_err(compression_error)?;
+            self.emit(AccountCompressionEvent::ChangeLog(*event))
+        })?;
+
+        let config = TreeConfig {
+            discriminator: account_discriminator("TreeConfig"),
+            tree_creator,
+            tree_delegate: tree_creator,
+            total_mint_capacity: 1 << args.max_depth,
+            num_minted: 0,
+            is_public: args.public.unwrap_or(false),
+            is_decompressible: DecompressibleState::Disabled
+        };
+        let mut data = config.try_to_vec().map_err(|_| InstructionError::InvalidAccountData)?;
+        data.resize(TreeConfig::LEN, 0);
+
+        accounts.insert(tree_config, SimulatedAccount { owner: mpl_bubblegum::ID, data });
+        Ok(())
+    }
+
+
+    /// Accounts: tree_config, leaf_owner, leaf_delegate, merkle_tree, payer, tree_creator_or_delegate, ...
+    fn mint_v1(&self, accounts: &mut Accounts, args: &MintV1InstructionArgs) -> Result<(), InstructionError> {
+        let (leaf_owner, leaf_delegate, merkle_tree, authority) = (self.key(1)?, self.key(2)?, self.key(3)?, self.key(5)?);
+        if !self.is_signer(4) {
+            return Err(InstructionError::MissingRequiredSignature);
+        }
+
+        let mut config = load_tree_config(accounts, &self.key(0)?, &merkle_tree)?;
+        if !config.is_public && (!self.is_signer(5) || (authority != config.tree_creator && authority != config.tree_delegate)) {
+            return Err(bubblegum_error(MplBubblegumError::TreeAuthorityIncorrect));
+        }
+        if config.num_minted >= config.total_mint_capacity {
+            return Err(bubblegum_error(MplBubblegumError::InsufficientMintCapacity));
+        }
+
+        let metadata = &args.metadata;
+        let unsigned_creator = metadata.creators
+            .iter()
+            .any(|creator| creator.verified && !self.accounts.iter().any(|(key, signer)| *signer && *key == creator.address));
+        if unsigned_creator {
+            return Err(bubblegum_error(MplBubblegumError::CreatorDidNotVerify));
+        }
+
+        let leaf = LeafSchema::V1 {
+            id: get_asset_id(&merkle_tree, config.num_minted),
+            owner: leaf_owner,
+            delegate: leaf_delegate,
+            nonce: config.num_minted,
+            data_hash: hash_metadata(metadata).map_err(|_| InstructionError::InvalidInstructionData)?,
+            creator_hash: hash_creators(&metadata.creators)
+        };
+
+        self.emit_leaf(&leaf)?;
+        self.invoke(&spl_account_compression::ID, || {
+            let event = with_tree(accounts, &merkle_tree, |header, tree_bytes| {
+                merkle_tree_append_leaf(header, merkle_tree, tree_bytes, &leaf.hash())
+            })?;
+            self.emit(AccountCompressionEvent::ChangeLog(*event))
+        })?;
+
+        config.num_minted += 1;
+        store_tree_config(accounts, &self.key(0)?, &config)
+    }
+
+
+    /// Accounts: tree_config, leaf_owner, leaf_delegate, new_leaf_owner, merkle_tree, log_wrapper,
+    /// compression_program, system_program, proof...
+    fn transfer(&self, accounts: &mut Accounts, args: &TransferInstructionArgs) -> Result<(), InstructionError> {
+        let (owner, delegate, new_owner, merkle_tree) = (self.key(1)?, self.key(2)?, self.key(3)?, self.key(4)?);
+        if !self.is_signer(1) && !self.is_signer(2) {
+            return Err(bubblegum_error(MplBubblegumError::LeafAuthorityMustSign));
+        }
+        load_tree_config(accounts, &self.key(0)?, &merkle_tree)?;
+
+        let previous = leaf_schema(&merkle_tree, owner, delegate, args.nonce, args.data_hash, args.creator_hash);
+        let new = leaf_schema(&merkle_tree, new_owner, new_owner, args.nonce, args.data_hash, args.creator_hash);
+
+        self.emit_leaf(&new)?;
+        self.replace_leaf(accounts, &merkle_tree, args.root, previous.hash(), new.hash(), args.index, 8)
+    }
+
+
+    /// Accounts: tree_config, leaf_owner, leaf_delegate, merkle_tree, log_wrapper, compression_program,
+    /// system_program, proof...
+    fn burn(&self, accounts: &mut Accounts, args: &BurnInstructionArgs) -> Result<(), InstructionError> {
+        let (owner, delegate, merkle_tree) = (self.key(1)?, self.key(2)?, self.key(3)?);
+        if !self.is_signer(1) && !self.is_signer(2) {
+            return Err(bubblegum_error(MplBubblegumError::LeafAuthorityMustSign));
+        }
+        load_tree_config(accounts, &self.key(0)?, &merkle_tree)?;
+
+        let previous = leaf_schema(&merkle_tree, owner, delegate, args.nonce, args.data_hash, args.creator_hash);
+
+        self.replace_leaf(accounts, &merkle_tree, args.root, previous.hash(), EMPTY, args.index, 7)
+    }
+
+
+    /// Accounts: tree_config, leaf_owner, previous_leaf_delegate, new_leaf_delegate, merkle_tree, log_wrapper,
+    /// compression_program, system_program, proof...
+    fn delegate(&self, accounts: &mut Accounts, args: &DelegateInstructionArgs) -> Result<(), InstructionError> {
+        let (owner, previous_delegate, new_delegate, merkle_tree) = (self.key(1)?, self.key(2)?, self.key(3)?, self.key(4)?);
+        if !self.is_signer(1) {
+            return Err(InstructionError::MissingRequiredSignature);
+        }
+        load_tree_config(accounts, &self.key(0)?, &merkle_tree)?;
+
+        let previous = leaf_schema(&merkle_tree, owner, previous_delegate, args.nonce, args.data_hash, args.creator_hash);
+        let new = leaf_schema(&merkle_tree, owner, new_delegate, args.nonce, args.data_hash, args.creator_hash);
+
+        self.emit_leaf(&new)?;
+        self.replace_leaf(accounts, &merkle_tree, args.root, previous.hash(), new.hash(), args.index, 8)
+    }
+
+
+    /// Replaces `previous_leaf` with `new_leaf`, proven by the accounts from `proof_start` onwards.
+    #[allow(clippy::too_many_arguments)]
+    fn replace_leaf(
+        &self,
+        accounts: &mut Accounts,
+        merkle_tree: &Pubkey,
+        root: [u8; 32],
+        previous_leaf: [u8; 32],
+        new_leaf: [u8; 32],
+        index: u32,
+        proof_start: usize
+    ) -> Result<(), InstructionError> {
+        let args = SetLeafArgs {
+            current_root: root,
+            previous_leaf,
+            new_leaf,
+            proof_vec: self.proof(proof_start),
+            index
+        };
+
+        self.invoke(&spl_account_compression::ID, || {
+            let event = with_tree(accounts, merkle_tree, |header, tree_bytes| {
+                merkle_tree_set_leaf(header, *merkle_tree, tree_bytes, &args)
+            })?;
+            self.emit(AccountCompressionEvent::ChangeLog(*event))
+        })
+    }
+}
+
+
+
+fn leaf_schema(merkle_tree: &Pubkey, owner: Pubkey, delegate: Pubkey, nonce: u64, data_hash: [u8; 32], creator_hash: [u8; 32]) -> LeafSchema {
+    LeafSchema::V1 {
+        id: get_asset_id(merkle_tree, nonce),
+        owner,
+        delegate,
+        nonce,
+        data_hash,
+        creator_hash
+    }
+}
+
+
+fn deserialize<T: AnchorDeserialize>(data: &mut &[u8]) -> Result<T, InstructionError> {
+    T::deserialize(data).map_err(|_| InstructionError::InvalidInstructionData)
+}
+
+
+fn split_header(data: &mut [u8]) -> Result<(&mut [u8], &mut [u8]), InstructionError> {
+    if data.len() < CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1 {
+        return Err(InstructionError::AccountDataTooSmall);
+    }
+    Ok(data.split_at_mut(CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1))
+}
+
+
+/// Runs `f` on the header and tree bytes of the initialized merkle tree account at `merkle_tree`.
+fn with_tree<T>(
+    accounts: &mut Accounts,
+    merkle_tree: &Pubkey,
+    f: impl FnOnce(&ConcurrentMerkleTreeHeader, &mut [u8]) -> anchor_lang::Result<T>
+) -> Result<T, InstructionError> {
+    let account = accounts.get_mut(merkle_tree).ok_or(InstructionError::UninitializedAccount)?;
+    if account.owner != spl_account_compression::ID {
+        return Err(compression_error(AccountCompressionError::IncorrectAccountOwner));
+    }
+
+    let (header_bytes, rest) = split_header(&mut account.data)?;
+    let header = ConcurrentMerkleTreeHeader::deserialize(&mut &*header_bytes)
+        .map_err(|_| InstructionError::InvalidAccountData)?;
+    header.assert_valid().map_err(compression_error)?;
+
+    let size = merkle_tree_get_size(&header).map_err(compression_error)?;
+    if rest.len() < size {
+        return Err(InstructionError::AccountDataTooSmall);
+    }
+
+    f(&header, &mut rest[..size]).map_err(compression_error)
+}
+
+
+fn load_tree_config(accounts: &Accounts, tree_config: &Pubkey, merkle_tree: &Pubkey) -> Result<TreeConfig, InstructionError> {
+    if TreeConfig::find_pda(merkle_tree).0 != *tree_config {
+        return Err(InstructionError::InvalidSeeds);
+    }
+
+    let account = accounts.get(tree_config).ok_or(InstructionError::UninitializedAccount)?;
+    if account.owner != mpl_bubblegum::ID {
+        return Err(bubblegum_error(MplBubblegumError::IncorrectOwner));
+    }
+
+    TreeConfig::from_bytes(&account.data).map_err(|_| InstructionError::InvalidAccountData)
+}
+
+
+fn store_tree_config(accounts: &mut Accounts, tree_config: &Pubkey, config: &TreeConfig) -> Result<(), InstructionError> {
+    let account = accounts.get_mut(tree_config).ok_or(InstructionError::UninitializedAccount)?;
+    let data = config.try_to_vec().map_err(|_| InstructionError::InvalidAccountData)?;
+
+    account.data[..data.len()].copy_from_slice(&data);
+    Ok(())
+}
+
+
+/// Anchor discriminator of the account type `name`.
+fn account_discriminator(name: &str) -> [u8; 8] {
+    let mut discriminator = [0; 8];
+    discriminator.copy_from_slice(&hashv(&[format!("account:{name}").as_bytes()]).to_bytes()[..8]);
+    discriminator
+}
+
+
+/// Runs `f` on a thread with a stack large enough for the account compression functions.
+fn on_execution_thread<R: Send>(f: impl FnOnce() -> R + Send) -> Result<R, CnftError> {
+    thread::scope(|scope| {
+        thread::Builder::new()
+            .stack_size(EXECUTION_STACK_SIZE)
+            .spawn_scoped(scope, f)
+            .map_err(|e| CnftError::rpc("simulator", e))?
+            .join()
+            .map_err(|_| CnftError::rpc("simulator", "transaction execution panicked"))
+    })
+}
+
+
+/// Compute units charged for the invocations `logs` record, see `UNITS_PER_INVOCATION`.
+fn estimated_units(logs: &[String]) -> u64 {
+    logs.iter().filter(|line| line.contains(" invoke [")).count() as u64 * UNITS_PER_INVOCATION
+}
+
+
+/// What is logged when `program_id` fails with `error`: Bubblegum and Account Compression, being Anchor programs,
+/// log their custom errors before the runtime reports the failure.
+fn failure_logs(program_id: &Pubkey, error: &InstructionError) -> Vec<String> {
+    let mut logs = Vec::new();
+
+    if let InstructionError::Custom(code) = error {
+        let anchor_error = match code.checked_sub(6000) {
+            Some(offset) if *program_id == mpl_bubblegum::ID =>
+                MplBubblegumError::from_u32(offset).map(|e| (format!("{e:?}"), e.to_string())),
+            Some(offset) if *program_id == spl_account_compression::ID =>
+                SplAccountCompressionError::from_u32(offset).map(|e| (format!("{e:?}"), e.to_string())),
+            _ => None
+        };
+
+        if let Some((name, message)) = anchor_error {
+            logs.push(format!("Program log: AnchorError occurred. Error Code: {name}. Error Number: {code}. Error Message: {message}."));
+        }
+    }
+
+    logs.push(format!("Program {program_id} failed: {error}"));
+    logs
+}
+
+
+fn bubblegum_error(error: MplBubblegumError) -> InstructionError {
+    InstructionError::Custom(6000 + error as u32)
+}
+
+
+fn compression_error(error: impl Into<anchor_lang::error::Error>) -> InstructionError {
+    match ProgramError::from(error.into()) {
+        ProgramError::Custom(code) => InstructionError::Custom(code),
+        _ => InstructionError::InvalidAccountData
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use mpl_bubblegum::types::LeafSchema;
+    use solana_sdk::{bs58, signature::{Keypair, Signature}, signer::Signer};
+    use spl_account_compression::EMPTY;
+
+    use crate::backend::{backend, simulated_backend};
+    use crate::error::{CnftError, ProgramName};
+    use crate::events;
+    use crate::pending::PendingStatus;
+    use crate::pipeline::PipelineOperation;
+    use crate::proof_source::{decode_tree, read_tree_account};
+    use crate::result::OperationResult;
+    use crate::setup::TreeManager;
+
+
+    /// A TreeManager whose tree is created on the simulated chain, along with the tree owner.
+    fn created_tree() -> (TreeManager, Keypair) {
+        let owner = Keypair::new();
+        let mut tree_manager = TreeManager::default();
+        tree_manager.create_tree(&owner).unwrap();
+
+        (tree_manager, owner)
+    }
+
+
+    /// Root of the tree account on the simulated chain.
+    fn chain_root(tree_manager: &TreeManager) -> [u8; 32] {
+        let (header, tree_bytes) = read_tree_account(&tree_manager.tree_pubkey().unwrap()).unwrap();
+
+        decode_tree(&header, &tree_bytes).unwrap().get_root()
+    }
+
+
+    /// The leaf schema Bubblegum logged for the leaf the operation wrote.
+    fn logged_schema(tree_manager: &TreeManager, result: &OperationResult) -> LeafSchema {
+        let signature: Signature = result.signature.parse().unwrap();
+        let transaction = backend().confirmed_transaction(&signature).unwrap().unwrap();
+
+        events::leaf_updates(&transaction.noop_data, &tree_manager.tree_pubkey().unwrap())
+            .pop()
+            .and_then(|update| update.schema)
+            .unwrap()
+    }
+
+
+    fn base58(hash: [u8; 32]) -> String {
+        bs58::encode(hash).into_string()
+    }
+
+
+    #[test]
+    fn mint_matches_chain_root_and_events() {
+        let _backend = simulated_backend();
+        let (mut tree_manager, owner) = created_tree();
+        let nft_owner = Keypair::new().pubkey().to_string();
+
+        for index in 0..3 {
+            let result = tree_manager.mint_cnft(&owner, &nft_owner).unwrap();
+
+            let signature: Signature = result.signature.parse().unwrap();
+            let transaction = backend().confirmed_transaction(&signature).unwrap().unwrap();
+            let update = events::leaf_updates(&transaction.noop_data, &tree_manager.tree_pubkey().unwrap()).pop().unwrap();
+
+            assert_eq!(result.leaf_index, Some(index));
+            assert_eq!(update.index as usize, index);
+            assert_eq!(result.leaf_hash, Some(base58(update.leaf_hash)));
+            assert_eq!(tree_manager.nodes[index], update.leaf_hash.to_vec());
+            assert_eq!(chain_root(&tree_manager), tree_manager.off_chain_merkle_tree().unwrap().root);
+        }
+
+        assert_eq!(tree_manager.minted, 3);
+    }
+
+
+    #[test]
+    fn transfer_and_burn_match_chain_root() {
+        let _backend = simulated_backend();
+        let (mut tree_manager, owner) = created_tree();
+        let nft_owner = Keypair::new();
+        let new_owner = Keypair::new();
+
+        let minted = tree_manager.mint_cnft(&owner, &nft_owner.pubkey().to_string()).unwrap();
+        let schema = logged_schema(&tree_manager, &minted);
+        let (data_hash, creator_hash) = (base58(schema.data_hash()), base58(schema.creator_hash()));
+
+        let transferred = tree_manager.transfer_cnft(&owner, &nft_owner, &new_owner.pubkey().to_string(), 0, &data_hash, &creator_hash).unwrap();
+        let schema = logged_schema(&tree_manager, &transferred);
+
+        assert_eq!(schema.owner(), new_owner.pubkey());
+        assert_eq!(transferred.leaf_hash, Some(base58(schema.hash())));
+        assert_eq!(chain_root(&tree_manager), tree_manager.off_chain_merkle_tree().unwrap().root);
+
+        tree_manager.burn_cnft(&owner, &new_owner, 0, &data_hash, &creator_hash).unwrap();
+
+        assert_eq!(tree_manager.nodes[0], EMPTY.to_vec());
+        assert_eq!(chain_root(&tree_manager), tree_manager.off_chain_merkle_tree().unwrap().root);
+    }
+
+
+    #[test]
+    fn stale_proof_fails_with_program_error() {
+        let _backend = simulated_backend();
+        let (mut tree_manager, owner) = created_tree();
+        let nft_owner = Keypair::new();
+
+        let minted = tree_manager.mint_cnft(&owner, &nft_owner.pubkey().to_string()).unwrap();
+        let schema = logged_schema(&tree_manager, &minted);
+        let (data_hash, creator_hash) = (base58(schema.data_hash()), base58(schema.creator_hash()));
+
+        // A copy which does not know about the transfer below proves the leaf the chain no longer holds.
+        let mut stale = tree_manager.clone();
+        tree_manager.transfer_cnft(&owner, &nft_owner, &Keypair::new().pubkey().to_string(), 0, &data_hash, &creator_hash).unwrap();
+        let root = chain_root(&tree_manager);
+
+        let transfer = stale.transfer_cnft(&owner, &nft_owner, &Keypair::new().pubkey().to_string(), 0, &data_hash, &creator_hash);
+        let burn = stale.burn_cnft(&owner, &nft_owner, 0, &data_hash, &creator_hash);
+
+        for error in [transfer.err(), burn.err()] {
+            match error {
+                Some(CnftError::Program { program, name, logs, .. }) => {
+                    assert_eq!(program, ProgramName::AccountCompression);
+                    assert_eq!(name, "ConcurrentMerkleTreeError");
+                    assert!(!logs.is_empty());
+                }
+                other => panic!("expected a program error, got {other:?}")
+            }
+        }
+
+        assert_eq!(chain_root(&tree_manager), root);
+        assert_eq!(stale.nodes[0], schema.hash().to_vec());
+    }
+
+
+    #[test]
+    fn proof_rejected_by_the_tree_is_rebuilt_from_chain() {
+        let _backend = simulated_backend();
+        let (mut tree_manager, owner) = created_tree();
+        let nft_owner = Keypair::new();
+        let new_owner = Keypair::new();
+
+        let minted = tree_manager.mint_cnft(&owner, &nft_owner.pubkey().to_string()).unwrap();
+        tree_manager.mint_cnft(&owner, &Keypair::new().pubkey().to_string()).unwrap();
+        let schema = logged_schema(&tree_manager, &minted);
+        let (data_hash, creator_hash) = (base58(schema.data_hash()), base58(schema.creator_hash()));
+
+        // A leaf the chain never held, off the path of any later change, so the program cannot fast-forward it.
+        tree_manager.nodes[2] = vec![7; 32];
+
+        let transferred = tree_manager.transfer_cnft(&owner, &nft_owner, &new_owner.pubkey().to_string(), 0, &data_hash, &creator_hash).unwrap();
+
+        assert_eq!(logged_schema(&tree_manager, &transferred).owner(), new_owner.pubkey());
+        assert_eq!(tree_manager.nodes[0], logged_schema(&tree_manager, &transferred).hash().to_vec());
+    }
+
+
+    #[test]
+    fn send_only_mints_to_the_same_recipient_are_distinct() {
+        let _backend = simulated_backend();
+        let (mut tree_manager, owner) = created_tree();
+        let nft_owner = Keypair::new().pubkey().to_string();
+
+        let first = tree_manager.mint_cnft_send_only(&owner, &nft_owner).unwrap();
+        let second = tree_manager.mint_cnft_send_only(&owner, &nft_owner).unwrap();
+
+        assert_eq!((first.leaf_index, second.leaf_index), (0, 1));
+        assert_ne!(first.signature, second.signature);
+        assert_eq!(tree_manager.pending_mints, 2);
+
+        let statuses = tree_manager.confirm_pending(&[first, second]).unwrap();
+
+        assert!(matches!(statuses[0], PendingStatus::Confirmed(OperationResult { leaf_index: Some(0), .. })));
+        assert!(matches!(statuses[1], PendingStatus::Confirmed(OperationResult { leaf_index: Some(1), .. })));
+        assert_eq!((tree_manager.minted, tree_manager.pending_mints), (2, 0));
+    }
+
+
+    #[test]
+    fn pipeline_dry_run_simulates_every_operation_without_sending() {
+        let _backend = simulated_backend();
+        let (mut tree_manager, owner) = created_tree();
+        let nft_owner = Keypair::new();
+
+        let mut hashes = Vec::new();
+        for _ in 0..2 {
+            let minted = tree_manager.mint_cnft(&owner, &nft_owner.pubkey().to_string()).unwrap();
+            let schema = logged_schema(&tree_manager, &minted);
+            hashes.push((base58(schema.data_hash()), base58(schema.creator_hash())));
+        }
+        let root = chain_root(&tree_manager);
+
+        let operations: Vec<PipelineOperation> = hashes.iter()
+            .enumerate()
+            .map(|(index, (data_hash, creator_hash))| PipelineOperation::Burn { owner: &nft_owner, index, data_hash, creator_hash })
+            .collect();
+        let simulations = tree_manager.simulate_pipeline(&owner, &operations).unwrap();
+
+        assert_eq!(simulations.len(), 2);
+        for simulation in simulations {
+            let simulation = simulation.unwrap();
+            assert_eq!(simulation.error, None);
+            assert!(simulation.units_consumed.is_some_and(|units| units > 0));
+        }
+        assert_eq!(chain_root(&tree_manager), root);
+    }
+
+
+    #[test]
+    fn mint_whose_transaction_cannot_be_read_back_is_not_assumed() {
+        let _backend = simulated_backend();
+        let (tree_manager, _) = created_tree();
+
+        let error = tree_manager.minted_leaf(&Signature::new_unique()).err();
+
+        assert!(matches!(error, Some(CnftError::InvalidState { field, .. }) if field == "transaction"));
+    }
+
+
+    #[test]
+    fn full_tree_is_rejected() {
+        let _backend = simulated_backend();
+        let (mut tree_manager, owner) = created_tree();
+        tree_manager.minted = tree_manager.capacity();
+
+        let error = tree_manager.mint_cnft(&owner, &Keypair::new().pubkey().to_string()).err();
+
+        assert_eq!(error, Some(CnftError::TreeFull { capacity: tree_manager.capacity() }));
+    }
+}
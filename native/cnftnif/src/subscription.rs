@@ -0,0 +1,483 @@
+//! This module implements subscriptions through Solana PubSub, notifying an Elixir process of signature
+//! confirmations and merkle tree account changes instead of polling for them
+//!
+//! Every subscription holds its own websocket connection, to any PubSub endpoint, e.g. `wss://api.devnet.solana.com`.
+//! The subscribed process receives:
+//! - `{:cnft_signature, id, signature, slot, :ok | {:error, error}}` once the transaction is confirmed.
+//! - `{:cnft_tree_update, id, %CnftNif.TreeUpdate{}}` every time the tree account changes.
+//! - `{:cnft_subscription_closed, id}` if the connection drops. Nothing is sent after an `unsubscribe`.
+
+
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use rustler::{Encoder, Env, LocalPid, NifStruct, OwnedEnv, Term};
+use solana_account_decoder::{UiAccount, UiAccountEncoding};
+use solana_client::{pubsub_client::PubsubClient, rpc_config::{RpcAccountInfoConfig, RpcSignatureSubscribeConfig}, rpc_response::{Response, RpcSignatureResult}};
+use solana_sdk::{bs58, commitment_config::CommitmentConfig, signature::Signature};
+
+use crate::backend::backend;
+use crate::error::CnftError;
+use crate::proof_source::{decode_tree, split_tree_account};
+use crate::utils::parse_pubkey;
+
+
+mod atoms {
+    rustler::atoms! {
+        ok,
+        error,
+        cnft_signature,
+        cnft_tree_update,
+        cnft_subscription_closed
+    }
+}
+
+
+/// Id of the next subscription, unique within the VM.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+
+
+/// The state of a merkle tree after a change, as delivered to subscribers of the tree.
+
+#[derive(NifStruct, Clone, Debug)]
+#[module = "CnftNif.TreeUpdate"]
+pub struct TreeUpdate {
+    /// Address of the merkle tree account.
+    pub tree_address: String,
+
+    /// Slot the change was made in.
+    pub slot: u64,
+
+    /// Base58 encoded root of the tree after the change.
+    pub root: String,
+
+    /// Number of changes made to the tree since it was created.
+    pub sequence_number: u64,
+
+    /// Number of leaves appended to the tree, i.e. the number of cNFTs minted into it.
+    pub leaf_count: u64
+}
+
+
+
+/// A live subscription, held by the BEAM as a resource.
+///
+/// Once the last reference is garbage collected the subscription is closed, like with `unsubscribe`.
+pub struct Subscription {
+    id: u64,
+
+    /// Set once unsubscribed, so nothing is forwarded afterwards.
+    closed: Arc<AtomicBool>,
+
+    /// The PubSub subscription, which unsubscribes and closes its connection when dropped.
+    connection: Mutex<Option<Box<dyn Send>>>
+}
+
+#[rustler::resource_impl]
+impl rustler::Resource for Subscription {}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.unsubscribe();
+    }
+}
+
+impl Subscription {
+
+    /// Id the messages of this subscription are tagged with.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+
+    /// Stops forwarding messages and closes the connection.
+    ///
+    /// The PubSub client only notices the unsubscription once the server sends its next message, so the
+    /// connection is closed from a thread of its own instead of blocking the caller.
+    pub fn unsubscribe(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+
+        if let Some(connection) = self.connection.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            thread::spawn(move || drop(connection));
+        }
+    }
+}
+
+
+
+/// Notifies `pid` once the transaction `signature` is confirmed, whether it succeeded or failed. The error of a
+/// failed transaction carries its logs, fetched through the backend, see `backend`.
+///
+/// # Errors
+///
+/// Returns an error if `signature` is invalid or the subscription cannot be made.
+pub fn subscribe_signature(ws_url: &str, signature: &str, pid: LocalPid) -> Result<Subscription, CnftError> {
+    signature_subscription(ws_url, signature, Process::new(pid))
+}
+
+
+fn signature_subscription(ws_url: &str, signature: &str, subscriber: impl Subscriber) -> Result<Subscription, CnftError> {
+    let signature = Signature::from_str(signature)
+        .map_err(|e| CnftError::invalid_argument("signature", e))?;
+    let config = RpcSignatureSubscribeConfig {
+        commitment: Some(CommitmentConfig::confirmed()),
+        enable_received_notification: Some(false)
+    };
+
+    let (connection, receiver) = PubsubClient::signature_subscribe(ws_url, &signature, Some(config))
+        .map_err(|e| CnftError::rpc("pubsub", e))?;
+
+    Ok(forward(Box::new(connection), receiver, subscriber, true, move |response: Response<RpcSignatureResult>| {
+        let RpcSignatureResult::ProcessedSignature(processed) = response.value else {
+            return None;
+        };
+
+        Some(Notification::Signature {
+            signature: signature.to_string(),
+            slot: response.context.slot,
+            error: processed.err.map(|e| CnftError::from_transaction_error(&e, None, &backend().transaction_logs(&signature)))
+        })
+    }))
+}
+
+
+/// Notifies `pid` of every change of the merkle tree account at `tree_address`, with its new root and sequence number.
+/// Notifications whose data is not a `MAX_DEPTH = 14`, `MAX_BUFFER_SIZE = 64` tree, e.g. once the account is closed,
+/// are skipped.
+///
+/// # Errors
+///
+/// Returns an error if `tree_address` is invalid or the subscription cannot be made.
+pub fn subscribe_tree(ws_url: &str, tree_address: &str, pid: LocalPid) -> Result<Subscription, CnftError> {
+    tree_subscription(ws_url, tree_address, Process::new(pid))
+}
+
+
+fn tree_subscription(ws_url: &str, tree_address: &str, subscriber: impl Subscriber) -> Result<Subscription, CnftError> {
+    let tree_account = parse_pubkey(tree_address, "tree_address")?;
+    let config = RpcAccountInfoConfig {
+        encoding: Some(UiAccountEncoding::Base64),
+        data_slice: None,
+        commitment: Some(CommitmentConfig::confirmed()),
+        min_context_slot: None
+    };
+
+    let (connection, receiver) = PubsubClient::account_subscribe(ws_url, &tree_account, Some(config))
+        .map_err(|e| CnftError::rpc("pubsub", e))?;
+
+    Ok(forward(Box::new(connection), receiver, subscriber, false, move |response: Response<UiAccount>| {
+        tree_update(&tree_account.to_string(), response.context.slot, &response.value).map(Notification::Tree)
+    }))
+}
+
+
+/// Decodes the root, sequence number and leaf count of a tree from a notification of its account.
+fn tree_update(tree_address: &str, slot: u64, account: &UiAccount) -> Option<TreeUpdate> {
+    let (header, tree_bytes) = split_tree_account(account.data.decode()?).ok()?;
+    let tree = decode_tree(&header, &tree_bytes).ok()?;
+
+    Some(TreeUpdate {
+        tree_address: tree_address.to_string(),
+        slot,
+        root: bs58::encode(tree.get_root()).into_string(),
+        sequence_number: tree.sequence_number,
+        leaf_count: tree.rightmost_proof.index as u64
+    })
+}
+
+
+
+
+/// A notification to deliver to the subscribed process.
+#[derive(Debug)]
+enum Notification {
+    Signature { signature: String, slot: u64, error: Option<CnftError> },
+    Tree(TreeUpdate),
+    Closed
+}
+
+impl Notification {
+    fn encode<'a>(&self, env: Env<'a>, id: u64) -> Term<'a> {
+        match self {
+            Notification::Signature { signature, slot, error } => {
+                let result = match error {
+                    None => atoms::ok().encode(env),
+                    Some(e) => (atoms::error(), e).encode(env)
+                };
+
+                (atoms::cnft_signature(), id, signature, slot, result).encode(env)
+            }
+            Notification::Tree(update) => (atoms::cnft_tree_update(), id, update).encode(env),
+            Notification::Closed => (atoms::cnft_subscription_closed(), id).encode(env)
+        }
+    }
+}
+
+
+
+
+/// Where the notifications of a subscription are delivered.
+trait Subscriber: Send + 'static {
+    /// Delivers `notification` of the subscription `id`, returning `false` once nobody listens anymore.
+    fn deliver(&mut self, id: u64, notification: Notification) -> bool;
+}
+
+
+/// The subscribed process, sent to from an environment of its own since messages cannot be sent from a thread
+/// managed by the VM.
+struct Process {
+    pid: LocalPid,
+    env: OwnedEnv
+}
+
+impl Process {
+    fn new(pid: LocalPid) -> Self {
+        Self { pid, env: OwnedEnv::new() }
+    }
+}
+
+impl Subscriber for Process {
+    fn deliver(&mut self, id: u64, notification: Notification) -> bool {
+        self.env.send_and_clear(&self.pid, |env| notification.encode(env, id)).is_ok()
+    }
+}
+
+
+
+
+/// Forwards the notifications of `receiver` to `subscriber` from a thread of its own. `notification` picks what
+/// to deliver, skipping a notification on `None`. With `once`, forwarding ends after the first delivery.
+fn forward<T>(
+    connection: Box<dyn Send>,
+    receiver: impl IntoIterator<Item = T> + Send + 'static,
+    mut subscriber: impl Subscriber,
+    once: bool,
+    notification: impl Fn(T) -> Option<Notification> + Send + 'static
+) -> Subscription
+where
+    T: Send + 'static
+{
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let closed = Arc::new(AtomicBool::new(false));
+    let forwarding = closed.clone();
+
+    thread::spawn(move || {
+        for message in receiver {
+            if forwarding.load(Ordering::Relaxed) {
+                return;
+            }
+            let Some(notification) = notification(message) else {
+                continue;
+            };
+
+            // The process is gone.
+            if !subscriber.deliver(id, notification) {
+                return;
+            }
+            if once {
+                return;
+            }
+        }
+
+        // The connection dropped.
+        if !forwarding.load(Ordering::Relaxed) {
+            subscriber.deliver(id, Notification::Closed);
+        }
+    });
+
+    Subscription {
+        id,
+        closed,
+        connection: Mutex::new(Some(connection))
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde_json::{json, Value};
+    use solana_sdk::{bs58, signature::Keypair, signer::Signer};
+    use tungstenite::{Message, WebSocket};
+
+    use super::{signature_subscription, tree_subscription, Notification, Subscriber, TreeUpdate};
+    use crate::backend::{backend, simulated_backend};
+    use crate::error::CnftError;
+    use crate::setup::TreeManager;
+
+
+    /// Subscription id the stub server hands out.
+    const SUBSCRIPTION: u64 = 7;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+
+    impl Subscriber for Sender<(u64, Notification)> {
+        fn deliver(&mut self, id: u64, notification: Notification) -> bool {
+            self.send((id, notification)).is_ok()
+        }
+    }
+
+
+    /// Serves one PubSub connection on a local port: answers the subscription request, then runs `serve` with the
+    /// socket and closes the connection. Returns the URL and the handle yielding the subscription request along
+    /// with what `serve` returned.
+    fn stub(serve: impl FnOnce(&mut WebSocket<TcpStream>) -> Value + Send + 'static) -> (String, JoinHandle<(Value, Value)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+
+            let request: Value = serde_json::from_str(&socket.read().unwrap().into_text().unwrap()).unwrap();
+            socket.send(Message::Text(json!({"jsonrpc": "2.0", "result": SUBSCRIPTION, "id": request["id"]}).to_string())).unwrap();
+
+            let served = serve(&mut socket);
+            (request, served)
+        });
+
+        (url, handle)
+    }
+
+
+    fn notification(method: &str, result: Value) -> Message {
+        Message::Text(json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": {"result": result, "subscription": SUBSCRIPTION}
+        }).to_string())
+    }
+
+
+    /// A tree account notification carrying `data`.
+    fn account_notification(slot: u64, data: &[u8]) -> Message {
+        notification("accountNotification", json!({
+            "context": {"slot": slot},
+            "value": {
+                "lamports": 1,
+                "data": [STANDARD.encode(data), "base64"],
+                "owner": spl_account_compression::id().to_string(),
+                "executable": false,
+                "rentEpoch": 0,
+                "space": data.len()
+            }
+        }))
+    }
+
+
+    #[test]
+    fn signature_failure_carries_the_transaction_logs() {
+        let _backend = simulated_backend();
+        let owner = Keypair::new();
+        let mut tree_manager = TreeManager::default();
+        tree_manager.create_tree(&owner).unwrap();
+        let minted = tree_manager.mint_cnft(&owner, &Keypair::new().pubkey().to_string()).unwrap();
+        let logs = backend().transaction_logs(&minted.signature.parse().unwrap());
+
+        let (url, server) = stub(|socket| {
+            socket.send(notification("signatureNotification", json!({
+                "context": {"slot": 42},
+                "value": {"err": {"InstructionError": [0, {"Custom": 6001}]}}
+            }))).unwrap();
+            Value::Null
+        });
+        let (sender, receiver) = mpsc::channel();
+
+        let subscription = signature_subscription(&url, &minted.signature, sender).unwrap();
+        let (request, _) = server.join().unwrap();
+
+        assert_eq!(request["method"], "signatureSubscribe");
+        assert_eq!(request["params"][0], minted.signature.as_str());
+
+        match receiver.recv_timeout(WAIT).unwrap() {
+            (id, Notification::Signature { signature, slot, error: Some(CnftError::Program { code, logs: error_logs, .. }) }) => {
+                assert_eq!(id, subscription.id());
+                assert_eq!((signature, slot, code), (minted.signature, 42, 6001));
+                assert!(!logs.is_empty());
+                assert_eq!(error_logs, logs);
+            }
+            other => panic!("expected a failed signature, got {other:?}")
+        }
+    }
+
+
+    #[test]
+    fn tree_changes_are_decoded_until_the_connection_drops() {
+        let _backend = simulated_backend();
+        let owner = Keypair::new();
+        let mut tree_manager = TreeManager::default();
+        tree_manager.create_tree(&owner).unwrap();
+        tree_manager.mint_cnft(&owner, &Keypair::new().pubkey().to_string()).unwrap();
+
+        let tree_account = tree_manager.tree_pubkey().unwrap();
+        let data = backend().account_data(&tree_account).unwrap().unwrap();
+
+        let (url, server) = stub(move |socket| {
+            // Data which is not a tree is skipped.
+            socket.send(account_notification(8, &[0; 16])).unwrap();
+            socket.send(account_notification(9, &data)).unwrap();
+            Value::Null
+        });
+        let (sender, receiver) = mpsc::channel();
+
+        let subscription = tree_subscription(&url, &tree_account.to_string(), sender).unwrap();
+        let (request, _) = server.join().unwrap();
+
+        assert_eq!(request["method"], "accountSubscribe");
+        assert_eq!(request["params"][0], tree_account.to_string());
+
+        match receiver.recv_timeout(WAIT).unwrap() {
+            (id, Notification::Tree(TreeUpdate { tree_address, slot, root, leaf_count, .. })) => {
+                assert_eq!(id, subscription.id());
+                assert_eq!((tree_address, slot, leaf_count), (tree_account.to_string(), 9, 1));
+                assert_eq!(root, bs58::encode(tree_manager.off_chain_merkle_tree().unwrap().root).into_string());
+            }
+            other => panic!("expected a tree update, got {other:?}")
+        }
+        assert!(matches!(receiver.recv_timeout(WAIT).unwrap(), (_, Notification::Closed)));
+    }
+
+
+    #[test]
+    fn nothing_is_delivered_after_unsubscribe() {
+        let (go, ready): (Sender<()>, Receiver<()>) = mpsc::channel();
+
+        let (url, server) = stub(move |socket| {
+            ready.recv().unwrap();
+            socket.send(account_notification(9, &[0; 16])).unwrap();
+
+            // The client only sends its unsubscription once a message wakes up its reader.
+            socket.get_ref().set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+            loop {
+                match socket.read() {
+                    Ok(Message::Text(text)) => return serde_json::from_str(&text).unwrap(),
+                    Ok(_) => {}
+                    Err(_) => socket.send(Message::Ping(Vec::new())).unwrap()
+                }
+            }
+        });
+        let (sender, receiver) = mpsc::channel();
+
+        let subscription = tree_subscription(&url, &Keypair::new().pubkey().to_string(), sender).unwrap();
+        subscription.unsubscribe();
+        go.send(()).unwrap();
+
+        let (_, unsubscribe) = server.join().unwrap();
+
+        assert_eq!(unsubscribe["method"], "accountUnsubscribe");
+        assert_eq!(unsubscribe["params"], json!([SUBSCRIPTION]));
+        assert!(receiver.recv_timeout(WAIT).is_err());
+    }
+}
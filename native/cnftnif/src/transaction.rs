@@ -0,0 +1,256 @@
+//! This module implements build-only transactions, which are signed outside of Rust (e.g. by a user wallet)
+//! and handed back to us for submission
+
+
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rustler::{NifStruct, NifUnitEnum};
+use solana_sdk::{signature::Signature, signer::Signer, transaction::Transaction};
+
+use crate::error::CnftError;
+
+
+
+/// The kind of leaf change a transaction applies to the merkle tree.
+#[derive(NifUnitEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Operation {
+    Mint,
+    Transfer,
+    Burn,
+    Delegate
+}
+
+
+
+/// Represents a serialized transaction which still needs one or more signatures before it can be submitted.
+
+#[derive(NifStruct, Clone)]
+#[module = "CnftNif.UnsignedTransaction"]
+pub struct UnsignedTransaction {
+    /// Base64 encoded, bincode serialized transaction. It may already carry some of the signatures.
+    pub transaction: String,
+
+    /// Base58 public keys of the signers whose signatures are still missing.
+    pub signers: Vec<String>,
+
+    /// The kind of leaf change this transaction applies.
+    pub operation: Operation,
+
+    /// Index of the leaf which is changed by this transaction.
+    pub leaf_index: usize,
+
+    /// Hash of the leaf once the transaction is confirmed, applied to the TreeManager on submission.
+    pub leaf_hash: Vec<u8>
+}
+
+impl UnsignedTransaction {
+
+    /// Wraps a built transaction together with the leaf change it applies.
+    pub fn new(transaction: &Transaction, operation: Operation, leaf_index: usize, leaf_hash: Vec<u8>) -> Result<Self, CnftError> {
+        Ok(Self {
+            transaction: encode_transaction(transaction)?,
+            signers: missing_signers(transaction),
+            operation,
+            leaf_index,
+            leaf_hash
+        })
+    }
+
+
+    /// Decodes the wrapped transaction.
+    pub fn to_transaction(&self) -> Result<Transaction, CnftError> {
+        decode_transaction(&self.transaction)
+    }
+
+
+    /// Adds the signature of `signer` to the wrapped transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `signer` is not one of the required signers of the transaction or fails to sign.
+    pub fn sign(&mut self, signer: &dyn Signer) -> Result<(), CnftError> {
+        let mut txn = self.to_transaction()?;
+        let blockhash = txn.message.recent_blockhash;
+
+        txn.try_partial_sign(&[signer], blockhash)?;
+
+        self.transaction = encode_transaction(&txn)?;
+        self.signers = missing_signers(&txn);
+
+        Ok(())
+    }
+
+
+    /// Decodes a wallet-signed transaction and checks it against the wrapped one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if:
+    /// - The signed transaction cannot be decoded.
+    /// - Its message differs from the message which was built.
+    /// - A required signature is missing or invalid.
+    pub fn verify_signed(&self, signed_transaction: &str) -> Result<Transaction, CnftError> {
+        let expected = self.to_transaction()?;
+        let signed = decode_transaction(signed_transaction)?;
+
+        if signed.message != expected.message {
+            return Err(CnftError::invalid_transaction("signed transaction does not match the built transaction"));
+        }
+
+        let missing = missing_signers(&signed);
+        if !missing.is_empty() {
+            return Err(CnftError::invalid_transaction(format!("missing signatures of {}", missing.join(", "))));
+        }
+
+        signed.verify()
+            .map_err(CnftError::invalid_transaction)?;
+
+        Ok(signed)
+    }
+}
+
+
+
+
+/// Serializes a transaction with bincode and encodes it as base64.
+pub fn encode_transaction(transaction: &Transaction) -> Result<String, CnftError> {
+    let bytes = bincode::serialize(transaction)
+        .map_err(CnftError::invalid_transaction)?;
+
+    Ok(STANDARD.encode(bytes))
+}
+
+
+
+
+/// Decodes a base64 encoded, bincode serialized transaction.
+pub fn decode_transaction(encoded: &str) -> Result<Transaction, CnftError> {
+    let bytes = STANDARD.decode(encoded.trim())
+        .map_err(CnftError::invalid_transaction)?;
+
+    bincode::deserialize(&bytes)
+        .map_err(CnftError::invalid_transaction)
+}
+
+
+
+
+/// Returns the base58 public keys of the required signers which have not signed the transaction yet.
+pub fn missing_signers(transaction: &Transaction) -> Vec<String> {
+    let required = transaction.message.header.num_required_signatures as usize;
+
+    transaction.message.account_keys
+        .iter()
+        .zip(transaction.signatures.iter())
+        .take(required)
+        .filter(|(_, signature)| **signature == Signature::default())
+        .map(|(key, _)| key.to_string())
+        .collect()
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{hash::Hash, pubkey::Pubkey, signature::Keypair, signer::Signer, system_instruction, transaction::Transaction};
+
+    use crate::backend::simulated_backend;
+    use crate::error::CnftError;
+    use crate::setup::TreeManager;
+
+    use super::{encode_transaction, missing_signers, Operation, UnsignedTransaction};
+
+
+    /// An unsigned transaction paid for by `payer` which `owner` has to sign as well.
+    fn unsigned(payer: &Keypair, owner: &Keypair) -> UnsignedTransaction {
+        let instruction = system_instruction::transfer(&owner.pubkey(), &Pubkey::new_unique(), 1);
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.message.recent_blockhash = Hash::new_unique();
+
+        UnsignedTransaction::new(&transaction, Operation::Transfer, 0, vec![0; 32]).unwrap()
+    }
+
+
+    #[test]
+    fn missing_signers_shrink_as_signatures_are_added() {
+        let (payer, owner) = (Keypair::new(), Keypair::new());
+        let mut unsigned = unsigned(&payer, &owner);
+
+        assert_eq!(unsigned.signers, vec![payer.pubkey().to_string(), owner.pubkey().to_string()]);
+
+        unsigned.sign(&owner).unwrap();
+        assert_eq!(unsigned.signers, vec![payer.pubkey().to_string()]);
+
+        unsigned.sign(&payer).unwrap();
+        assert!(unsigned.signers.is_empty());
+        assert!(missing_signers(&unsigned.to_transaction().unwrap()).is_empty());
+    }
+
+
+    #[test]
+    fn signing_with_a_key_which_is_not_required_fails() {
+        let (payer, owner) = (Keypair::new(), Keypair::new());
+        let mut unsigned = unsigned(&payer, &owner);
+
+        assert!(unsigned.sign(&Keypair::new()).is_err());
+        assert_eq!(unsigned.signers.len(), 2);
+    }
+
+
+    #[test]
+    fn verify_signed_accepts_the_signed_transaction() {
+        let (payer, owner) = (Keypair::new(), Keypair::new());
+        let unsigned = unsigned(&payer, &owner);
+
+        let mut signed = unsigned.to_transaction().unwrap();
+        let blockhash = signed.message.recent_blockhash;
+        signed.sign(&[&payer, &owner], blockhash);
+
+        assert_eq!(unsigned.verify_signed(&encode_transaction(&signed).unwrap()).unwrap(), signed);
+    }
+
+
+    #[test]
+    fn verify_signed_rejects_missing_invalid_and_altered_signatures() {
+        let (payer, owner) = (Keypair::new(), Keypair::new());
+        let unsigned = unsigned(&payer, &owner);
+        let blockhash = unsigned.to_transaction().unwrap().message.recent_blockhash;
+
+        let mut partially_signed = unsigned.to_transaction().unwrap();
+        partially_signed.partial_sign(&[&payer], blockhash);
+
+        let mut forged = unsigned.to_transaction().unwrap();
+        forged.sign(&[&payer, &owner], blockhash);
+        forged.signatures[1] = Keypair::new().sign_message(&forged.message_data());
+
+        let mut altered = unsigned.to_transaction().unwrap();
+        altered.message.recent_blockhash = Hash::new_unique();
+        altered.sign(&[&payer, &owner], altered.message.recent_blockhash);
+
+        for transaction in [partially_signed, forged, altered] {
+            let error = unsigned.verify_signed(&encode_transaction(&transaction).unwrap()).err();
+            assert!(matches!(error, Some(CnftError::InvalidTransaction { .. })), "accepted {:?}", transaction.signatures);
+        }
+
+        assert!(matches!(unsigned.verify_signed("not base64"), Err(CnftError::InvalidTransaction { .. })));
+    }
+
+
+    #[test]
+    fn wallet_signed_mint_is_recorded() {
+        let _backend = simulated_backend();
+        let owner = Keypair::new();
+        let mut tree_manager = TreeManager::default();
+        tree_manager.create_tree(&owner).unwrap();
+
+        let mut unsigned = tree_manager.build_mint_cnft(&owner.pubkey().to_string(), &Keypair::new().pubkey().to_string()).unwrap();
+        assert_eq!(unsigned.signers, vec![owner.pubkey().to_string()]);
+        unsigned.sign(&owner).unwrap();
+
+        let result = tree_manager.submit_signed_transaction(&unsigned, &unsigned.transaction).unwrap();
+
+        assert_eq!(result.leaf_index, Some(0));
+        assert_eq!(tree_manager.minted, 1);
+        assert_eq!(tree_manager.nodes[0], unsigned.leaf_hash);
+    }
+}
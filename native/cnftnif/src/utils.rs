@@ -2,7 +2,12 @@
 
 
 
-use solana_sdk::{bs58, signature::Keypair};
+use std::str::FromStr;
+
+use solana_sdk::{bs58, pubkey::Pubkey, signature::Keypair};
+
+use crate::error::CnftError;
+use crate::setup::MAX_DEPTH;
 
 
 
@@ -31,7 +36,9 @@ pub fn safely_from_base58_string(s: &str) -> Result<Keypair, Box<dyn std::error:
 
 
 
-/// Converts a vector of byte arrays (`Vec<Vec<u8>>`) into a fixed-size array of `[u8; 32]`.
+/// Converts a vector of byte arrays (`Vec<Vec<u8>>`) into the 16384 leaves of the off-chain merkle tree.
+///
+/// The leaves are kept on the heap, a `[[u8; 32]; 16384]` array is too large for the stack of a NIF thread.
 ///
 /// # Arguments
 ///
@@ -39,20 +46,26 @@ pub fn safely_from_base58_string(s: &str) -> Result<Keypair, Box<dyn std::error:
 ///
 /// # Returns
 ///
-/// * `[[u8; 32]; 16384]` - A fixed-size array of 16384 elements, each being a 32-byte array.
+/// * `Ok(Vec<[u8; 32]>)` - The 16384 leaves, each being a 32-byte array.
+/// * `Err(CnftError::InvalidState)` - An error if the nodes do not have the expected shape.
 ///
-/// # Panics
+/// # Errors
 ///
-/// This function will panic if:
+/// This function can fail if:
 /// - Any inner vector is not exactly 32 bytes long.
 /// - The input vector does not contain exactly 16384 elements.
-pub fn convert_nodes(nodes: Vec<Vec<u8>>) -> [[u8; 32]; 16384]{
-    let result: Vec<[u8; 32]> = nodes.into_iter()
-        .map(|inner| inner
-        .try_into().expect("Error occurred while converting nodes to vec<[u8; 32]>"))
-        .collect();
+pub fn convert_nodes(nodes: Vec<Vec<u8>>) -> Result<Vec<[u8; 32]>, CnftError>{
+    let capacity = 1usize << MAX_DEPTH;
+    if nodes.len() != capacity {
+        return Err(CnftError::invalid_state("nodes", format!("expected {capacity} nodes, got {}", nodes.len())));
+    }
 
-    result.try_into().expect("Error occurred while converting vec to [[u8; 32]]")
+    nodes.into_iter()
+        .enumerate()
+        .map(|(index, inner)| inner
+            .try_into()
+            .map_err(|inner: Vec<u8>| CnftError::invalid_state("nodes", format!("node {index} is {} bytes long, expected 32", inner.len()))))
+        .collect()
 }
 
 
@@ -79,3 +92,39 @@ pub fn base58_to_array(b58_str: &str) -> Result<[u8; 32], anyhow::Error> {
     
     Ok(bytes.as_slice().try_into()?)
 }
+
+
+
+
+/// Decodes a base58-encoded public key parameter.
+///
+/// # Arguments
+///
+/// * `b58_str` - A base58-encoded public key.
+/// * `field` - Name of the parameter, reported back in the error.
+///
+/// # Returns
+///
+/// * `Ok(Pubkey)` - The decoded public key.
+/// * `Err(CnftError::InvalidKey)` - If the string is not a valid public key.
+pub fn parse_pubkey(b58_str: &str, field: &str) -> Result<Pubkey, CnftError> {
+    Pubkey::from_str(b58_str).map_err(|_| CnftError::invalid_key(field))
+}
+
+
+
+
+/// Decodes a base58-encoded 32 byte hash parameter, such as a data hash or creator hash.
+///
+/// # Arguments
+///
+/// * `b58_str` - A base58-encoded hash.
+/// * `field` - Name of the parameter, reported back in the error.
+///
+/// # Returns
+///
+/// * `Ok([u8; 32])` - The decoded hash.
+/// * `Err(CnftError::InvalidArgument)` - If the string is not valid base58 or not 32 bytes long.
+pub fn parse_hash(b58_str: &str, field: &str) -> Result<[u8; 32], CnftError> {
+    base58_to_array(b58_str).map_err(|e| CnftError::invalid_argument(field, e))
+}
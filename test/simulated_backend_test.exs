@@ -0,0 +1,41 @@
+defmodule CnftNif.SimulatedBackendTest do
+  # The backend is process wide, so these tests cannot run alongside others using it.
+  use ExUnit.Case, async: false
+
+  setup do
+    :ok = CnftNif.set_backend(:simulated)
+
+    owner = CnftNif.keypair_generate()
+    {:ok, {tree_manager, _}} = CnftNif.create_merkle_tree(CnftNif.tree_manager_init(), owner)
+
+    %{owner: owner, tree_manager: tree_manager}
+  end
+
+  defp recipient, do: CnftNif.keypair_pubkey(CnftNif.keypair_generate())
+
+  test "mint records the leaf reported by the chain", %{owner: owner, tree_manager: tree_manager} do
+    {:ok, {tree_manager, first}} = CnftNif.mint_cnft(tree_manager, owner, recipient())
+    {:ok, {tree_manager, second}} = CnftNif.mint_cnft(tree_manager, owner, recipient())
+
+    assert %CnftNif.OperationResult{leaf_index: 0, nonce: 0} = first
+    assert %CnftNif.OperationResult{leaf_index: 1, nonce: 1} = second
+    assert tree_manager.minted == 2
+
+    {:ok, proof} = CnftNif.get_proof(tree_manager, 1)
+    assert proof.leaf == second.leaf_hash
+  end
+
+  test "dry run leaves the tree manager unchanged", %{owner: owner, tree_manager: tree_manager} do
+    assert {:ok, %CnftNif.SimulationResult{error: nil}} =
+             CnftNif.mint_cnft(tree_manager, owner, recipient(), dry_run: true)
+
+    {:ok, {_, result}} = CnftNif.mint_cnft(tree_manager, owner, recipient())
+    assert result.leaf_index == 0
+  end
+
+  test "minting into a full tree is rejected", %{owner: owner, tree_manager: tree_manager} do
+    full = %{tree_manager | minted: 16384}
+
+    assert {:error, {:tree_full, 16384}} = CnftNif.mint_cnft(full, owner, recipient())
+  end
+end